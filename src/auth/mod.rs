@@ -0,0 +1,488 @@
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::future::{ready, Future, Ready};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, RwLock};
+
+use actix_web::dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::http::StatusCode;
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest, HttpResponse, ResponseError};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use teloxide::types::UserId;
+use teloxide::Bot;
+
+use crate::context;
+use crate::feedback;
+
+const DEFAULT_KEYS_PATH: &str = "api_keys.json";
+// every key starts with this, followed by its ID and the secret part
+const KEY_PREFIX: &str = "drk";
+// Telegram Login Widget payloads older than this are refused
+const LOGIN_MAX_AGE_SECS: i64 = 24 * 60 * 60;
+
+#[derive(Debug, thiserror::Error)]
+pub enum AuthError {
+    #[error("missing credentials, send `Authorization: Bearer <key>` or `Authorization: Telegram <login payload>`")]
+    MissingCredentials,
+    #[error("invalid API key")]
+    InvalidKey,
+    #[error("invalid Telegram login payload")]
+    InvalidLogin,
+    #[error("the Telegram login has expired, sign in again")]
+    ExpiredLogin,
+    #[error("the Telegram user is not registered with the bot")]
+    UnknownUser,
+    #[error("the `{0}` scope is required")]
+    Forbidden(Scope),
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+impl ResponseError for AuthError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AuthError::Forbidden(_) => StatusCode::FORBIDDEN,
+            _ => StatusCode::UNAUTHORIZED,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(ErrorBody {
+            error: self.to_string(),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Scope {
+    // read reports of any user
+    #[serde(rename = "reports:read")]
+    ReportsRead,
+    // everything, including users, subscriptions and deliveries
+    #[serde(rename = "admin")]
+    Admin,
+}
+
+impl Scope {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Scope::ReportsRead => "reports:read",
+            Scope::Admin => "admin",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Scope> {
+        match name.trim() {
+            "reports:read" => Some(Scope::ReportsRead),
+            "admin" => Some(Scope::Admin),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// an API key as stored, the key itself is only known to whoever created it
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    // hex SHA-256 of the whole key
+    hash: String,
+    pub scopes: Vec<Scope>,
+    pub created_by: u64,
+    pub created_at: DateTime<Utc>,
+}
+
+/// the API keys, saved to `API_KEYS_PATH` on every change
+#[derive(Clone)]
+pub struct ApiKeys {
+    keys: Arc<RwLock<Vec<ApiKey>>>,
+    path: PathBuf,
+}
+
+fn hash_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+impl ApiKeys {
+    pub fn from_env() -> ApiKeys {
+        let path = PathBuf::from(env::var("API_KEYS_PATH").unwrap_or_else(|_| DEFAULT_KEYS_PATH.to_string()));
+        let keys = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|err| {
+                log::error!("ignoring unreadable {}: {}", path.display(), err);
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+
+        ApiKeys {
+            keys: Arc::new(RwLock::new(keys)),
+            path,
+        }
+    }
+
+    /// creates a key and returns it with the secret to hand out, which is not stored
+    pub fn create(&self, name: &str, scopes: Vec<Scope>, created_by: UserId) -> (ApiKey, String) {
+        let mut rng = rand::thread_rng();
+        let id = hex::encode(rng.gen::<[u8; 4]>());
+        let secret = format!("{}_{}_{}", KEY_PREFIX, id, hex::encode(rng.gen::<[u8; 24]>()));
+
+        let key = ApiKey {
+            id,
+            name: name.to_string(),
+            hash: hash_key(&secret),
+            scopes,
+            created_by: created_by.0,
+            created_at: Utc::now(),
+        };
+        self.keys.write().unwrap().push(key.clone());
+        self.save();
+
+        (key, secret)
+    }
+
+    /// returns a bool indicating whether the key existed
+    pub fn revoke(&self, id: &str) -> bool {
+        let removed = {
+            let mut keys = self.keys.write().unwrap();
+            let before = keys.len();
+            keys.retain(|key| key.id != id);
+            keys.len() != before
+        };
+        if removed {
+            self.save();
+        }
+        removed
+    }
+
+    /// the file the keys are saved to
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// saves the keys again, in case a write after a change failed
+    pub fn flush(&self) {
+        self.save();
+    }
+
+    pub fn list(&self) -> Vec<ApiKey> {
+        self.keys.read().unwrap().clone()
+    }
+
+    /// the stored key matching the secret, if it was not revoked
+    fn find(&self, secret: &str) -> Option<ApiKey> {
+        let hash = hash_key(secret);
+        self.keys.read().unwrap().iter().find(|key| key.hash == hash).cloned()
+    }
+
+    fn save(&self) {
+        let result = serde_json::to_string_pretty(&*self.keys.read().unwrap())
+            .map_err(|err| err.to_string())
+            .and_then(|content| fs::write(&self.path, content).map_err(|err| err.to_string()));
+        if let Err(err) = result {
+            log::error!("failed to save {}: {}", self.path.display(), err);
+        }
+    }
+}
+
+/// checks a Telegram Login Widget payload signed with the bot token, returns the user who signed in
+pub fn verify_login(fields: &HashMap<String, String>, bot_token: &str, now: DateTime<Utc>) -> Result<UserId, AuthError> {
+    let hash = fields
+        .get("hash")
+        .and_then(|hash| hex::decode(hash).ok())
+        .ok_or(AuthError::InvalidLogin)?;
+
+    // every other field as `key=value`, sorted by key and joined with line breaks
+    let mut pairs: Vec<(&String, &String)> = fields.iter().filter(|(name, _)| *name != "hash").collect();
+    pairs.sort();
+    let check = pairs
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(&Sha256::digest(bot_token.as_bytes()))
+        .expect("HMAC accepts keys of any length");
+    mac.update(check.as_bytes());
+    mac.verify_slice(&hash).map_err(|_| AuthError::InvalidLogin)?;
+
+    let auth_date = fields
+        .get("auth_date")
+        .and_then(|date| date.parse::<i64>().ok())
+        .ok_or(AuthError::InvalidLogin)?;
+    if now.timestamp() - auth_date > LOGIN_MAX_AGE_SECS {
+        return Err(AuthError::ExpiredLogin);
+    }
+
+    fields
+        .get("id")
+        .and_then(|id| id.parse::<u64>().ok())
+        .map(UserId)
+        .ok_or(AuthError::InvalidLogin)
+}
+
+/// the caller behind a Telegram Login Widget payload given as a query string: admins
+/// get every scope and other registered users may read reports
+pub fn login(ctxt: &RwLock<context::Context>, payload: &str, bot_token: &str) -> Result<Caller, AuthError> {
+    let fields = web::Query::<HashMap<String, String>>::from_query(payload)
+        .map_err(|_| AuthError::InvalidLogin)?
+        .into_inner();
+    let user_id = verify_login(&fields, bot_token, Utc::now())?;
+
+    let scopes = if feedback::is_admin(user_id) {
+        vec![Scope::Admin]
+    } else if ctxt.read().unwrap().get_gitlab_user(user_id).is_some() {
+        vec![Scope::ReportsRead]
+    } else {
+        return Err(AuthError::UnknownUser);
+    };
+
+    Ok(Caller {
+        identity: Identity::Telegram(user_id),
+        scopes,
+    })
+}
+
+#[derive(Clone, Debug)]
+pub enum Identity {
+    Key { id: String, name: String },
+    Telegram(UserId),
+}
+
+/// who made an authenticated request, available to handlers as an extractor
+#[derive(Clone, Debug)]
+pub struct Caller {
+    pub identity: Identity,
+    pub scopes: Vec<Scope>,
+}
+
+impl Caller {
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.contains(&Scope::Admin) || self.scopes.contains(&scope)
+    }
+
+    pub fn require(&self, scope: Scope) -> Result<(), AuthError> {
+        if self.allows(scope) {
+            Ok(())
+        } else {
+            Err(AuthError::Forbidden(scope))
+        }
+    }
+
+    /// the Telegram user who signed in, None for API keys
+    pub fn user_id(&self) -> Option<UserId> {
+        match self.identity {
+            Identity::Telegram(user_id) => Some(user_id),
+            Identity::Key { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for Caller {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.identity {
+            Identity::Key { id, name } => write!(f, "key {} ({})", id, name),
+            Identity::Telegram(user_id) => write!(f, "telegram user {}", user_id),
+        }
+    }
+}
+
+impl FromRequest for Caller {
+    type Error = AuthError;
+    type Future = Ready<Result<Caller, AuthError>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(req.extensions().get::<Caller>().cloned().ok_or(AuthError::MissingCredentials))
+    }
+}
+
+/// identifies the caller from an API key, or from a Telegram login for people
+fn authenticate(req: &ServiceRequest) -> Result<Caller, AuthError> {
+    let header = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .ok_or(AuthError::MissingCredentials)?;
+
+    if let Some(secret) = header.strip_prefix("Bearer ") {
+        let key = req
+            .app_data::<web::Data<ApiKeys>>()
+            .and_then(|keys| keys.find(secret.trim()))
+            .ok_or(AuthError::InvalidKey)?;
+        return Ok(Caller {
+            identity: Identity::Key {
+                id: key.id,
+                name: key.name,
+            },
+            scopes: key.scopes,
+        });
+    }
+
+    if let Some(payload) = header.strip_prefix("Telegram ") {
+        let bot = req.app_data::<web::Data<Bot>>().ok_or(AuthError::InvalidLogin)?;
+        let ctxt = req
+            .app_data::<web::Data<Arc<RwLock<context::Context>>>>()
+            .ok_or(AuthError::UnknownUser)?;
+        return login(ctxt, payload.trim(), bot.token());
+    }
+
+    Err(AuthError::MissingCredentials)
+}
+
+/// middleware refusing requests without valid credentials, every authenticated call is
+/// written to the `audit` log target
+pub struct Authentication;
+
+impl<S, B> Transform<S, ServiceRequest> for Authentication
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = AuthenticationMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AuthenticationMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct AuthenticationMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for AuthenticationMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+
+        Box::pin(async move {
+            let caller = match authenticate(&req) {
+                Ok(caller) => caller,
+                Err(err) => {
+                    log::warn!(
+                        target: "audit",
+                        "refused {} {} from {:?}: {}",
+                        req.method(),
+                        req.path(),
+                        req.peer_addr(),
+                        err
+                    );
+                    return Err(err.into());
+                }
+            };
+
+            let method = req.method().clone();
+            let path = req.path().to_string();
+            req.extensions_mut().insert(caller.clone());
+
+            let response = service.call(req).await?;
+            log::info!(target: "audit", "{} {} {} -> {}", caller, method, path, response.status().as_u16());
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const BOT_TOKEN: &str = "123456:ABC-DEF1234ghIkl-zyx57W2v1u123ew11";
+
+    fn now() -> DateTime<Utc> {
+        Utc.timestamp_opt(1_700_000_000, 0).unwrap()
+    }
+
+    /// a payload as the login widget sends it, signed with `token`
+    fn payload(token: &str, auth_date: i64) -> HashMap<String, String> {
+        let mut fields: HashMap<String, String> = [
+            ("id", "42".to_string()),
+            ("first_name", "Budi".to_string()),
+            ("username", "budi".to_string()),
+            ("auth_date", auth_date.to_string()),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect();
+
+        let check = format!("auth_date={}\nfirst_name=Budi\nid=42\nusername=budi", auth_date);
+        let mut mac = Hmac::<Sha256>::new_from_slice(&Sha256::digest(token.as_bytes())).unwrap();
+        mac.update(check.as_bytes());
+        fields.insert("hash".to_string(), hex::encode(mac.finalize().into_bytes()));
+
+        fields
+    }
+
+    #[test]
+    fn accepts_a_signed_login() {
+        let fields = payload(BOT_TOKEN, now().timestamp() - 60);
+
+        assert_eq!(verify_login(&fields, BOT_TOKEN, now()).unwrap(), UserId(42));
+    }
+
+    #[test]
+    fn refuses_a_login_signed_for_another_bot() {
+        let fields = payload("654321:another-bot", now().timestamp());
+
+        assert!(matches!(verify_login(&fields, BOT_TOKEN, now()), Err(AuthError::InvalidLogin)));
+    }
+
+    #[test]
+    fn refuses_changed_fields() {
+        let mut fields = payload(BOT_TOKEN, now().timestamp());
+        fields.insert("id".to_string(), "43".to_string());
+        assert!(matches!(verify_login(&fields, BOT_TOKEN, now()), Err(AuthError::InvalidLogin)));
+
+        let mut fields = payload(BOT_TOKEN, now().timestamp());
+        fields.insert("last_name".to_string(), "Santoso".to_string());
+        assert!(matches!(verify_login(&fields, BOT_TOKEN, now()), Err(AuthError::InvalidLogin)));
+    }
+
+    #[test]
+    fn refuses_a_missing_or_malformed_hash() {
+        let mut fields = payload(BOT_TOKEN, now().timestamp());
+        fields.insert("hash".to_string(), "not hex".to_string());
+        assert!(matches!(verify_login(&fields, BOT_TOKEN, now()), Err(AuthError::InvalidLogin)));
+
+        fields.remove("hash");
+        assert!(matches!(verify_login(&fields, BOT_TOKEN, now()), Err(AuthError::InvalidLogin)));
+    }
+
+    #[test]
+    fn refuses_an_old_login() {
+        let fields = payload(BOT_TOKEN, now().timestamp() - LOGIN_MAX_AGE_SECS - 1);
+
+        assert!(matches!(verify_login(&fields, BOT_TOKEN, now()), Err(AuthError::ExpiredLogin)));
+    }
+}
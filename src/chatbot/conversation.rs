@@ -0,0 +1,111 @@
+use std::collections::VecDeque;
+
+use super::llm::ChatMessage;
+use crate::intent::CommitQuery;
+
+// turns remembered per chat
+const MAX_TURNS: usize = 5;
+// answers are cut to this many characters, enough to give a follow-up context
+const MAX_ANSWER_CHARS: usize = 500;
+
+#[derive(Clone, Debug)]
+pub struct Turn {
+    pub question: String,
+    pub answer: String,
+    // the commit query the question was resolved to, None for knowledge questions
+    pub topic: Option<CommitQuery>,
+}
+
+/// the last few questions of a chat, kept in the dialogue state so follow-ups
+/// like "and what about yesterday?" can be resolved
+#[derive(Clone, Debug, Default)]
+pub struct Conversation {
+    turns: VecDeque<Turn>,
+}
+
+impl Conversation {
+    pub fn push(&mut self, question: String, answer: &str, topic: Option<CommitQuery>) {
+        self.turns.push_back(Turn {
+            question,
+            answer: answer.chars().take(MAX_ANSWER_CHARS).collect(),
+            topic,
+        });
+        while self.turns.len() > MAX_TURNS {
+            self.turns.pop_front();
+        }
+    }
+
+    pub fn last(&self) -> Option<&Turn> {
+        self.turns.back()
+    }
+
+    /// the commit query of the previous question, if it was about the history
+    pub fn last_topic(&self) -> Option<&CommitQuery> {
+        self.last().and_then(|turn| turn.topic.as_ref())
+    }
+
+    /// the conversation so far as chat messages for the LLM
+    pub fn messages(&self) -> Vec<ChatMessage> {
+        self.turns
+            .iter()
+            .flat_map(|turn| {
+                [
+                    ChatMessage::user(turn.question.clone()),
+                    ChatMessage::assistant(turn.answer.clone()),
+                ]
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::DateRange;
+    use chrono::NaiveDate;
+
+    fn topic() -> CommitQuery {
+        CommitQuery {
+            person: Some("Budi".to_string()),
+            range: DateRange::day(NaiveDate::from_ymd_opt(2023, 7, 3).unwrap()),
+            projects: vec!["payment".to_string()],
+        }
+    }
+
+    #[test]
+    fn remembers_the_last_five_turns() {
+        let mut conversation = Conversation::default();
+        for turn in 0..7 {
+            conversation.push(format!("question {}", turn), &format!("answer {}", turn), None);
+        }
+
+        let messages = conversation.messages();
+        assert_eq!(messages.len(), MAX_TURNS * 2);
+        assert_eq!(messages[0].role, "user");
+        assert_eq!(messages[0].content, "question 2");
+        assert_eq!(messages[1].role, "assistant");
+        assert_eq!(messages[1].content, "answer 2");
+        assert_eq!(conversation.last().unwrap().question, "question 6");
+    }
+
+    #[test]
+    fn cuts_long_answers() {
+        let mut conversation = Conversation::default();
+        conversation.push("question".to_string(), &"é".repeat(MAX_ANSWER_CHARS + 10), None);
+
+        assert_eq!(conversation.last().unwrap().answer.chars().count(), MAX_ANSWER_CHARS);
+    }
+
+    #[test]
+    fn last_topic_is_the_previous_question_only() {
+        let mut conversation = Conversation::default();
+        assert_eq!(conversation.last_topic(), None);
+
+        conversation.push("what did Budi do?".to_string(), "a lot", Some(topic()));
+        assert_eq!(conversation.last_topic(), Some(&topic()));
+
+        // a knowledge question in between ends the topic
+        conversation.push("how do we deploy?".to_string(), "with docker", None);
+        assert_eq!(conversation.last_topic(), None);
+    }
+}
@@ -0,0 +1,350 @@
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::i18n::Lang;
+
+const DEFAULT_MODEL: &str = "local";
+const DEFAULT_MAX_TOKENS: u32 = 512;
+const DEFAULT_TIMEOUT_SECS: u64 = 60;
+
+#[derive(Debug, thiserror::Error)]
+pub enum LlmError {
+    #[error("request failed: {0}")]
+    Request(#[from] reqwest::Error),
+    #[error("invalid header: {0}")]
+    Header(#[from] reqwest::header::InvalidHeaderValue),
+    #[error("the response has no choices")]
+    EmptyResponse,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    // system, user or assistant
+    pub role: String,
+    pub content: String,
+}
+
+impl ChatMessage {
+    pub fn system(content: String) -> ChatMessage {
+        ChatMessage {
+            role: "system".to_string(),
+            content,
+        }
+    }
+
+    pub fn user(content: String) -> ChatMessage {
+        ChatMessage {
+            role: "user".to_string(),
+            content,
+        }
+    }
+
+    pub fn assistant(content: String) -> ChatMessage {
+        ChatMessage {
+            role: "assistant".to_string(),
+            content,
+        }
+    }
+}
+
+pub type Generation<'a> = Pin<Box<dyn Future<Output = Result<String, LlmError>> + Send + 'a>>;
+
+/// something that continues a conversation, implemented by the HTTP client
+/// below and by fakes in tests
+pub trait TextGenerator: Send + Sync {
+    fn generate<'a>(&'a self, messages: &'a [ChatMessage]) -> Generation<'a>;
+}
+
+/// a server speaking the OpenAI chat completions API, e.g. llama.cpp or vLLM
+pub struct OpenAiCompatible {
+    // base URL including the version, e.g. http://localhost:8080/v1
+    url: String,
+    api_key: Option<String>,
+    model: String,
+    max_tokens: u32,
+    client: reqwest::Client,
+}
+
+#[derive(Serialize)]
+struct CompletionRequest<'a> {
+    model: &'a str,
+    messages: &'a [ChatMessage],
+    max_tokens: u32,
+}
+
+#[derive(Deserialize)]
+struct CompletionResponse {
+    choices: Vec<CompletionChoice>,
+}
+
+#[derive(Deserialize)]
+struct CompletionChoice {
+    message: ChatMessage,
+}
+
+impl OpenAiCompatible {
+    /// reads `LLM_API_URL`, `LLM_API_KEY`, `LLM_MODEL`, `LLM_MAX_TOKENS` and
+    /// `LLM_TIMEOUT_SECS`, returns None when no endpoint is configured
+    pub fn from_env() -> Option<OpenAiCompatible> {
+        let url = env::var("LLM_API_URL").ok()?;
+        let timeout = env::var("LLM_TIMEOUT_SECS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_TIMEOUT_SECS);
+
+        let client = match reqwest::Client::builder()
+            .timeout(Duration::from_secs(timeout))
+            .build()
+        {
+            Ok(client) => client,
+            Err(err) => {
+                log::error!("failed to create the LLM client: {}", err);
+                return None;
+            }
+        };
+
+        Some(OpenAiCompatible {
+            url: url.trim_end_matches('/').to_string(),
+            api_key: env::var("LLM_API_KEY").ok(),
+            model: env::var("LLM_MODEL").unwrap_or_else(|_| DEFAULT_MODEL.to_string()),
+            max_tokens: env::var("LLM_MAX_TOKENS")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(DEFAULT_MAX_TOKENS),
+            client,
+        })
+    }
+
+    async fn complete(&self, messages: &[ChatMessage]) -> Result<String, LlmError> {
+        let mut headers = HeaderMap::new();
+        if let Some(api_key) = &self.api_key {
+            headers.insert(AUTHORIZATION, HeaderValue::from_str(&format!("Bearer {}", api_key))?);
+        }
+
+        let request = CompletionRequest {
+            model: &self.model,
+            messages,
+            max_tokens: self.max_tokens,
+        };
+        let response = self
+            .client
+            .post(format!("{}/chat/completions", self.url))
+            .headers(headers)
+            .json(&request)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<CompletionResponse>()
+            .await?;
+
+        response
+            .choices
+            .into_iter()
+            .next()
+            .map(|choice| choice.message.content.trim().to_string())
+            .filter(|content| !content.is_empty())
+            .ok_or(LlmError::EmptyResponse)
+    }
+}
+
+impl TextGenerator for OpenAiCompatible {
+    fn generate<'a>(&'a self, messages: &'a [ChatMessage]) -> Generation<'a> {
+        Box::pin(self.complete(messages))
+    }
+}
+
+/// prompt templates, `{lang}`, `{report}`, `{question}` and `{context}` are replaced
+/// before sending
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Prompts {
+    pub system: String,
+    pub report: String,
+    pub question: String,
+}
+
+impl Default for Prompts {
+    fn default() -> Self {
+        Prompts {
+            system: "You are the reporting assistant of a software team. Be brief and factual, \
+                     never invent work that is not in the given data. Reply in {lang}."
+                .to_string(),
+            report: "Rewrite this work report as a short, readable update for the team channel. \
+                     Keep the heading, group related commits and keep every commit ID.\n\n{report}"
+                .to_string(),
+            question: "Answer the question using only the context below. If the context does not \
+                       contain the answer, say you don't know.\n\nContext:\n{context}\n\nQuestion: {question}"
+                .to_string(),
+        }
+    }
+}
+
+impl Prompts {
+    /// loads the templates from the JSON file in `LLM_PROMPTS_PATH`, missing keys keep their default
+    fn from_env() -> Prompts {
+        let path = match env::var("LLM_PROMPTS_PATH") {
+            Ok(path) => path,
+            Err(_) => return Prompts::default(),
+        };
+
+        match fs::read_to_string(&path)
+            .map_err(|err| err.to_string())
+            .and_then(|content| serde_json::from_str(&content).map_err(|err| err.to_string()))
+        {
+            Ok(prompts) => prompts,
+            Err(err) => {
+                log::error!("failed to read prompts from {}, using the defaults: {}", path, err);
+                Prompts::default()
+            }
+        }
+    }
+}
+
+fn fill(template: &str, values: &[(&str, &str)]) -> String {
+    values
+        .iter()
+        .fold(template.to_string(), |text, (name, value)| text.replace(&format!("{{{}}}", name), value))
+}
+
+/// free-form text generation for reports and open questions, every method
+/// returns None when no backend is configured or it fails so callers can fall back
+#[derive(Clone)]
+pub struct Llm {
+    backend: Option<Arc<dyn TextGenerator>>,
+    prompts: Arc<Prompts>,
+}
+
+impl Llm {
+    pub fn new(backend: Option<Arc<dyn TextGenerator>>, prompts: Prompts) -> Llm {
+        Llm {
+            backend,
+            prompts: Arc::new(prompts),
+        }
+    }
+
+    pub fn from_env() -> Llm {
+        let backend = OpenAiCompatible::from_env().map(|backend| Arc::new(backend) as Arc<dyn TextGenerator>);
+        match &backend {
+            Some(_) => log::info!("Using the LLM at {}", env::var("LLM_API_URL").unwrap_or_default()),
+            None => log::info!("No LLM configured, answers are extracted from the context"),
+        }
+
+        Llm::new(backend, Prompts::from_env())
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.backend.is_some()
+    }
+
+    /// sends the prompt after the system message and the earlier turns of the conversation
+    async fn generate(&self, lang: Lang, history: &[ChatMessage], prompt: String) -> Option<String> {
+        let backend = self.backend.as_ref()?;
+        let mut messages = vec![ChatMessage::system(fill(&self.prompts.system, &[("lang", lang.name())]))];
+        messages.extend_from_slice(history);
+        messages.push(ChatMessage::user(prompt));
+
+        match backend.generate(&messages).await {
+            Ok(text) => Some(text),
+            Err(err) => {
+                log::error!("LLM generation failed: {}", err);
+                None
+            }
+        }
+    }
+
+    /// rephrases a rendered report
+    pub async fn phrase_report(&self, report: &str, lang: Lang) -> Option<String> {
+        let prompt = fill(&self.prompts.report, &[("report", report), ("lang", lang.name())]);
+        self.generate(lang, &[], prompt).await
+    }
+
+    /// answers a question from retrieved knowledge or commits, `history` holds
+    /// the earlier questions and answers of the conversation
+    pub async fn answer(&self, question: &str, context: &str, lang: Lang, history: &[ChatMessage]) -> Option<String> {
+        let prompt = fill(
+            &self.prompts.question,
+            &[("question", question), ("context", context), ("lang", lang.name())],
+        );
+        self.generate(lang, history, prompt).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// answers every request with the same reply and remembers what it was sent
+    struct FakeGenerator {
+        reply: Option<String>,
+        sent: Mutex<Vec<Vec<ChatMessage>>>,
+    }
+
+    impl FakeGenerator {
+        fn new(reply: Option<&str>) -> Arc<FakeGenerator> {
+            Arc::new(FakeGenerator {
+                reply: reply.map(str::to_string),
+                sent: Mutex::new(Vec::new()),
+            })
+        }
+    }
+
+    impl TextGenerator for FakeGenerator {
+        fn generate<'a>(&'a self, messages: &'a [ChatMessage]) -> Generation<'a> {
+            self.sent.lock().unwrap().push(messages.to_vec());
+            let reply = self.reply.clone().ok_or(LlmError::EmptyResponse);
+            Box::pin(async move { reply })
+        }
+    }
+
+    fn prompts() -> Prompts {
+        Prompts {
+            system: "reply in {lang}".to_string(),
+            report: "rewrite: {report}".to_string(),
+            question: "{context} | {question}".to_string(),
+        }
+    }
+
+    #[test]
+    fn fills_every_placeholder() {
+        assert_eq!(fill("{a} and {b}, {a}", &[("a", "1"), ("b", "2")]), "1 and 2, 1");
+        assert_eq!(fill("{unknown}", &[("a", "1")]), "{unknown}");
+    }
+
+    #[tokio::test]
+    async fn answers_after_the_conversation() {
+        let fake = FakeGenerator::new(Some("it was Budi"));
+        let llm = Llm::new(Some(fake.clone() as Arc<dyn TextGenerator>), prompts());
+        let history = vec![
+            ChatMessage::user("who merged it?".to_string()),
+            ChatMessage::assistant("nobody yet".to_string()),
+        ];
+
+        let answer = llm.answer("and now?", "Budi merged !4", Lang::Id, &history).await;
+        assert_eq!(answer.as_deref(), Some("it was Budi"));
+
+        let sent = fake.sent.lock().unwrap();
+        let roles: Vec<&str> = sent[0].iter().map(|message| message.role.as_str()).collect();
+        assert_eq!(roles, ["system", "user", "assistant", "user"]);
+        assert_eq!(sent[0][0].content, "reply in Bahasa Indonesia");
+        assert_eq!(sent[0][3].content, "Budi merged !4 | and now?");
+    }
+
+    #[tokio::test]
+    async fn falls_back_without_a_reply() {
+        let llm = Llm::new(None, prompts());
+        assert!(!llm.is_enabled());
+        assert_eq!(llm.phrase_report("report", Lang::En).await, None);
+
+        let fake = FakeGenerator::new(None);
+        let llm = Llm::new(Some(fake.clone() as Arc<dyn TextGenerator>), prompts());
+        assert_eq!(llm.phrase_report("report", Lang::En).await, None);
+        assert_eq!(fake.sent.lock().unwrap()[0][1].content, "rewrite: report");
+    }
+}
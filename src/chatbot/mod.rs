@@ -1,29 +1,36 @@
+use chrono::Utc;
 use dotenv::dotenv;
-use rust_bert::pipelines::common::{ModelResource, ModelType};
-use rust_bert::pipelines::question_answering::{
-    QaInput, QuestionAnsweringConfig, QuestionAnsweringModel,
-};
-use rust_bert::resources::LocalResource;
-use teloxide::dptree::di::Injectable;
-use core::panic;
-use std::future::Future;
-use std::io::Read;
-use std::path::PathBuf;
-use std::{env, fs, thread};
 use std::{
-    error::Error,
-    sync::{
-        mpsc::Sender,
-        Arc, Mutex, RwLock,
-    },
+    collections::HashSet,
+    sync::{Arc, RwLock},
+    time::Duration,
 };
-use teloxide::types::Me;
+use teloxide::types::{InputFile, Me, Recipient};
 use teloxide::{
-    dispatching::{dialogue::InMemStorage, DefaultKey},
+    dispatching::{dialogue::InMemStorage, UpdateHandler},
     prelude::*,
 };
 
+mod conversation;
+pub mod llm;
+
+use crate::auth::{ApiKeys, Scope};
 use crate::context;
+use crate::draft::{self, DeliveryStatus, DraftAction, DraftId, DraftTimers};
+use crate::feedback::{self, AnsweredQuestion, Rating};
+use crate::gitlab::GitlabUser;
+use crate::i18n::{Lang, Text};
+use crate::intent::{self, CommitQuery, Intent};
+use crate::knowledge::{Chunk, KnowledgeBase};
+use crate::metrics;
+use crate::qa::{Qa, QaAnswer};
+use crate::report::{self, DateRange, ReportWriter};
+use crate::scheduler;
+use crate::shutdown::{self, Shutdown};
+use crate::standup::{self, StandupAnswer, StandupMember};
+use crate::webhook::{self, UpdateReceiver, WebhookConfig};
+use conversation::Conversation;
+use llm::Llm;
 
 type MyDialogue = Dialogue<State, InMemStorage<State>>;
 type HandlerResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
@@ -36,27 +43,53 @@ pub enum State {
     ReceiveGitlabToken {
         full_name: String,
     },
-    ReceiveLocation {
-        full_name: String,
-        age: u8,
+    General {
+        conversation: Conversation,
+    },
+    // the conversation is the one the edit interrupted, resumed once the draft is revised
+    EditDraft {
+        id: DraftId,
+        conversation: Option<Conversation>,
+    },
+    // the conversation is the one the stand-up interrupted, resumed once it is answered
+    StandupPlans {
+        conversation: Option<Conversation>,
     },
-    General,
+    StandupBlockers {
+        plans: String,
+        conversation: Option<Conversation>,
+    },
+}
+
+impl State {
+    /// label of the state in the metrics
+    fn name(&self) -> &'static str {
+        match self {
+            State::Start => "start",
+            State::ReceiveFullName => "receive_full_name",
+            State::ReceiveGitlabToken { .. } => "receive_gitlab_token",
+            State::General { .. } => "general",
+            State::EditDraft { .. } => "edit_draft",
+            State::StandupPlans { .. } => "standup_plans",
+            State::StandupBlockers { .. } => "standup_blockers",
+        }
+    }
 }
 
+/// runs the bot until the shutdown, returns false when deliveries were cut short
 pub async fn serve(
-    tx: Sender<Dispatcher<Bot, Box<dyn Error + Send + Sync>, DefaultKey>>,
+    shutdown: Shutdown,
+    bot: Bot,
     ctxt: Arc<RwLock<context::Context>>,
-) {
+    writer: ReportWriter,
+    keys: ApiKeys,
+    qa: Qa,
+    webhook: Option<(WebhookConfig, UpdateReceiver)>,
+) -> bool {
     dotenv().ok(); // Load the .env file if it exists
 
-    // Retrieve the BOT_TOKEN from the environment variable
-    let bot_token = env::var("BOT_TOKEN").expect("BOT_TOKEN not found in the environment");
-    // store gitlab user data
-
-    pretty_env_logger::init();
     log::info!("Starting digireport bot...");
 
-    let bot = Bot::new(bot_token);
     let bot_info = bot.get_me();
     let mut me: Option<Me> = None;
 
@@ -68,7 +101,7 @@ pub async fn serve(
         Err(err) => println!("Error: {:?}", err),
     }
 
-    if !me.is_some() {
+    if me.is_none() {
         println!("Bot info is none");
     }
 
@@ -76,60 +109,111 @@ pub async fn serve(
 
     ctxt.write().unwrap().set_bot(u_me);
 
-    //    Set-up Question Answering model
-    let config = QuestionAnsweringConfig::new(
-        ModelType::Bert,
-        ModelResource::Torch(Box::new(LocalResource {
-            local_path: PathBuf::from("/Users/mac/StartUp/digireport-rs/rust_model.ot"),
-        })),
-        LocalResource {
-            local_path: PathBuf::from("/Users/mac/StartUp/digireport-rs/config.json"),
-        },
-        LocalResource {
-            local_path: PathBuf::from("/Users/mac/StartUp/digireport-rs/vocab.txt"),
-        },
-        None, //merges resource only relevant with ModelType::Roberta
-        false,
-        false,
-        None,
-    );
-
-    let qa_model = QuestionAnsweringModel::new(config);
-    let qa_model_result: QuestionAnsweringModel;
-    match qa_model {
-        Ok(model) => {
-            qa_model_result = model;
-        }
-        Err(err) => {
-            println!("Error: {:?}", err);
-            panic!("Error no model");
+    let knowledge = KnowledgeBase::from_env();
+    let watcher = tokio::spawn(knowledge.clone().watch(Arc::clone(&ctxt), shutdown.clone()));
+
+    let llm = writer.llm.clone();
+    let timers = DraftTimers::new(shutdown.clone());
+    let reports = tokio::spawn(scheduler::run(
+        bot.clone(),
+        Arc::clone(&ctxt),
+        writer.clone(),
+        timers.clone(),
+        shutdown.clone(),
+    ));
+
+    let memory_state = InMemStorage::<State>::new();
+    let standups = tokio::spawn(standup::run(
+        bot.clone(),
+        Arc::clone(&ctxt),
+        Arc::clone(&memory_state),
+        shutdown.clone(),
+    ));
+    let (drafts_bot, drafts_ctxt) = (bot.clone(), Arc::clone(&ctxt));
+    let deps = dptree::deps![memory_state, ctxt, qa, knowledge, writer, llm, keys, timers.clone()];
+
+    // telegram only delivers updates to the webhook once it is registered
+    if let Some((config, _)) = &webhook {
+        log::info!("Receiving updates through the webhook");
+        if let Err(err) = bot
+            .set_webhook(config.url.clone())
+            .secret_token(config.secret_token.clone())
+            .await
+        {
+            log::error!("failed to register the webhook: {}", err);
         }
     }
 
-    let qa_model_safe = Arc::new(Mutex::new(qa_model_result));
+    let mut server_bot = Dispatcher::builder(bot, schema()).dependencies(deps).build();
 
-    let memory_state = InMemStorage::<State>::new();
-    let deps = dptree::deps![memory_state, ctxt, qa_model_safe];
-
-    let mut server_bot = Dispatcher::builder(
-        bot,
-        Update::filter_message()
-            .enter_dialogue::<Message, InMemStorage<State>, State>()
-            .branch(dptree::case![State::Start].endpoint(start))
-            .branch(dptree::case![State::ReceiveFullName].endpoint(receive_full_name))
-            .branch(dptree::case![State::ReceiveLocation { full_name, age }].endpoint(receive_location))
-            .branch(dptree::case![State::General].endpoint(general))
-            .branch(dptree::case![State::ReceiveGitlabToken { full_name }].endpoint(gitlab_token)),
-    )
-    .dependencies(deps)
-    .enable_ctrlc_handler()
-    .build();
-
-    server_bot.dispatch().await;
-
-    let _ = tx.send(server_bot);
+    // the dispatcher finishes the updates it is handling and stops taking new ones
+    let token = server_bot.shutdown_token();
+    let stop = shutdown.clone();
+    tokio::spawn(async move {
+        stop.wait().await;
+        // idle until dispatching has started
+        while token.shutdown().is_err() {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    });
+
+    match webhook {
+        Some((_, updates)) => {
+            server_bot
+                .dispatch_with_listener(
+                    webhook::listener(updates, shutdown.clone()),
+                    LoggingErrorHandler::with_custom_text("An error from the webhook listener"),
+                )
+                .await;
+        }
+        // polling removes any webhook left from an earlier deployment
+        None => server_bot.dispatch().await,
+    }
+
+    // reports, stand-up digests and drafts being sent and the embedding index being saved
+    // are finished before the thread exits, drafts still waiting for review are dropped
+    let drained = async {
+        let _ = reports.await;
+        let _ = standups.await;
+        timers.drain().await;
+        draft::drop_pending(&drafts_bot, &drafts_ctxt).await;
+        let _ = watcher.await;
+    };
+    match tokio::time::timeout(shutdown::drain_timeout(), drained).await {
+        Ok(()) => true,
+        Err(_) => {
+            log::warn!("the reports, stand-ups or knowledge base did not finish within the shutdown timeout");
+            false
+        }
+    }
 }
 
+/// routes commands before the dialogue state so every state understands them
+fn schema() -> UpdateHandler<Box<dyn std::error::Error + Send + Sync + 'static>> {
+    dptree::entry()
+        .branch(
+            Update::filter_message()
+                .enter_dialogue::<Message, InMemStorage<State>, State>()
+                .inspect(|state: State| metrics::MESSAGES.with_label_values(&[state.name()]).inc())
+                .branch(dptree::filter_map(|msg: Message| msg.text().and_then(Command::parse)).endpoint(command))
+                .branch(dptree::case![State::Start].endpoint(start))
+                .branch(dptree::case![State::ReceiveFullName].endpoint(receive_full_name))
+                .branch(dptree::case![State::General { conversation }].endpoint(general))
+                .branch(dptree::case![State::ReceiveGitlabToken { full_name }].endpoint(gitlab_token))
+                .branch(dptree::case![State::EditDraft { id, conversation }].endpoint(edit_draft))
+                .branch(dptree::case![State::StandupPlans { conversation }].endpoint(standup_plans))
+                .branch(dptree::case![State::StandupBlockers { plans, conversation }].endpoint(standup_blockers)),
+        )
+        .branch(
+            Update::filter_callback_query()
+                .enter_dialogue::<CallbackQuery, InMemStorage<State>, State>()
+                .branch(
+                    dptree::filter(|q: CallbackQuery| q.data.as_deref().and_then(Rating::parse).is_some())
+                        .endpoint(feedback_callback),
+                )
+                .branch(dptree::endpoint(draft_callback)),
+        )
+}
 
 async fn start(
     bot: Bot, 
@@ -137,133 +221,586 @@ async fn start(
     ctxt: Arc<RwLock<context::Context>>,
     msg: Message
 ) -> HandlerResult<()> {
-    match msg.text() {
-        Some(reply) => {
-            let question_1 = reply.to_string();
-            let user = msg.from();
-            println!("here {}", reply);
-
-            // check string contain / or not
-            if question_1.starts_with("/") {
-                // switch the cmd and arguments
-                let mut parts = question_1[1..].splitn(2, ' ');
-                let command = parts.next().unwrap_or("").to_lowercase();
-                let argument = parts.next().unwrap_or("");
-                println!("here {}", command.as_str());
-                match command.as_str() {
-                    "repo" | "repository" => {
-                        let guser =  user.unwrap();
-
-                        let msg_clone = msg.clone();
-                        let id: UserId = guser.clone().id;
-
-                        let kkctx1 = ctxt.read();
-                        let kkctx = kkctx1.ok().unwrap();
-                        let usaaa = kkctx.get_gitlab_user(id);
-                        let ctxtp = usaaa.unwrap().clone();
-                        let repositories = ctxtp.get_repositories().await;
-                        if repositories.is_ok() {
-                            let repos = repositories.unwrap();
-                                let mut message = String::new();
-        
-                                for repo in repos {
-                                    message.push_str(&format!(
-                                        "id: {}\nname: {}\ndescription: {}\nvisibility: {}\n\n",
-                                        repo.id,
-                                        repo.name,
-                                        repo.description.unwrap_or("".to_string()),
-                                        repo.visibility
-                                    ));
-                                }
-                                bot.send_message(msg.chat.id, message).await;
-                        }else {
-                            bot.send_message(msg.chat.id, "sorry, i don't understand").await;
-                        }
-                       
-                         return Ok(());
-                    }
-                    "add_token" => {
-                        // get all repository of user using token
-                        let token = argument.to_string();
-                        let user = msg.from();
-            
-                        match user {
-                            Some(user) => {
-                                ctxt.write().unwrap().register_gitlab_user(user.id, token);
-                                bot.send_message(msg.chat.id, "your token has been saved").await?;
-                                dialogue.update(State::Start).await?;
-                                return Ok(());
-                            }
-                            None => {
-                                bot.send_message(msg.chat.id, "Error: User not found")
-                                    .await?;
-                            }
-                        }
+    let lang = lang_of(&ctxt, &msg);
+
+    if msg.text().is_none() {
+        bot.send_message(msg.chat.id, Text::NotUnderstood.get(lang))
+                        .await?;
+    }
+
+    bot.send_message(msg.chat.id, Text::AskFullName.get(lang))
+        .await?;
+    dialogue.update(State::ReceiveFullName).await?;
+    Ok(())
+}
+
+/// a slash command and its argument, `/report last week` is `report` and `last week`
+#[derive(Clone, Debug, PartialEq)]
+struct Command {
+    name: String,
+    argument: String,
+}
+
+impl Command {
+    /// None when the text is not a command
+    fn parse(text: &str) -> Option<Command> {
+        let mut parts = text.strip_prefix('/')?.splitn(2, ' ');
+        Some(Command {
+            name: parts.next().unwrap_or("").to_lowercase(),
+            argument: parts.next().unwrap_or("").to_string(),
+        })
+    }
+}
+
+/// handles a command whatever the dialogue is waiting for, the dialogue stays where it was
+/// so a command sent during a conversation, a stand-up or a draft edit does not end it
+#[allow(clippy::too_many_arguments)]
+async fn command(
+    bot: Bot,
+    dialogue: MyDialogue,
+    ctxt: Arc<RwLock<context::Context>>,
+    writer: ReportWriter,
+    keys: ApiKeys,
+    state: State,
+    Command { name: command, argument }: Command,
+    msg: Message,
+) -> HandlerResult<()> {
+    let lang = lang_of(&ctxt, &msg);
+    let user = msg.from();
+    let argument = argument.as_str();
+
+    match command.as_str() {
+        "repo" | "repository" => {
+            let gitlab_user = user.and_then(|user| ctxt.read().unwrap().get_gitlab_user(user.id).cloned());
+            let gitlab_user = match gitlab_user {
+                Some(gitlab_user) => gitlab_user,
+                None => {
+                    bot.send_message(msg.chat.id, Text::RegisterTokenFirst.get(lang)).await?;
+                    return Ok(());
+                }
+            };
+
+            match gitlab_user.get_repositories().await {
+                Ok(repos) if repos.is_empty() => {
+                    bot.send_message(msg.chat.id, Text::RepositoriesNone.get(lang)).await?;
+                }
+                Ok(repos) => {
+                    let entries: Vec<String> = repos
+                        .into_iter()
+                        .map(|repo| {
+                            Text::RepositoryEntry.fmt(
+                                lang,
+                                &[
+                                    &repo.id.to_string(),
+                                    &repo.name,
+                                    &repo.description.unwrap_or_default(),
+                                    &repo.visibility,
+                                ],
+                            )
+                        })
+                        .collect();
+                    for part in report::split_message(&entries.join("\n\n")) {
+                        bot.send_message(msg.chat.id, part).await?;
                     }
-                    _ => {
-                        bot.send_message(msg.chat.id, "sorry, i don't understand")
-                            .await?;
+                }
+                Err(err) => {
+                    log::error!("failed to list the repositories: {}", err);
+                    bot.send_message(msg.chat.id, Text::RepositoriesFailed.get(lang)).await?;
+                }
+            }
+
+            return Ok(());
+        }
+        "report" => {
+            report_command(&bot, &msg, &ctxt, &writer, argument).await?;
+        }
+        "link_channel" => {
+            link_channel(&bot, &msg, &ctxt, argument).await?;
+        }
+        "join_standup" | "leave_standup" => {
+            if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+                bot.send_message(msg.chat.id, Text::StandupGroupOnly.get(lang))
+                    .await?;
+            } else if let Some(user) = msg.from() {
+                let reply = if command == "join_standup" {
+                    let member = StandupMember {
+                        user_id: user.id,
+                        name: user.full_name(),
+                    };
+                    if ctxt.write().unwrap().join_standup(msg.chat.id, member) {
+                        Text::StandupJoined
+                    } else {
+                        Text::StandupAlreadyJoined
                     }
+                } else if ctxt.write().unwrap().leave_standup(msg.chat.id, user.id) {
+                    Text::StandupLeft
+                } else {
+                    Text::StandupNotMember
+                };
+                bot.send_message(msg.chat.id, reply.get(lang)).await?;
+            }
+        }
+        "export_feedback" => {
+            export_feedback(&bot, &msg, lang).await?;
+        }
+        "api_key" => {
+            api_key_command(&bot, &msg, &keys, lang, argument).await?;
+        }
+        "summary" => {
+            let chat_id = summary_chat(&ctxt.read().unwrap(), &msg);
+            let reply = match argument.trim() {
+                "on" if !writer.summarizer.is_enabled() => Text::SummaryUnavailable,
+                "on" => {
+                    ctxt.write().unwrap().set_summary(chat_id, true);
+                    Text::SummaryOn
+                }
+                "off" => {
+                    ctxt.write().unwrap().set_summary(chat_id, false);
+                    Text::SummaryOff
                 }
+                _ => Text::SummaryUsage,
+            };
+            bot.send_message(msg.chat.id, reply.get(lang)).await?;
+        }
+        "lang" => match Lang::from_code(argument.trim()) {
+            Some(new_lang) => {
+                ctxt.write().unwrap().set_lang(msg.chat.id, new_lang);
+                bot.send_message(msg.chat.id, Text::LangChanged.fmt(new_lang, &[new_lang.name()]))
+                    .await?;
+            }
+            None => {
+                bot.send_message(msg.chat.id, Text::LangUsage.get(lang)).await?;
+            }
+        },
+        "add_token" => {
+            // get all repository of user using token
+            let token = argument.to_string();
+
+            match user {
+                Some(user) => {
+                    let gitlab_user = load_gitlab_user(user.id, token).await;
+                    ctxt.write().unwrap().register_gitlab_user(user.id, gitlab_user);
+                    bot.send_message(msg.chat.id, Text::TokenSaved.get(lang)).await?;
+                }
+                None => {
+                    bot.send_message(msg.chat.id, Text::UserNotFound.get(lang))
+                        .await?;
+                }
+            }
+        }
+        // only a conversation has anything to forget
+        "reset" if matches!(state, State::General { .. }) => {
+            dialogue
+                .update(State::General {
+                    conversation: Conversation::default(),
+                })
+                .await?;
+            bot.send_message(msg.chat.id, Text::ConversationReset.get(lang)).await?;
+        }
+        _ => {
+            bot.send_message(msg.chat.id, Text::NotUnderstood.get(lang))
+                .await?;
+        }
+    }
+
+    Ok(())
+}
 
-                dialogue.update(State::Start {}).await?;
 
-                return Ok(());
+/// records the sender's telegram language and returns the language to reply in
+fn lang_of(ctxt: &Arc<RwLock<context::Context>>, msg: &Message) -> Lang {
+    let mut ctxt = ctxt.write().unwrap();
+    if let Some(user) = msg.from() {
+        ctxt.detect_lang(user.id, user.language_code.as_deref());
+    }
+    ctxt.lang(msg.chat.id, msg.from().map(|user| user.id))
+}
+
+/// the chat whose `/summary` setting applies to `msg`, a private chat stands for the
+/// channel its user publishes to since scheduled reports read the setting there
+fn summary_chat(ctxt: &context::Context, msg: &Message) -> ChatId {
+    msg.from()
+        .filter(|_| msg.chat.is_private())
+        .and_then(|user| ctxt.get_channel(user.id))
+        .unwrap_or(msg.chat.id)
+}
+
+/// handles `/report [range] [--publish]`, replying with the report in the chat
+/// and posting it to the user's linked channel when asked to publish
+async fn report_command(
+    bot: &Bot,
+    msg: &Message,
+    ctxt: &Arc<RwLock<context::Context>>,
+    writer: &ReportWriter,
+    argument: &str,
+) -> HandlerResult<()> {
+    let lang = lang_of(ctxt, msg);
+    let user = match msg.from() {
+        Some(user) => user,
+        None => {
+            bot.send_message(msg.chat.id, Text::UserNotFound.get(lang)).await?;
+            return Ok(());
+        }
+    };
+
+    let publish = argument.split_whitespace().any(|arg| arg == "--publish");
+    let range_text = argument
+        .split_whitespace()
+        .filter(|arg| *arg != "--publish")
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let range = match DateRange::parse(&range_text, Utc::now().date_naive()) {
+        Ok(range) => range,
+        Err(err) => {
+            bot.send_message(msg.chat.id, Text::ReportUsage.fmt(lang, &[&err.localize(lang)]))
+                .await?;
+            return Ok(());
+        }
+    };
+
+    // clone what we need so the lock is not held across awaits
+    let (gitlab_user, channel) = {
+        let ctxt = ctxt.read().unwrap();
+        (ctxt.get_gitlab_user(user.id).cloned(), ctxt.get_channel(user.id))
+    };
+
+    let gitlab_user = match gitlab_user {
+        Some(gitlab_user) => gitlab_user,
+        None => {
+            bot.send_message(msg.chat.id, Text::RegisterTokenFirst.get(lang))
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let mut report = match report::generate(&gitlab_user, range).await {
+        Ok(report) => report,
+        Err(err) => {
+            log::error!("failed to generate report: {}", err);
+            bot.send_message(msg.chat.id, Text::ReportFailed.get(lang)).await?;
+            return Ok(());
+        }
+    };
+    let summary = {
+        let ctxt = ctxt.read().unwrap();
+        ctxt.summary_enabled(summary_chat(&ctxt, msg))
+    };
+    let text = writer.write(&mut report, lang, summary).await;
+    for part in report::split_message(&text) {
+        bot.send_message(msg.chat.id, part).await?;
+    }
+
+    if publish {
+        match channel {
+            Some(channel) => {
+                for part in report::split_message(&text) {
+                    bot.send_message(channel, part).await?;
+                }
+                bot.send_message(msg.chat.id, Text::ReportPublished.get(lang)).await?;
+            }
+            None => {
+                bot.send_message(msg.chat.id, Text::NoLinkedChannel.get(lang))
+                    .await?;
             }
         }
+    }
+
+    Ok(())
+}
+
+/// handles `/link_channel @channel`, the bot has to be an admin allowed to post there
+async fn link_channel(
+    bot: &Bot,
+    msg: &Message,
+    ctxt: &Arc<RwLock<context::Context>>,
+    argument: &str,
+) -> HandlerResult<()> {
+    let lang = lang_of(ctxt, msg);
+    let user = match msg.from() {
+        Some(user) => user,
         None => {
-            bot.send_message(msg.chat.id, "sorry, i don't understand")
-                            .await?;
+            bot.send_message(msg.chat.id, Text::UserNotFound.get(lang)).await?;
+            return Ok(());
+        }
+    };
+
+    let name = argument.trim();
+    if !name.starts_with('@') || name.len() < 2 {
+        bot.send_message(msg.chat.id, Text::LinkChannelUsage.get(lang)).await?;
+        return Ok(());
+    }
+
+    let recipient = Recipient::ChannelUsername(name.to_string());
+    let chat = match bot.get_chat(recipient.clone()).await {
+        Ok(chat) => chat,
+        Err(err) => {
+            log::warn!("failed to look up {}: {}", name, err);
+            bot.send_message(msg.chat.id, Text::ChannelNotFound.fmt(lang, &[name]))
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let bot_id = ctxt.read().unwrap().get_bot().id;
+    let can_post = match bot.get_chat_member(recipient, bot_id).await {
+        Ok(member) => member.is_privileged() && (!chat.is_channel() || member.can_post_messages()),
+        Err(err) => {
+            log::warn!("failed to get bot membership in {}: {}", name, err);
+            false
         }
+    };
+
+    if !can_post {
+        bot.send_message(msg.chat.id, Text::NotChannelAdmin.fmt(lang, &[name]))
+            .await?;
+        return Ok(());
     }
 
-    bot.send_message(msg.chat.id, "Let's start! What's your full name?")
+    ctxt.write().unwrap().set_channel(user.id, chat.id);
+    bot.send_message(msg.chat.id, Text::ChannelLinked.fmt(lang, &[name]))
         .await?;
-    dialogue.update(State::ReceiveFullName).await?;
+
+    Ok(())
+}
+
+/// handles the Approve / Edit / Skip buttons under a draft report
+async fn draft_callback(
+    bot: Bot,
+    dialogue: MyDialogue,
+    ctxt: Arc<RwLock<context::Context>>,
+    timers: DraftTimers,
+    q: CallbackQuery,
+) -> HandlerResult<()> {
+    let (action, id) = match q.data.as_deref().and_then(DraftAction::parse) {
+        Some(parsed) => parsed,
+        None => {
+            bot.answer_callback_query(q.id).await?;
+            return Ok(());
+        }
+    };
+
+    let (is_author, lang) = {
+        let mut ctxt = ctxt.write().unwrap();
+        ctxt.detect_lang(q.from.id, q.from.language_code.as_deref());
+        let is_author = ctxt.get_draft_mut(id).map(|draft| draft.author == q.from.id);
+        (is_author, ctxt.user_lang(q.from.id))
+    };
+
+    let reply = match is_author {
+        None => Text::DraftHandled,
+        Some(false) => Text::DraftNotAuthor,
+        Some(true) => match action {
+            DraftAction::Approve => {
+                timers.cancel(id);
+                draft::publish(&bot, &ctxt, id).await?;
+                Text::ReportPublished
+            }
+            DraftAction::Edit => {
+                // the review window keeps running, a draft left half edited is still published
+                let conversation = match dialogue.get().await? {
+                    Some(State::General { conversation }) => Some(conversation),
+                    Some(State::EditDraft { conversation, .. })
+                    | Some(State::StandupPlans { conversation })
+                    | Some(State::StandupBlockers { conversation, .. }) => conversation,
+                    _ => None,
+                };
+                dialogue.update(State::EditDraft { id, conversation }).await?;
+                bot.send_message(q.from.id, Text::DraftEditPrompt.get(lang)).await?;
+                Text::DraftWaiting
+            }
+            DraftAction::Skip => {
+                timers.cancel(id);
+                let mut ctxt = ctxt.write().unwrap();
+                ctxt.take_draft(id);
+                ctxt.set_delivery_status(id, DeliveryStatus::Skipped);
+                Text::DraftSkipped
+            }
+        },
+    };
+
+    bot.answer_callback_query(q.id).text(reply.get(lang)).await?;
+
+    // the buttons are stale once the draft is published or skipped
+    if is_author == Some(true) && action != DraftAction::Edit {
+        if let Some(message) = q.message {
+            bot.edit_message_reply_markup(message.chat.id, message.id).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// receives the revised text (or notes) for a draft the author chose to edit
+async fn edit_draft(
+    bot: Bot,
+    dialogue: MyDialogue,
+    ctxt: Arc<RwLock<context::Context>>,
+    timers: DraftTimers,
+    (id, conversation): (DraftId, Option<Conversation>),
+    msg: Message,
+) -> HandlerResult<()> {
+    let lang = lang_of(&ctxt, &msg);
+    let text = match msg.text() {
+        Some(text) => text.to_string(),
+        None => {
+            bot.send_message(msg.chat.id, Text::PlainTextOnly.get(lang)).await?;
+            return Ok(());
+        }
+    };
+
+    let draft = ctxt.write().unwrap().get_draft_mut(id).map(|draft| {
+        match text.strip_prefix("notes:") {
+            Some(notes) => draft.notes = Some(notes.trim().to_string()),
+            None => draft.text = text,
+        }
+        draft.clone()
+    });
+
+    match draft {
+        Some(draft) => {
+            draft::send_preview(&bot, id, &draft, lang).await?;
+            // the revised draft gets a new review window
+            timers.start(&bot, &ctxt, id);
+        }
+        None => {
+            bot.send_message(msg.chat.id, Text::DraftHandled.get(lang)).await?;
+        }
+    }
+
+    // back to where the user was when they chose to edit
+    match conversation {
+        Some(conversation) => dialogue.update(State::General { conversation }).await?,
+        None => dialogue.update(State::Start).await?,
+    }
+
+    Ok(())
+}
+
+async fn standup_plans(
+    bot: Bot,
+    dialogue: MyDialogue,
+    ctxt: Arc<RwLock<context::Context>>,
+    conversation: Option<Conversation>,
+    msg: Message,
+) -> HandlerResult<()> {
+    let lang = lang_of(&ctxt, &msg);
+
+    match msg.text() {
+        Some(text) => {
+            bot.send_message(msg.chat.id, Text::StandupAskBlockers.get(lang))
+                .await?;
+            dialogue
+                .update(State::StandupBlockers {
+                    plans: text.into(),
+                    conversation,
+                })
+                .await?;
+        }
+        None => {
+            bot.send_message(msg.chat.id, Text::PlainTextOnly.get(lang)).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn standup_blockers(
+    bot: Bot,
+    dialogue: MyDialogue,
+    ctxt: Arc<RwLock<context::Context>>,
+    (plans, conversation): (String, Option<Conversation>),
+    msg: Message,
+) -> HandlerResult<()> {
+    let lang = lang_of(&ctxt, &msg);
+
+    match (msg.text(), msg.from()) {
+        (Some(text), Some(user)) => {
+            let answer = StandupAnswer {
+                plans,
+                blockers: text.to_string(),
+            };
+            ctxt.write().unwrap().set_standup_answer(user.id, answer);
+            bot.send_message(msg.chat.id, Text::StandupThanks.get(lang))
+                .await?;
+            // back to where the user was when the stand-up started
+            match conversation {
+                Some(conversation) => dialogue.update(State::General { conversation }).await?,
+                None => dialogue.update(State::Start).await?,
+            }
+        }
+        _ => {
+            bot.send_message(msg.chat.id, Text::PlainTextOnly.get(lang)).await?;
+        }
+    }
+
     Ok(())
 }
 
+/// reads who owns the token so reports only list their work, reports read it again
+/// when Gitlab cannot be reached now
+async fn load_gitlab_user(user_id: UserId, token: String) -> GitlabUser {
+    let mut gitlab_user = GitlabUser::new(token);
+    if let Err(err) = gitlab_user.load_account().await {
+        log::warn!("failed to read the Gitlab account of {}: {}", user_id, err);
+    }
+
+    gitlab_user
+}
 
 async fn gitlab_token(
     bot: Bot,
     dialogue: MyDialogue,
-    full_name: String,
     ctxt: Arc<RwLock<context::Context>>,
     msg: Message,
 ) -> HandlerResult<()> {
+    let lang = lang_of(&ctxt, &msg);
+
     match msg.text() {
         Some(text) => {
-            bot.send_message(msg.chat.id, "Processing Token").await?;
+            bot.send_message(msg.chat.id, Text::ProcessingToken.get(lang)).await?;
 
             let token = text.to_string();
             let user = msg.from();
 
             match user {
                 Some(user) => {
-                    ctxt.write().unwrap().register_gitlab_user(user.id, token);
-                    bot.send_message(msg.chat.id, "Token saved").await?;
-                    dialogue.update(State::General).await?;
+                    let gitlab_user = load_gitlab_user(user.id, token).await;
+                    ctxt.write().unwrap().register_gitlab_user(user.id, gitlab_user);
+                    bot.send_message(msg.chat.id, Text::TokenSaved.get(lang)).await?;
+                    dialogue
+                        .update(State::General {
+                            conversation: Conversation::default(),
+                        })
+                        .await?;
                     return Ok(());
                 }
                 None => {
-                    bot.send_message(msg.chat.id, "Error: User not found")
+                    bot.send_message(msg.chat.id, Text::UserNotFound.get(lang))
                         .await?;
                 }
             }
         }
         None => {
-            bot.send_message(msg.chat.id, "Send me token text.").await?;
+            bot.send_message(msg.chat.id, Text::TokenTextOnly.get(lang)).await?;
         }
     }
 
     Ok(())
 }
 
-async fn receive_full_name(bot: Bot, dialogue: MyDialogue, msg: Message) -> HandlerResult<()> {
+async fn receive_full_name(
+    bot: Bot,
+    dialogue: MyDialogue,
+    ctxt: Arc<RwLock<context::Context>>,
+    msg: Message,
+) -> HandlerResult<()> {
+    let lang = lang_of(&ctxt, &msg);
+
     match msg.text() {
         Some(text) => {
             let fullname = text.to_string();
-            let text_msg = format!("Give me your gitlab token, {}?", fullname);
+            let text_msg = Text::AskGitlabToken.fmt(lang, &[&fullname]);
             bot.send_message(msg.chat.id, text_msg).await?;
             dialogue
                 .update(State::ReceiveGitlabToken {
@@ -272,140 +809,547 @@ async fn receive_full_name(bot: Bot, dialogue: MyDialogue, msg: Message) -> Hand
                 .await?;
         }
         None => {
-            bot.send_message(msg.chat.id, "Send me plain text.").await?;
+            bot.send_message(msg.chat.id, Text::PlainTextOnly.get(lang)).await?;
         }
     }
 
     Ok(())
 }
 
-async fn receive_age(
+/// sends an answer with thumbs up and down buttons and keeps it until it is rated
+async fn send_rated_answer(
+    bot: &Bot,
+    ctxt: &Arc<RwLock<context::Context>>,
+    msg: &Message,
+    reply: String,
+    answered: AnsweredQuestion,
+) -> HandlerResult<()> {
+    let id = ctxt.write().unwrap().add_answer(answered);
+    bot.send_message(msg.chat.id, reply)
+        .reply_markup(feedback::keyboard(id))
+        .await?;
+
+    Ok(())
+}
+
+/// logs a thumbs up or down for an answer, the first rating counts
+async fn feedback_callback(
     bot: Bot,
-    dialogue: MyDialogue,
-    full_name: String, // Available from `State::ReceiveAge`.
-    msg: Message,
+    ctxt: Arc<RwLock<context::Context>>,
+    q: CallbackQuery,
 ) -> HandlerResult<()> {
-    match msg.text().map(|text| text.parse::<u8>()) {
-        Some(Ok(age)) => {
-            bot.send_message(msg.chat.id, "What's your location?")
-                .await?;
-            dialogue
-                .update(State::ReceiveLocation { full_name, age })
-                .await?;
+    let (rating, id) = match q.data.as_deref().and_then(Rating::parse) {
+        Some(parsed) => parsed,
+        None => {
+            bot.answer_callback_query(q.id).await?;
+            return Ok(());
         }
-        _ => {
-            bot.send_message(msg.chat.id, "Send me a number.").await?;
+    };
+
+    let (answered, lang) = {
+        let mut ctxt = ctxt.write().unwrap();
+        ctxt.detect_lang(q.from.id, q.from.language_code.as_deref());
+        (ctxt.take_answer(id), ctxt.user_lang(q.from.id))
+    };
+
+    let reply = match answered {
+        Some(answered) => {
+            if let Err(err) = feedback::record(q.from.id, rating, &answered) {
+                log::error!("failed to record feedback: {}", err);
+            }
+            Text::FeedbackThanks
+        }
+        None => Text::FeedbackExpired,
+    };
+    bot.answer_callback_query(q.id).text(reply.get(lang)).await?;
+
+    if reply == Text::FeedbackThanks {
+        if let Some(message) = q.message {
+            bot.edit_message_reply_markup(message.chat.id, message.id).await?;
         }
     }
 
     Ok(())
 }
 
-async fn receive_location(
-    bot: Bot,
-    dialogue: MyDialogue,
-    wmodel: Arc<Mutex<QuestionAnsweringModel>>,
-    (full_name, age): (String, u8), // Available from `State::ReceiveLocation`.
-    msg: Message,
-) -> HandlerResult<()> {
-    match msg.text() {
-        Some(location) => {
-            let report = format!("Full name: {full_name}\nAge: {age}\nLocation: {location}");
-            bot.send_message(msg.chat.id, report).await?;
-            dialogue.exit().await?;
+/// sends the feedback log as a file to an admin
+/// the sources of `chunks` in order of relevance, each named once
+fn unique_sources(chunks: &[Chunk]) -> Vec<String> {
+    let mut seen = HashSet::new();
+    chunks
+        .iter()
+        .filter(|chunk| seen.insert(chunk.source.as_str()))
+        .map(|chunk| chunk.source.clone())
+        .collect()
+}
+
+async fn export_feedback(bot: &Bot, msg: &Message, lang: Lang) -> HandlerResult<()> {
+    if !msg.from().is_some_and(|user| feedback::is_admin(user.id)) {
+        bot.send_message(msg.chat.id, Text::AdminOnly.get(lang)).await?;
+        return Ok(());
+    }
+
+    let path = feedback::log_path();
+    if !path.is_file() {
+        bot.send_message(msg.chat.id, Text::FeedbackEmpty.get(lang)).await?;
+        return Ok(());
+    }
+
+    if let Err(err) = bot.send_document(msg.chat.id, InputFile::file(path)).await {
+        log::error!("failed to export feedback: {}", err);
+        bot.send_message(msg.chat.id, Text::FeedbackExportFailed.get(lang)).await?;
+    }
+
+    Ok(())
+}
+
+/// handles `/api_key create <name> [scopes]`, `/api_key revoke <id>` and `/api_key list`
+/// for admins, keys are only created in private chats
+async fn api_key_command(bot: &Bot, msg: &Message, keys: &ApiKeys, lang: Lang, argument: &str) -> HandlerResult<()> {
+    let user = match msg.from() {
+        Some(user) if feedback::is_admin(user.id) => user,
+        _ => {
+            bot.send_message(msg.chat.id, Text::AdminOnly.get(lang)).await?;
+            return Ok(());
         }
-        None => {
-            bot.send_message(msg.chat.id, "Send me plain text.").await?;
+    };
+
+    let words: Vec<&str> = argument.split_whitespace().collect();
+    let reply = match words.as_slice() {
+        ["create", name, rest @ ..] => {
+            if !msg.chat.is_private() {
+                bot.send_message(msg.chat.id, Text::ApiKeyPrivateOnly.get(lang)).await?;
+                return Ok(());
+            }
+            // keys only read reports unless told otherwise
+            let names: Vec<&str> = match rest {
+                [] => vec![Scope::ReportsRead.name()],
+                _ => rest.iter().flat_map(|scopes| scopes.split(',')).filter(|scope| !scope.is_empty()).collect(),
+            };
+            let mut scopes = Vec::new();
+            for name in names {
+                match Scope::parse(name) {
+                    Some(scope) if !scopes.contains(&scope) => scopes.push(scope),
+                    Some(_) => {}
+                    None => {
+                        bot.send_message(msg.chat.id, Text::ApiKeyInvalidScope.fmt(lang, &[name])).await?;
+                        return Ok(());
+                    }
+                }
+            }
+
+            let (key, secret) = keys.create(name, scopes, user.id);
+            log::info!(target: "audit", "user {} created API key {} ({})", user.id, key.id, key.name);
+            let scopes = key.scopes.iter().map(Scope::name).collect::<Vec<_>>().join(", ");
+            Text::ApiKeyCreated.fmt(lang, &[&key.id, &scopes, &secret])
         }
-    }
+        ["revoke", id] => {
+            if keys.revoke(id) {
+                log::info!(target: "audit", "user {} revoked API key {}", user.id, id);
+                Text::ApiKeyRevoked.fmt(lang, &[id])
+            } else {
+                Text::ApiKeyNotFound.fmt(lang, &[id])
+            }
+        }
+        ["list"] => {
+            let keys = keys.list();
+            if keys.is_empty() {
+                Text::ApiKeyNone.get(lang).to_string()
+            } else {
+                keys.iter()
+                    .map(|key| {
+                        let scopes = key.scopes.iter().map(Scope::name).collect::<Vec<_>>().join(", ");
+                        format!("{} {} ({}), {}", key.id, key.name, scopes, lang.format_date(key.created_at.date_naive()))
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            }
+        }
+        _ => Text::ApiKeyUsage.get(lang).to_string(),
+    };
+    bot.send_message(msg.chat.id, reply).await?;
 
     Ok(())
 }
 
-fn read_file_to_string(file_path: &std::path::PathBuf) -> std::io::Result<String> {
-    let mut file = fs::File::open(file_path)?;
-    let mut buffer = String::new();
-    file.read_to_string(&mut buffer)?;
+// links listed under an answer about the commit history
+const HISTORY_LINKS: usize = 10;
+
+/// answers a question about the commit history from the matching commits and merge requests,
+/// returns the reply to send
+async fn commit_history(
+    qa: &Qa,
+    llm: &Llm,
+    ctxt: &Arc<RwLock<context::Context>>,
+    msg: &Message,
+    question: &str,
+    query: &mut CommitQuery,
+    conversation: &Conversation,
+) -> String {
+    let lang = lang_of(ctxt, msg);
+    let gitlab_user = match msg.from() {
+        Some(user) => ctxt.read().unwrap().get_gitlab_user(user.id).cloned(),
+        None => None,
+    };
+    let gitlab_user = match gitlab_user {
+        Some(gitlab_user) => gitlab_user,
+        None => return Text::RegisterTokenFirst.get(lang).to_string(),
+    };
+
+    let entries = match intent::find_history(&gitlab_user, question, query).await {
+        Ok(entries) => entries,
+        Err(err) => {
+            log::error!("failed to read the history for {:?}: {}", query, err);
+            return Text::HistoryFailed.get(lang).to_string();
+        }
+    };
+    if entries.is_empty() {
+        let (from, to) = (lang.format_date(query.range.from), lang.format_date(query.range.to));
+        return Text::HistoryEmpty.fmt(lang, &[&from, &to]);
+    }
+
+    let context = entries
+        .iter()
+        .map(|entry| entry.text.as_str())
+        .collect::<Vec<_>>()
+        .join("\n");
+    // a generated answer reads better, the extractive model is the fallback
+    let answer = match llm.answer(question, &context, lang, &conversation.messages()).await {
+        Some(answer) => Some(answer),
+        None => match qa.answer(question.to_string(), context).await {
+            QaAnswer::Answer(candidates) => candidates.into_iter().next().map(|candidate| candidate.text),
+            _ => None,
+        },
+    };
 
-    buffer = buffer.replace('\n', " ");
+    // link the changes the answer was taken from, or every match when there is no answer
+    let matching: Vec<_> = match &answer {
+        Some(answer) if entries.iter().any(|entry| entry.text.contains(answer.as_str())) => entries
+            .iter()
+            .filter(|entry| entry.text.contains(answer.as_str()))
+            .collect(),
+        _ => entries.iter().collect(),
+    };
+    let mut reply = match &answer {
+        Some(answer) => format!("{}\n\n", answer),
+        None => String::new(),
+    };
+    reply.push_str(Text::HistoryMatches.get(lang));
+    for entry in matching.iter().take(HISTORY_LINKS) {
+        match &entry.url {
+            Some(url) => reply.push_str(&format!("\n- {} {}", entry.text, url)),
+            None => reply.push_str(&format!("\n- {}", entry.text)),
+        }
+    }
 
-    Ok(buffer)
+    reply
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn general(
     bot: Bot,
     dialogue: MyDialogue,
-    wmodel: Arc<Mutex<QuestionAnsweringModel>>,
+    qa: Qa,
+    llm: Llm,
+    knowledge: KnowledgeBase,
+    ctxt: Arc<RwLock<context::Context>>,
+    mut conversation: Conversation,
     msg: Message,
 ) -> HandlerResult<()> {
+    let lang = lang_of(&ctxt, &msg);
+
     match msg.text() {
         Some(reply) => {
             let question_1 = reply.to_string();
 
-            // load about me txt
-            let context_path = "about_me.txt";
-            let current_dir = env::current_dir().expect("Failed to get current directory");
-
-            let file_path = current_dir.join(context_path);
-            let context_result = read_file_to_string(&file_path);
-
-            match context_result {
-                Ok(file_content) => {
-                    // check string contain / or not
-                    if question_1.starts_with("/") {
-                        // switch the cmd and arguments
-                        let mut parts = question_1[1..].splitn(2, ' ');
-                        let command = parts.next().unwrap_or("").to_lowercase();
-                        let argument = parts.next().unwrap_or("");
-
-                        match command.as_str() {
-                            "repo" | "repository" => {
-                                // get all repository of user using token
-                            }
-                            _ => {
-                                bot.send_message(msg.chat.id, "sorry, i don't understand")
-                                    .await?;
-                            }
-                        }
+            // answers come from the repositories of the asker, who may not be the one who
+            // registered when the conversation is in a group
+            let user_id = match msg.from() {
+                Some(user) if ctxt.read().unwrap().get_gitlab_user(user.id).is_some() => user.id,
+                _ => {
+                    bot.send_message(msg.chat.id, Text::RegisterTokenFirst.get(lang)).await?;
+                    return Ok(());
+                }
+            };
 
-                        return Ok(());
-                    }
+            // a follow-up keeps the person, repositories and period of the previous history question
+            let today = Utc::now().date_naive();
+            let query = match conversation
+                .last_topic()
+                .and_then(|previous| intent::follow_up(&question_1, today, previous))
+            {
+                Some(query) => Some(query),
+                None => match intent::detect(&question_1, today) {
+                    Intent::CommitHistory(query) => Some(query),
+                    Intent::General => None,
+                },
+            };
+            if let Some(mut query) = query {
+                let reply = commit_history(&qa, &llm, &ctxt, &msg, &question_1, &mut query, &conversation).await;
+                for part in report::split_message(&reply) {
+                    bot.send_message(msg.chat.id, part).await?;
+                }
+                conversation.push(question_1, &reply, Some(query));
+                dialogue.update(State::General { conversation }).await?;
+                return Ok(());
+            }
 
-                    let qa_input_1 = QaInput {
-                        question: question_1,
-                        context: file_content,
-                    };
+            // a follow-up alone rarely says what it is about, search with the previous question too
+            let search = match conversation.last() {
+                Some(turn) if intent::is_follow_up(&question_1) => format!("{} {}", turn.question, question_1),
+                _ => question_1.clone(),
+            };
 
-                    let answers = wmodel.lock().unwrap().predict(&[qa_input_1], 1, 32).clone();
+            // only the most relevant parts of the knowledge base are read by the model
+            let chunks = knowledge.retrieve(&search, user_id).await;
+            if chunks.is_empty() {
+                bot.send_message(msg.chat.id, Text::NoKnowledge.get(lang)).await?;
+                return Ok(());
+            }
+            log::debug!(
+                "answering from {:?}",
+                chunks.iter().map(|chunk| chunk.source.as_str()).collect::<Vec<_>>()
+            );
+            let context = chunks
+                .iter()
+                .map(|chunk| chunk.text.as_str())
+                .collect::<Vec<_>>()
+                .join("\n");
 
-                    if answers.len() > 0 {
-                        let answer = answers[0][0].clone();
-                        println!("{}", answer.score);
-                        if answer.score > 0.07 {
-                            let text_msg = answer.answer.to_string();
+            if let Some(answer) = llm.answer(&question_1, &context, lang, &conversation.messages()).await {
+                // a generated answer can draw on every retrieved chunk
+                let sources = unique_sources(&chunks);
+                let reply = format!("{}\n\n{}", answer, Text::AnswerSource.fmt(lang, &[&sources.join(", ")]));
+                conversation.push(question_1.clone(), &answer, None);
+                let answered = AnsweredQuestion {
+                    question: question_1,
+                    answer,
+                    score: None,
+                    sources,
+                    context,
+                };
+                send_rated_answer(&bot, &ctxt, &msg, reply, answered).await?;
+                dialogue.update(State::General { conversation }).await?;
+                return Ok(());
+            }
 
-                            bot.send_message(msg.chat.id, text_msg).await?;
-                        } else {
-                            bot.send_message(msg.chat.id, "sorry, i don't understand")
-                                .await?;
+            // the extractive model reads the question as asked, the previous one only helped the search
+            match qa.answer(question_1.clone(), context.clone()).await {
+                QaAnswer::Answer(candidates) => {
+                    let best = &candidates[0];
+                    let mut reply = format!(
+                        "{}\n\n{}",
+                        best.text,
+                        Text::AnswerConfidence.fmt(lang, &[&format!("{:.0}", best.score * 100.0)])
+                    );
+                    if candidates.len() > 1 {
+                        reply.push_str(&format!("\n{}", Text::AnswerAlternatives.get(lang)));
+                        for candidate in &candidates[1..] {
+                            reply.push_str(&format!("\n- {} ({:.0}%)", candidate.text, candidate.score * 100.0));
                         }
-                    } else {
-                        bot.send_message(msg.chat.id, "sorry, i don't understand")
-                            .await?;
                     }
+
+                    // name the document the answer was taken from
+                    let chunk = chunks.iter().find(|chunk| chunk.text.contains(&best.text));
+                    if let Some(chunk) = chunk {
+                        reply.push_str(&format!("\n\n{}", Text::AnswerSource.fmt(lang, &[&chunk.source])));
+                    }
+
+                    conversation.push(question_1.clone(), &best.text, None);
+                    let answered = AnsweredQuestion {
+                        question: question_1,
+                        answer: best.text.clone(),
+                        score: Some(best.score),
+                        sources: chunk.map(|chunk| vec![chunk.source.clone()]).unwrap_or_default(),
+                        context: chunk.map(|chunk| chunk.text.clone()).unwrap_or(context),
+                    };
+                    send_rated_answer(&bot, &ctxt, &msg, reply, answered).await?;
+                    dialogue.update(State::General { conversation }).await?;
                 }
-                Err(err) => {
-                    bot.send_message(msg.chat.id, "failed to load information")
+                QaAnswer::NoAnswer => {
+                    bot.send_message(msg.chat.id, Text::NotUnderstood.get(lang))
                         .await?;
                 }
+                QaAnswer::Loading => {
+                    bot.send_message(msg.chat.id, Text::QaLoading.get(lang)).await?;
+                }
+                QaAnswer::Disabled => {
+                    bot.send_message(msg.chat.id, Text::QaDisabled.get(lang)).await?;
+                }
+                QaAnswer::Busy => {
+                    bot.send_message(msg.chat.id, Text::QaBusy.get(lang)).await?;
+                }
+                QaAnswer::TimedOut => {
+                    bot.send_message(msg.chat.id, Text::QaTimedOut.get(lang)).await?;
+                }
             }
 
             return Ok(());
         }
         None => {
-            bot.send_message(msg.chat.id, "kosong").await?;
+            bot.send_message(msg.chat.id, Text::EmptyMessage.get(lang)).await?;
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+    use serde_json::{json, Value};
+    use std::sync::Mutex;
+    use teloxide::dispatching::dialogue::Storage;
+    use teloxide::types::UpdateKind;
+
+    const CHAT_ID: i64 = 42;
+
+    fn message(text: &str) -> Value {
+        json!({
+            "message_id": 1,
+            "date": 1700000000,
+            "chat": { "id": CHAT_ID, "type": "private", "first_name": "Adi" },
+            "from": { "id": CHAT_ID, "is_bot": false, "first_name": "Adi", "language_code": "en" },
+            "text": text,
+        })
+    }
+
+    // the texts of the messages the bot sent
+    type Sent = web::Data<Mutex<Vec<String>>>;
+
+    /// a Telegram API answering every call with the same message
+    fn telegram(sent: Sent) -> Bot {
+        let server = HttpServer::new(move || {
+            App::new().app_data(sent.clone()).default_service(web::to(
+                |req: HttpRequest, body: web::Bytes, sent: Sent| async move {
+                    if req.path().to_lowercase().ends_with("/sendmessage") {
+                        let body: Value = serde_json::from_slice(&body).unwrap();
+                        sent.lock().unwrap().push(body["text"].as_str().unwrap_or_default().to_string());
+                    }
+                    HttpResponse::Ok().json(json!({ "ok": true, "result": message("") }))
+                },
+            ))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let url = format!("http://{}", server.addrs()[0]);
+        actix_rt::spawn(server.run());
+
+        Bot::new("1:token").set_api_url(reqwest::Url::parse(&url).unwrap())
+    }
+
+    /// sends `text` to the bot while the chat is in `state`, returns the replies and the new state
+    async fn send(state: State, text: &str) -> (Vec<String>, State) {
+        let sent = web::Data::new(Mutex::new(Vec::new()));
+        let bot = telegram(sent.clone());
+        let storage = InMemStorage::<State>::new();
+        storage.clone().update_dialogue(ChatId(CHAT_ID), state).await.unwrap();
+        let update = Update {
+            id: 1,
+            kind: UpdateKind::Message(serde_json::from_value(message(text)).unwrap()),
+        };
+        let ctxt = Arc::new(RwLock::new(context::Context::new()));
+
+        let result = schema()
+            .dispatch(dptree::deps![
+                update,
+                bot,
+                Arc::clone(&storage),
+                ctxt,
+                ReportWriter::from_env(),
+                ApiKeys::from_env()
+            ])
+            .await;
+        assert!(matches!(result, std::ops::ControlFlow::Break(Ok(()))), "{:?}", result);
+
+        let state = storage.get_dialogue(ChatId(CHAT_ID)).await.unwrap().unwrap();
+        let sent = sent.lock().unwrap().clone();
+        (sent, state)
+    }
+
+    fn conversation() -> Conversation {
+        let mut conversation = Conversation::default();
+        conversation.push("what is digireport?".to_string(), "a report bot", None);
+        conversation
+    }
+
+    #[test]
+    fn parses_commands() {
+        let command = Command::parse("/Report last week --publish").unwrap();
+        assert_eq!(command.name, "report");
+        assert_eq!(command.argument, "last week --publish");
+        assert_eq!(Command::parse("/reset").unwrap().argument, "");
+        assert_eq!(Command::parse("what is /report?"), None);
+    }
+
+    #[actix_rt::test]
+    async fn commands_are_understood_during_a_conversation() {
+        let (sent, state) = send(State::General { conversation: conversation() }, "/report yesterday").await;
+
+        assert_eq!(sent, vec![Text::RegisterTokenFirst.get(Lang::En)]);
+        match state {
+            State::General { conversation } => {
+                assert_eq!(conversation.last().unwrap().question, "what is digireport?")
+            }
+            state => panic!("the conversation ended in {:?}", state),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn reset_forgets_the_conversation() {
+        let (sent, state) = send(State::General { conversation: conversation() }, "/reset").await;
+
+        assert_eq!(sent, vec![Text::ConversationReset.get(Lang::En)]);
+        match state {
+            State::General { conversation } => assert!(conversation.last().is_none()),
+            state => panic!("the conversation ended in {:?}", state),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn reset_is_only_understood_during_a_conversation() {
+        let (sent, state) = send(State::Start, "/reset").await;
+
+        assert_eq!(sent, vec![Text::NotUnderstood.get(Lang::En)]);
+        assert!(matches!(state, State::Start));
+    }
+
+    #[actix_rt::test]
+    async fn commands_are_not_taken_as_the_draft() {
+        let editing = State::EditDraft {
+            id: 1,
+            conversation: Some(conversation()),
+        };
+        let (sent, state) = send(editing, "/report").await;
+
+        assert_eq!(sent, vec![Text::RegisterTokenFirst.get(Lang::En)]);
+        assert!(matches!(state, State::EditDraft { id: 1, conversation: Some(_) }));
+    }
+
+    #[test]
+    fn names_each_source_once_most_relevant_first() {
+        let chunks: Vec<Chunk> = ["deploy.md", "faq.md", "deploy.md", "api.md", "faq.md"]
+            .iter()
+            .map(|source| Chunk::new(source, None, "text".to_string()))
+            .collect();
+
+        assert_eq!(unique_sources(&chunks), vec!["deploy.md", "faq.md", "api.md"]);
+    }
+
+    #[actix_rt::test]
+    async fn only_admins_export_the_feedback() {
+        std::env::set_var("ADMIN_USER_IDS", format!("7, x,{}", CHAT_ID));
+        std::env::set_var(
+            "FEEDBACK_LOG_PATH",
+            std::env::temp_dir().join(format!("digireport-no-feedback-{}.jsonl", std::process::id())),
+        );
+        let (sent, _) = send(State::Start, "/export_feedback").await;
+        assert_eq!(sent, vec![Text::FeedbackEmpty.get(Lang::En)]);
+
+        std::env::set_var("ADMIN_USER_IDS", "7");
+        let (sent, _) = send(State::Start, "/export_feedback").await;
+        assert_eq!(sent, vec![Text::AdminOnly.get(Lang::En)]);
+    }
+}
@@ -0,0 +1,313 @@
+use rust_bert::pipelines::common::{ModelResource, ModelType};
+use rust_bert::pipelines::zero_shot_classification::{
+    ZeroShotClassificationConfig, ZeroShotClassificationModel,
+};
+use rust_bert::resources::LocalResource;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::gitlab::Commit;
+
+const DEFAULT_CATEGORIES: &str = "feature,bugfix,refactor,docs,infra,test";
+const DEFAULT_LABELS_PATH: &str = "commit_labels.json";
+// classification requests waiting for the worker
+const QUEUE_SIZE: usize = 8;
+// commit titles classified per forward pass
+const BATCH_SIZE: usize = 16;
+// commit titles are short, longer inputs are truncated
+const MAX_LENGTH: usize = 128;
+const TIMEOUT_SECS: u64 = 60;
+
+#[derive(Default, Serialize, Deserialize)]
+struct StoredLabels {
+    // categories the labels were chosen from, the cache is dropped when they change
+    categories: Vec<String>,
+    // category of each commit SHA
+    labels: HashMap<String, String>,
+}
+
+struct ClassifyRequest {
+    titles: Vec<String>,
+    reply: oneshot::Sender<Option<Vec<String>>>,
+}
+
+/// labels commits with a work category, from their Conventional Commits prefix
+/// when they have one and with a zero-shot model otherwise
+#[derive(Clone)]
+pub struct Classifier {
+    categories: Arc<Vec<String>>,
+    labels: Arc<RwLock<HashMap<String, String>>>,
+    labels_path: PathBuf,
+    // None when no model is configured
+    queue: Option<mpsc::Sender<ClassifyRequest>>,
+}
+
+impl Classifier {
+    /// reads `COMMIT_CATEGORIES` and `COMMIT_LABELS_PATH`, the model is loaded from
+    /// `CLASSIFIER_MODEL_DIR` (`rust_model.ot`, `config.json`, `vocab.json`, `merges.txt`)
+    /// with `CLASSIFIER_MODEL_TYPE` `bart` (default) or `roberta`
+    pub fn from_env() -> Classifier {
+        let categories = categories_from_env();
+        let labels_path =
+            PathBuf::from(env::var("COMMIT_LABELS_PATH").unwrap_or_else(|_| DEFAULT_LABELS_PATH.to_string()));
+
+        let labels = match fs::read_to_string(&labels_path) {
+            Ok(content) => match serde_json::from_str::<StoredLabels>(&content) {
+                Ok(stored) if stored.categories == categories => stored.labels,
+                Ok(_) => {
+                    log::info!("Commit categories changed, relabelling commits");
+                    HashMap::new()
+                }
+                Err(err) => {
+                    log::warn!("ignoring unreadable {}: {}", labels_path.display(), err);
+                    HashMap::new()
+                }
+            },
+            Err(_) => HashMap::new(),
+        };
+
+        let queue = match model_config() {
+            Ok(Some(config)) => start_worker(config),
+            Ok(None) => {
+                log::info!("No commit classifier configured, only Conventional Commits are categorised");
+                None
+            }
+            Err(err) => {
+                log::error!("invalid commit classifier configuration: {}", err);
+                None
+            }
+        };
+
+        Classifier {
+            categories: Arc::new(categories),
+            labels: Arc::new(RwLock::new(labels)),
+            labels_path,
+            queue,
+        }
+    }
+
+    pub fn categories(&self) -> &[String] {
+        &self.categories
+    }
+
+    /// whether commits without a Conventional Commits prefix are categorised by a model
+    pub fn has_model(&self) -> bool {
+        self.queue.is_some()
+    }
+
+    /// saves the labels again, in case a write after classifying failed
+    pub fn flush(&self) {
+        self.save();
+    }
+
+    /// the category of every commit that could be labelled, by commit SHA
+    pub async fn classify(&self, commits: &[&Commit]) -> HashMap<String, String> {
+        let mut result = HashMap::new();
+        let mut missing = Vec::new();
+
+        {
+            let labels = self.labels.read().unwrap();
+            for commit in commits {
+                if let Some(category) = conventional_category(&commit.title, &self.categories) {
+                    result.insert(commit.id.clone(), category);
+                } else if let Some(category) = labels.get(&commit.id) {
+                    result.insert(commit.id.clone(), category.clone());
+                } else {
+                    missing.push(*commit);
+                }
+            }
+        }
+
+        let queue = match &self.queue {
+            Some(queue) if !missing.is_empty() => queue,
+            _ => return result,
+        };
+
+        let (reply, predicted) = oneshot::channel();
+        let request = ClassifyRequest {
+            titles: missing.iter().map(|commit| commit.title.clone()).collect(),
+            reply,
+        };
+        if let Err(err) = queue.try_send(request) {
+            log::warn!("commits not classified: {}", err);
+            return result;
+        }
+
+        let predicted = match tokio::time::timeout(Duration::from_secs(TIMEOUT_SECS), predicted).await {
+            Ok(Ok(Some(predicted))) => predicted,
+            Ok(_) => return result,
+            Err(_) => {
+                log::warn!("classifying {} commits timed out", missing.len());
+                return result;
+            }
+        };
+
+        {
+            let mut labels = self.labels.write().unwrap();
+            for (commit, category) in missing.iter().zip(predicted) {
+                labels.insert(commit.id.clone(), category.clone());
+                result.insert(commit.id.clone(), category);
+            }
+        }
+        self.save();
+
+        result
+    }
+
+    fn save(&self) {
+        let stored = StoredLabels {
+            categories: self.categories.to_vec(),
+            labels: self.labels.read().unwrap().clone(),
+        };
+
+        let result = serde_json::to_string(&stored)
+            .map_err(|err| err.to_string())
+            .and_then(|content| fs::write(&self.labels_path, content).map_err(|err| err.to_string()));
+        if let Err(err) = result {
+            log::error!("failed to save {}: {}", self.labels_path.display(), err);
+        }
+    }
+}
+
+/// the categories are the labels the model chooses from, reports translate the built-in
+/// ones and show added ones as configured
+fn categories_from_env() -> Vec<String> {
+    env::var("COMMIT_CATEGORIES")
+        .unwrap_or_else(|_| DEFAULT_CATEGORIES.to_string())
+        .split(',')
+        .map(|category| category.trim().to_lowercase())
+        .filter(|category| !category.is_empty())
+        .collect()
+}
+
+/// maps `feat:`, `fix(api):`, `docs!:` and friends to a configured category
+fn conventional_category(title: &str, categories: &[String]) -> Option<String> {
+    let (prefix, _) = title.split_once(':')?;
+    let kind = prefix
+        .split('(')
+        .next()?
+        .trim_end_matches('!')
+        .trim()
+        .to_lowercase();
+
+    let category = match kind.as_str() {
+        "feat" | "feature" => "feature",
+        "fix" | "bugfix" | "hotfix" => "bugfix",
+        "refactor" => "refactor",
+        "docs" | "doc" => "docs",
+        "ci" | "build" | "chore" | "infra" => "infra",
+        "test" | "tests" => "test",
+        _ => return None,
+    };
+
+    categories
+        .iter()
+        .find(|configured| configured.as_str() == category)
+        .cloned()
+}
+
+struct ModelConfig {
+    model_type: ModelType,
+    dir: PathBuf,
+    categories: Vec<String>,
+}
+
+fn model_config() -> Result<Option<ModelConfig>, String> {
+    let dir = match env::var("CLASSIFIER_MODEL_DIR") {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => return Ok(None),
+    };
+
+    let model_type = match env::var("CLASSIFIER_MODEL_TYPE")
+        .unwrap_or_else(|_| "bart".to_string())
+        .to_lowercase()
+        .as_str()
+    {
+        "bart" => ModelType::Bart,
+        "roberta" => ModelType::Roberta,
+        other => return Err(format!("unsupported CLASSIFIER_MODEL_TYPE `{}`", other)),
+    };
+
+    for file in ["rust_model.ot", "config.json", "vocab.json", "merges.txt"] {
+        if !dir.join(file).is_file() {
+            return Err(format!("classifier model file {} does not exist", dir.join(file).display()));
+        }
+    }
+
+    Ok(Some(ModelConfig {
+        model_type,
+        dir,
+        categories: categories_from_env(),
+    }))
+}
+
+/// loads the model on its own thread and labels queued commit titles
+fn start_worker(config: ModelConfig) -> Option<mpsc::Sender<ClassifyRequest>> {
+    let (tx, mut rx) = mpsc::channel::<ClassifyRequest>(QUEUE_SIZE);
+
+    let spawned = thread::Builder::new()
+        .name("classifier-worker".to_string())
+        .spawn(move || {
+            log::info!("Loading commit classifier from {}", config.dir.display());
+            let pipeline_config = ZeroShotClassificationConfig::new(
+                config.model_type,
+                ModelResource::Torch(Box::new(LocalResource {
+                    local_path: config.dir.join("rust_model.ot"),
+                })),
+                LocalResource {
+                    local_path: config.dir.join("config.json"),
+                },
+                LocalResource {
+                    local_path: config.dir.join("vocab.json"),
+                },
+                Some(LocalResource {
+                    local_path: config.dir.join("merges.txt"),
+                }),
+                false,
+                None,
+                None,
+            );
+            let model = match ZeroShotClassificationModel::new(pipeline_config) {
+                Ok(model) => model,
+                Err(err) => {
+                    log::error!("failed to load the commit classifier: {}", err);
+                    return;
+                }
+            };
+            log::info!("Commit classifier ready");
+
+            let categories: Vec<&str> = config.categories.iter().map(String::as_str).collect();
+            while let Some(request) = rx.blocking_recv() {
+                let mut predicted = Vec::with_capacity(request.titles.len());
+                for batch in request.titles.chunks(BATCH_SIZE) {
+                    let titles: Vec<&str> = batch.iter().map(String::as_str).collect();
+                    let template = Box::new(|label: &str| format!("This commit is about {}.", label));
+                    match model.predict(&titles, &categories, Some(template), MAX_LENGTH) {
+                        Ok(labels) => predicted.extend(labels.into_iter().map(|label| label.text)),
+                        Err(err) => {
+                            log::error!("failed to classify commits: {}", err);
+                            break;
+                        }
+                    }
+                }
+
+                let complete = predicted.len() == request.titles.len();
+                let _ = request.reply.send(if complete { Some(predicted) } else { None });
+            }
+        });
+
+    match spawned {
+        Ok(_) => Some(tx),
+        Err(err) => {
+            log::error!("failed to start the commit classifier: {}", err);
+            None
+        }
+    }
+}
@@ -1,12 +1,23 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fs;
+use std::path::PathBuf;
 
 use teloxide::types::ChatId;
 use teloxide::types::Me;
 use teloxide::types::UserId;
+use crate::draft::{Delivery, DeliveryStatus, Draft, DraftId};
+use crate::feedback::{AnswerId, AnsweredQuestion};
 use crate::gitlab::GitlabUser;
+use crate::i18n::Lang;
+use crate::standup::{StandupAnswer, StandupMember};
 
 type Address = String;
 
+// answers kept for rating, older ones lose their buttons' effect
+const MAX_RATABLE_ANSWERS: usize = 1000;
+// scheduled reports remembered for the dashboard
+const MAX_DELIVERIES: usize = 2000;
+
 
 #[derive(Clone, Debug)]
 struct MeBot {
@@ -43,6 +54,28 @@ pub struct Context {
     chatid_to_addrs: HashMap<ChatId, HashSet<Address>>,
     // map associating several user IDs to each Gitlab user
     user_to_gitlab: HashMap<UserId, GitlabUser>,
+    // channel each user publishes reports to
+    user_to_channel: HashMap<UserId, ChatId>,
+    // reports waiting for their author's approval
+    drafts: HashMap<DraftId, Draft>,
+    next_draft_id: DraftId,
+    // recent scheduled reports, oldest first
+    deliveries: VecDeque<Delivery>,
+    // file the deliveries are saved to, None keeps them in memory only
+    deliveries_path: Option<PathBuf>,
+    // answers waiting for a thumbs up or down
+    answers: BTreeMap<AnswerId, AnsweredQuestion>,
+    next_answer_id: AnswerId,
+    // members of the stand-up of each team group
+    standup_teams: HashMap<ChatId, Vec<StandupMember>>,
+    // answers of the current stand-up round
+    standup_answers: HashMap<UserId, StandupAnswer>,
+    // language chosen with /lang, per private or group chat
+    chat_to_lang: HashMap<ChatId, Lang>,
+    // language telegram reported for each user
+    user_to_lang: HashMap<UserId, Lang>,
+    // chats that turned the report summary off with /summary off
+    summary_disabled: HashSet<ChatId>,
     // current bot
     bot: MeBot,
 }
@@ -89,6 +122,15 @@ impl Context {
         self.addr_to_chatids.get(addr)
     }
 
+    /// returns every address with the chat IDs subscribed to it
+    pub fn subscriptions(&self) -> Vec<(Address, Vec<ChatId>)> {
+        self.addr_to_chatids
+            .iter()
+            .filter(|(_, chat_ids)| !chat_ids.is_empty())
+            .map(|(addr, chat_ids)| (addr.clone(), chat_ids.iter().copied().collect()))
+            .collect()
+    }
+
     pub fn set_bot(&mut self, bot: Me) {
         self.bot.me = bot;
     }
@@ -97,9 +139,7 @@ impl Context {
         &self.bot.me
     }
 
-    pub fn register_gitlab_user(&mut self, user_id: UserId, token: String) {
-        let gitlab_user = GitlabUser::new(token);
-
+    pub fn register_gitlab_user(&mut self, user_id: UserId, gitlab_user: GitlabUser) {
         self.user_to_gitlab.insert(user_id, gitlab_user);
     }
 
@@ -107,4 +147,264 @@ impl Context {
         self.user_to_gitlab.get(&user_id)
     }
 
-}
\ No newline at end of file
+    pub fn gitlab_users(&self) -> Vec<GitlabUser> {
+        self.user_to_gitlab.values().cloned().collect()
+    }
+
+    /// returns every user with a Gitlab token and the channel they publish to, if any
+    pub fn users(&self) -> Vec<(UserId, GitlabUser, Option<ChatId>)> {
+        self.user_to_gitlab
+            .iter()
+            .map(|(user_id, gitlab_user)| (*user_id, gitlab_user.clone(), self.get_channel(*user_id)))
+            .collect()
+    }
+
+    pub fn get_channel(&self, user_id: UserId) -> Option<ChatId> {
+        self.user_to_channel.get(&user_id).copied()
+    }
+
+    /// links the channel reports of the user are delivered to, replacing the previous one
+    pub fn set_channel(&mut self, user_id: UserId, channel: ChatId) {
+        self.user_to_channel.insert(user_id, channel);
+    }
+
+    /// returns every user with both a Gitlab token and a linked channel
+    pub fn report_targets(&self) -> Vec<(UserId, GitlabUser, ChatId)> {
+        self.user_to_channel
+            .iter()
+            .filter_map(|(user_id, channel)| {
+                self.user_to_gitlab
+                    .get(user_id)
+                    .map(|gitlab_user| (*user_id, gitlab_user.clone(), *channel))
+            })
+            .collect()
+    }
+
+    /// stores a pending draft and returns its ID
+    pub fn add_draft(&mut self, draft: Draft) -> DraftId {
+        self.next_draft_id += 1;
+        self.drafts.insert(self.next_draft_id, draft);
+        self.next_draft_id
+    }
+
+    pub fn get_draft_mut(&mut self, id: DraftId) -> Option<&mut Draft> {
+        self.drafts.get_mut(&id)
+    }
+
+    /// removes the draft, returns None if it was already published or skipped
+    pub fn take_draft(&mut self, id: DraftId) -> Option<Draft> {
+        self.drafts.remove(&id)
+    }
+
+    /// removes every pending draft
+    pub fn take_drafts(&mut self) -> Vec<(DraftId, Draft)> {
+        self.drafts.drain().collect()
+    }
+
+    /// loads the deliveries saved at `path` and saves them there on every change
+    pub fn persist_deliveries(&mut self, path: PathBuf) {
+        if let Ok(content) = fs::read_to_string(&path) {
+            match serde_json::from_str(&content) {
+                Ok(deliveries) => self.deliveries = deliveries,
+                Err(err) => log::error!("ignoring unreadable {}: {}", path.display(), err),
+            }
+        }
+        // drafts only live in memory, the review of those pending before the restart never comes
+        for delivery in &mut self.deliveries {
+            if delivery.status == DeliveryStatus::AwaitingReview {
+                delivery.status = DeliveryStatus::Skipped;
+            }
+        }
+        // new drafts must not take the IDs of the saved deliveries
+        let last_draft = self.deliveries.iter().filter_map(|delivery| delivery.draft).max();
+        self.next_draft_id = self.next_draft_id.max(last_draft.unwrap_or(0));
+
+        self.deliveries_path = Some(path);
+        self.save_deliveries();
+    }
+
+    fn save_deliveries(&self) {
+        let path = match &self.deliveries_path {
+            Some(path) => path,
+            None => return,
+        };
+        let result = serde_json::to_string(&self.deliveries)
+            .map_err(|err| err.to_string())
+            .and_then(|content| fs::write(path, content).map_err(|err| err.to_string()));
+        if let Err(err) = result {
+            log::error!("failed to save {}: {}", path.display(), err);
+        }
+    }
+
+    pub fn add_delivery(&mut self, delivery: Delivery) {
+        self.deliveries.push_back(delivery);
+        while self.deliveries.len() > MAX_DELIVERIES {
+            self.deliveries.pop_front();
+        }
+        self.save_deliveries();
+    }
+
+    /// updates the delivery of the draft, if it is still remembered
+    pub fn set_delivery_status(&mut self, draft: DraftId, status: DeliveryStatus) {
+        if let Some(delivery) = self.deliveries.iter_mut().rev().find(|delivery| delivery.draft == Some(draft)) {
+            delivery.status = status;
+            delivery.updated_at = chrono::Utc::now();
+            self.save_deliveries();
+        }
+    }
+
+    /// returns the remembered deliveries, most recent first
+    pub fn deliveries(&self) -> Vec<Delivery> {
+        self.deliveries.iter().rev().cloned().collect()
+    }
+
+    /// keeps an answer for rating and returns its ID, forgetting the oldest ones
+    pub fn add_answer(&mut self, answer: AnsweredQuestion) -> AnswerId {
+        self.next_answer_id += 1;
+        self.answers.insert(self.next_answer_id, answer);
+        while self.answers.len() > MAX_RATABLE_ANSWERS {
+            self.answers.pop_first();
+        }
+        self.next_answer_id
+    }
+
+    /// removes the answer, returns None if it was already rated or forgotten
+    pub fn take_answer(&mut self, id: AnswerId) -> Option<AnsweredQuestion> {
+        self.answers.remove(&id)
+    }
+
+    /// returns a bool indicating whether the member newly joined the team
+    pub fn join_standup(&mut self, team: ChatId, member: StandupMember) -> bool {
+        let members = self.standup_teams.entry(team).or_default();
+        if members.iter().any(|m| m.user_id == member.user_id) {
+            return false;
+        }
+        members.push(member);
+        true
+    }
+
+    /// returns a bool indicating whether the user was a member of the team
+    pub fn leave_standup(&mut self, team: ChatId, user_id: UserId) -> bool {
+        match self.standup_teams.get_mut(&team) {
+            Some(members) => {
+                let before = members.len();
+                members.retain(|m| m.user_id != user_id);
+                members.len() != before
+            }
+            None => false,
+        }
+    }
+
+    pub fn standup_teams(&self) -> Vec<(ChatId, Vec<StandupMember>)> {
+        self.standup_teams
+            .iter()
+            .filter(|(_, members)| !members.is_empty())
+            .map(|(team, members)| (*team, members.clone()))
+            .collect()
+    }
+
+    /// returns every user taking part in at least one stand-up
+    pub fn standup_members(&self) -> HashSet<UserId> {
+        self.standup_teams
+            .values()
+            .flatten()
+            .map(|member| member.user_id)
+            .collect()
+    }
+
+    pub fn set_standup_answer(&mut self, user_id: UserId, answer: StandupAnswer) {
+        self.standup_answers.insert(user_id, answer);
+    }
+
+    pub fn get_standup_answer(&self, user_id: UserId) -> Option<&StandupAnswer> {
+        self.standup_answers.get(&user_id)
+    }
+
+    pub fn clear_standup_answers(&mut self) {
+        self.standup_answers.clear();
+    }
+
+    pub fn set_lang(&mut self, chat_id: ChatId, lang: Lang) {
+        self.chat_to_lang.insert(chat_id, lang);
+    }
+
+    /// remembers the language of the user from their telegram `language_code`
+    pub fn detect_lang(&mut self, user_id: UserId, language_code: Option<&str>) {
+        if let Some(lang) = language_code.and_then(Lang::from_code) {
+            self.user_to_lang.insert(user_id, lang);
+        }
+    }
+
+    /// returns the language to use in a chat, the /lang choice wins over
+    /// the language telegram reported for the user
+    pub fn lang(&self, chat_id: ChatId, user_id: Option<UserId>) -> Lang {
+        self.chat_to_lang
+            .get(&chat_id)
+            .or_else(|| user_id.and_then(|user_id| self.user_to_lang.get(&user_id)))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// returns the language to use when messaging the user privately
+    pub fn user_lang(&self, user_id: UserId) -> Lang {
+        self.lang(ChatId(user_id.0 as i64), Some(user_id))
+    }
+
+    pub fn set_summary(&mut self, chat_id: ChatId, enabled: bool) {
+        if enabled {
+            self.summary_disabled.remove(&chat_id);
+        } else {
+            self.summary_disabled.insert(chat_id);
+        }
+    }
+
+    /// reports are summarised unless the chat turned it off
+    pub fn summary_enabled(&self, chat_id: ChatId) -> bool {
+        !self.summary_disabled.contains(&chat_id)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::draft::Delivery;
+
+    fn delivery(draft: DraftId, status: DeliveryStatus) -> Delivery {
+        Delivery {
+            draft: Some(draft),
+            author: UserId(1),
+            channel: ChatId(-10),
+            date: chrono::NaiveDate::from_ymd_opt(2023, 7, 14).unwrap(),
+            commits: 3,
+            status,
+            updated_at: chrono::Utc::now(),
+        }
+    }
+
+    #[test]
+    fn deliveries_survive_a_restart() {
+        let path = std::env::temp_dir().join(format!("digireport-deliveries-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut before = Context::new();
+        before.persist_deliveries(path.clone());
+        before.add_delivery(delivery(1, DeliveryStatus::AwaitingReview));
+        before.add_delivery(delivery(2, DeliveryStatus::AwaitingReview));
+        before.set_delivery_status(2, DeliveryStatus::Published);
+
+        let mut after = Context::new();
+        after.persist_deliveries(path.clone());
+        fs::remove_file(&path).unwrap();
+
+        let statuses: Vec<(Option<DraftId>, DeliveryStatus)> = after
+            .deliveries()
+            .iter()
+            .map(|delivery| (delivery.draft, delivery.status))
+            .collect();
+        // the pending draft was lost with the restart
+        assert_eq!(
+            statuses,
+            vec![(Some(2), DeliveryStatus::Published), (Some(1), DeliveryStatus::Skipped)]
+        );
+        assert_eq!(after.add_draft(Draft::new(UserId(1), ChatId(-10), String::new())), 3);
+    }
+}
@@ -0,0 +1,514 @@
+use std::sync::{Arc, RwLock};
+
+use actix_web::http::StatusCode;
+use actix_web::{delete, get, post, web, HttpResponse, ResponseError};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use teloxide::prelude::*;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::auth::{AuthError, Authentication, Caller, Scope};
+use crate::context;
+use crate::draft::{Delivery, DeliveryStatus};
+use crate::report::{self, DateRange, Report, ReportError, ReportWriter};
+use crate::scheduler;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ApiError {
+    #[error("{0}")]
+    Auth(#[from] AuthError),
+    #[error("unknown user {0}")]
+    UnknownUser(u64),
+    #[error("user {0} has no linked channel")]
+    NoChannel(u64),
+    #[error("{0}")]
+    InvalidRange(#[from] ReportError),
+    #[error("the subscription does not exist")]
+    UnknownSubscription,
+    #[error("failed to read from Gitlab: {0}")]
+    Gitlab(String),
+    #[error("failed to send to telegram: {0}")]
+    Telegram(#[from] teloxide::RequestError),
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ErrorBody {
+    error: String,
+}
+
+impl ErrorBody {
+    pub fn new(error: String) -> ErrorBody {
+        ErrorBody { error }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::Auth(err) => err.status_code(),
+            ApiError::UnknownUser(_) | ApiError::UnknownSubscription => StatusCode::NOT_FOUND,
+            ApiError::NoChannel(_) => StatusCode::CONFLICT,
+            ApiError::InvalidRange(_) => StatusCode::BAD_REQUEST,
+            ApiError::Gitlab(_) | ApiError::Telegram(_) => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(ErrorBody::new(self.to_string()))
+    }
+}
+
+type State = web::Data<Arc<RwLock<context::Context>>>;
+
+#[derive(Serialize, ToSchema)]
+pub struct UserView {
+    user_id: u64,
+    gitlab_username: String,
+    // only the last characters of the token are shown
+    gitlab_token: String,
+    channel: Option<i64>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct SubscriptionView {
+    address: String,
+    chat_ids: Vec<i64>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct Subscription {
+    address: String,
+    chat_id: i64,
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct ReportQuery {
+    /// `today`, `yesterday`, `week`, `YYYY-MM-DD` or `YYYY-MM-DD..YYYY-MM-DD`, defaults to today
+    #[serde(default)]
+    range: String,
+    /// whether to summarise the report, defaults to the setting of the user's channel
+    summary: Option<bool>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ReportView {
+    report: Report,
+    // the message the bot would send
+    text: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct DeliveryView {
+    channel: i64,
+    messages: usize,
+}
+
+/// registers the endpoints under `/api/v1`, all of them require credentials
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/api/v1")
+            .wrap(Authentication)
+            .service(users)
+            .service(subscriptions)
+            .service(subscribe)
+            .service(unsubscribe)
+            .service(user_report)
+            .service(deliver)
+            .service(super::events::stream),
+    );
+}
+
+/// lists the users with a linked Gitlab account
+#[utoipa::path(
+    context_path = "/api/v1",
+    responses(
+        (status = 200, description = "users with their Gitlab account, tokens redacted", body = [UserView]),
+        (status = 401, description = "missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "the `admin` scope is required", body = ErrorBody),
+    ),
+    security(("api_key" = []), ("telegram_login" = [])),
+)]
+#[get("/users")]
+async fn users(state: State, caller: Caller) -> Result<HttpResponse, ApiError> {
+    caller.require(Scope::Admin)?;
+    let mut users: Vec<UserView> = state
+        .read()
+        .unwrap()
+        .users()
+        .into_iter()
+        .map(|(user_id, gitlab_user, channel)| UserView {
+            user_id: user_id.0,
+            gitlab_username: gitlab_user.username().to_string(),
+            gitlab_token: gitlab_user.redacted_token(),
+            channel: channel.map(|channel| channel.0),
+        })
+        .collect();
+    users.sort_by_key(|user| user.user_id);
+
+    Ok(HttpResponse::Ok().json(users))
+}
+
+/// lists the chats subscribed to each address
+#[utoipa::path(
+    context_path = "/api/v1",
+    responses(
+        (status = 200, description = "addresses with their subscribed chats", body = [SubscriptionView]),
+        (status = 401, description = "missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "the `admin` scope is required", body = ErrorBody),
+    ),
+    security(("api_key" = []), ("telegram_login" = [])),
+)]
+#[get("/subscriptions")]
+async fn subscriptions(state: State, caller: Caller) -> Result<HttpResponse, ApiError> {
+    caller.require(Scope::Admin)?;
+    let mut subscriptions: Vec<SubscriptionView> = state
+        .read()
+        .unwrap()
+        .subscriptions()
+        .into_iter()
+        .map(|(address, chat_ids)| {
+            let mut chat_ids: Vec<i64> = chat_ids.into_iter().map(|chat_id| chat_id.0).collect();
+            chat_ids.sort_unstable();
+            SubscriptionView { address, chat_ids }
+        })
+        .collect();
+    subscriptions.sort_by(|a, b| a.address.cmp(&b.address));
+
+    Ok(HttpResponse::Ok().json(subscriptions))
+}
+
+/// subscribes a chat to an address, 201 when it is new and 200 when it already existed
+#[utoipa::path(
+    context_path = "/api/v1",
+    request_body = Subscription,
+    responses(
+        (status = 201, description = "the chat was subscribed"),
+        (status = 200, description = "the chat was already subscribed"),
+        (status = 401, description = "missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "the `admin` scope is required", body = ErrorBody),
+    ),
+    security(("api_key" = []), ("telegram_login" = [])),
+)]
+#[post("/subscriptions")]
+async fn subscribe(state: State, caller: Caller, body: web::Json<Subscription>) -> Result<HttpResponse, ApiError> {
+    caller.require(Scope::Admin)?;
+    let Subscription { address, chat_id } = body.into_inner();
+    let created = state.write().unwrap().register_addr(ChatId(chat_id), address);
+
+    if created {
+        Ok(HttpResponse::Created().finish())
+    } else {
+        Ok(HttpResponse::Ok().finish())
+    }
+}
+
+/// unsubscribes a chat from an address
+#[utoipa::path(
+    context_path = "/api/v1",
+    request_body = Subscription,
+    responses(
+        (status = 204, description = "the chat was unsubscribed"),
+        (status = 404, description = "the chat was not subscribed to the address", body = ErrorBody),
+        (status = 401, description = "missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "the `admin` scope is required", body = ErrorBody),
+    ),
+    security(("api_key" = []), ("telegram_login" = [])),
+)]
+#[delete("/subscriptions")]
+async fn unsubscribe(state: State, caller: Caller, body: web::Json<Subscription>) -> Result<HttpResponse, ApiError> {
+    caller.require(Scope::Admin)?;
+    let Subscription { address, chat_id } = body.into_inner();
+    if !state.write().unwrap().unregister_addr(ChatId(chat_id), address) {
+        return Err(ApiError::UnknownSubscription);
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// builds the report of a user, categorised and rendered like the `/report` command
+#[utoipa::path(
+    context_path = "/api/v1",
+    params(("user_id" = u64, Path, description = "telegram user ID"), ReportQuery),
+    responses(
+        (status = 200, description = "the report and the message the bot would send", body = ReportView),
+        (status = 400, description = "invalid range", body = ErrorBody),
+        (status = 401, description = "missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "the `reports:read` scope is required, or `admin` for other users", body = ErrorBody),
+        (status = 404, description = "unknown user", body = ErrorBody),
+        (status = 502, description = "Gitlab could not be read", body = ErrorBody),
+    ),
+    security(("api_key" = []), ("telegram_login" = [])),
+)]
+#[get("/users/{user_id}/report")]
+async fn user_report(
+    state: State,
+    caller: Caller,
+    writer: web::Data<ReportWriter>,
+    user_id: web::Path<u64>,
+    query: web::Query<ReportQuery>,
+) -> Result<HttpResponse, ApiError> {
+    caller.require(Scope::ReportsRead)?;
+    let user_id = UserId(user_id.into_inner());
+    // people signed in with telegram only read their own reports
+    if caller.user_id().is_some_and(|own| own != user_id) {
+        caller.require(Scope::Admin)?;
+    }
+    let range = DateRange::parse(&query.range, Utc::now().date_naive())?;
+    let (gitlab_user, lang, channel_summary) = {
+        let ctxt = state.read().unwrap();
+        let gitlab_user = ctxt
+            .get_gitlab_user(user_id)
+            .cloned()
+            .ok_or(ApiError::UnknownUser(user_id.0))?;
+        let channel_summary = ctxt.get_channel(user_id).is_none_or(|channel| ctxt.summary_enabled(channel));
+        (gitlab_user, ctxt.user_lang(user_id), channel_summary)
+    };
+
+    let mut report = report::generate(&gitlab_user, range)
+        .await
+        .map_err(|err| ApiError::Gitlab(err.to_string()))?;
+    let text = writer
+        .write(&mut report, lang, query.summary.unwrap_or(channel_summary))
+        .await;
+
+    Ok(HttpResponse::Ok().json(ReportView { report, text }))
+}
+
+/// sends today's report, or the one of `range`, to the user's channel right away
+#[utoipa::path(
+    context_path = "/api/v1",
+    params(("user_id" = u64, Path, description = "telegram user ID"), ReportQuery),
+    responses(
+        (status = 200, description = "the report was sent to the user's channel", body = DeliveryView),
+        (status = 400, description = "invalid range", body = ErrorBody),
+        (status = 401, description = "missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "the `admin` scope is required", body = ErrorBody),
+        (status = 404, description = "unknown user", body = ErrorBody),
+        (status = 409, description = "the user has no linked channel", body = ErrorBody),
+        (status = 502, description = "Gitlab or telegram failed", body = ErrorBody),
+    ),
+    security(("api_key" = []), ("telegram_login" = [])),
+)]
+#[post("/users/{user_id}/deliveries")]
+async fn deliver(
+    state: State,
+    caller: Caller,
+    writer: web::Data<ReportWriter>,
+    bot: web::Data<Bot>,
+    user_id: web::Path<u64>,
+    query: web::Query<ReportQuery>,
+) -> Result<HttpResponse, ApiError> {
+    caller.require(Scope::Admin)?;
+    let range = DateRange::parse(&query.range, Utc::now().date_naive())?;
+    let user_id = UserId(user_id.into_inner());
+    let (gitlab_user, channel, lang, summary) = {
+        let ctxt = state.read().unwrap();
+        let gitlab_user = ctxt
+            .get_gitlab_user(user_id)
+            .cloned()
+            .ok_or(ApiError::UnknownUser(user_id.0))?;
+        let channel = ctxt.get_channel(user_id).ok_or(ApiError::NoChannel(user_id.0))?;
+        (gitlab_user, channel, ctxt.user_lang(user_id), ctxt.summary_enabled(channel))
+    };
+
+    // recorded like scheduled reports so the dashboard and the metrics see it
+    let delivery = |commits, status| Delivery {
+        draft: None,
+        author: user_id,
+        channel,
+        date: range.from,
+        commits,
+        status,
+        updated_at: Utc::now(),
+    };
+
+    let mut report = report::generate(&gitlab_user, range).await.map_err(|err| {
+        scheduler::record(&state, delivery(0, DeliveryStatus::Failed));
+        ApiError::Gitlab(err.to_string())
+    })?;
+    let text = writer
+        .write(&mut report, lang, query.summary.unwrap_or(summary))
+        .await;
+
+    let parts = report::split_message(&text);
+    for part in &parts {
+        if let Err(err) = bot.send_message(channel, part.as_str()).await {
+            scheduler::record(&state, delivery(report.commit_count(), DeliveryStatus::Failed));
+            return Err(err.into());
+        }
+    }
+    scheduler::record(&state, delivery(report.commit_count(), DeliveryStatus::Published));
+    log::info!("delivered the report of {} to {} through the API", user_id, channel);
+
+    Ok(HttpResponse::Ok().json(DeliveryView {
+        channel: channel.0,
+        messages: parts.len(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::ApiKeys;
+    use crate::gitlab::GitlabUser;
+    use actix_web::dev::Service;
+    use actix_web::{test, App};
+    use hmac::{Hmac, Mac};
+    use serde_json::{json, Value};
+    use sha2::{Digest, Sha256};
+
+    const BOT_TOKEN: &str = "1:token";
+    // a registered user who is not an admin
+    const MEMBER: u64 = 100;
+    const OTHER: u64 = 200;
+
+    fn keys() -> ApiKeys {
+        let path = std::env::temp_dir().join(format!("digireport-api-keys-{}.json", std::process::id()));
+        std::env::set_var("API_KEYS_PATH", path);
+        ApiKeys::from_env()
+    }
+
+    fn context() -> Arc<RwLock<context::Context>> {
+        let mut ctxt = context::Context::new();
+        for (user_id, username) in [(MEMBER, "budi"), (OTHER, "adi")] {
+            let mut gitlab_user = GitlabUser::new(format!("glpat-secret-{}", user_id));
+            gitlab_user.set_user(username.to_string());
+            ctxt.register_gitlab_user(UserId(user_id), gitlab_user);
+        }
+        Arc::new(RwLock::new(ctxt))
+    }
+
+    /// the `Authorization` header of someone signed in with the Telegram login widget
+    fn telegram_login(user_id: u64) -> String {
+        let auth_date = Utc::now().timestamp();
+        let mut mac = Hmac::<Sha256>::new_from_slice(&Sha256::digest(BOT_TOKEN.as_bytes())).unwrap();
+        mac.update(format!("auth_date={}\nid={}", auth_date, user_id).as_bytes());
+        let hash = hex::encode(mac.finalize().into_bytes());
+
+        format!("Telegram id={}&auth_date={}&hash={}", user_id, auth_date, hash)
+    }
+
+    async fn call(
+        keys: &ApiKeys,
+        ctxt: &Arc<RwLock<context::Context>>,
+        request: test::TestRequest,
+        authorization: Option<&str>,
+    ) -> HttpResponse {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(Arc::clone(ctxt)))
+                .app_data(web::Data::new(keys.clone()))
+                .app_data(web::Data::new(Bot::new(BOT_TOKEN)))
+                .app_data(web::Data::new(ReportWriter::from_env()))
+                .configure(configure),
+        )
+        .await;
+        let request = match authorization {
+            Some(authorization) => request.insert_header(("Authorization", authorization)),
+            None => request,
+        };
+
+        // refused credentials come back as errors of the middleware
+        match app.call(request.to_request()).await {
+            Ok(response) => response.into_parts().1.map_into_boxed_body(),
+            Err(err) => err.error_response(),
+        }
+    }
+
+    async fn body(response: HttpResponse) -> Value {
+        let bytes = actix_web::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    async fn error(response: HttpResponse) -> String {
+        body(response).await["error"].as_str().unwrap().to_string()
+    }
+
+    #[actix_rt::test]
+    async fn refuses_missing_or_unknown_credentials() {
+        let (keys, ctxt) = (keys(), context());
+
+        let response = call(&keys, &ctxt, test::TestRequest::get().uri("/api/v1/users"), None).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(error(response).await.starts_with("missing credentials"));
+
+        let response = call(&keys, &ctxt, test::TestRequest::get().uri("/api/v1/users"), Some("Bearer drk_0_0")).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(error(response).await, "invalid API key");
+    }
+
+    #[actix_rt::test]
+    async fn admin_endpoints_need_the_admin_scope() {
+        let (keys, ctxt) = (keys(), context());
+        let (_, secret) = keys.create("reader", vec![Scope::ReportsRead], UserId(1));
+
+        let bearer = format!("Bearer {}", secret);
+        let response = call(&keys, &ctxt, test::TestRequest::get().uri("/api/v1/users"), Some(&bearer)).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(error(response).await, "the `admin` scope is required");
+    }
+
+    #[actix_rt::test]
+    async fn lists_users_with_redacted_tokens() {
+        let (keys, ctxt) = (keys(), context());
+        let (_, secret) = keys.create("admin", vec![Scope::Admin], UserId(1));
+
+        let bearer = format!("Bearer {}", secret);
+        let response = call(&keys, &ctxt, test::TestRequest::get().uri("/api/v1/users"), Some(&bearer)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let listed = body(response).await;
+        assert_eq!(
+            listed,
+            json!([
+                { "user_id": MEMBER, "gitlab_username": "budi", "gitlab_token": "****-100", "channel": null },
+                { "user_id": OTHER, "gitlab_username": "adi", "gitlab_token": "****-200", "channel": null },
+            ])
+        );
+    }
+
+    #[actix_rt::test]
+    async fn subscribes_and_unsubscribes_chats() {
+        let (keys, ctxt) = (keys(), context());
+        let (_, secret) = keys.create("admin", vec![Scope::Admin], UserId(1));
+        let bearer = format!("Bearer {}", secret);
+        let subscription = json!({ "address": "group/project", "chat_id": -5 });
+
+        let add = || test::TestRequest::post().uri("/api/v1/subscriptions").set_json(&subscription);
+        assert_eq!(call(&keys, &ctxt, add(), Some(&bearer)).await.status(), StatusCode::CREATED);
+        assert_eq!(call(&keys, &ctxt, add(), Some(&bearer)).await.status(), StatusCode::OK);
+
+        let response = call(&keys, &ctxt, test::TestRequest::get().uri("/api/v1/subscriptions"), Some(&bearer)).await;
+        let listed = body(response).await;
+        assert_eq!(listed, json!([{ "address": "group/project", "chat_ids": [-5] }]));
+
+        let remove = || test::TestRequest::delete().uri("/api/v1/subscriptions").set_json(&subscription);
+        assert_eq!(call(&keys, &ctxt, remove(), Some(&bearer)).await.status(), StatusCode::NO_CONTENT);
+        let response = call(&keys, &ctxt, remove(), Some(&bearer)).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(error(response).await, "the subscription does not exist");
+    }
+
+    #[actix_rt::test]
+    async fn members_only_read_their_own_reports() {
+        let (keys, ctxt) = (keys(), context());
+        let login = telegram_login(MEMBER);
+
+        let other = test::TestRequest::get().uri(&format!("/api/v1/users/{}/report", OTHER));
+        let response = call(&keys, &ctxt, other, Some(&login)).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        // an invalid range fails after the access check, before Gitlab is read
+        let own = test::TestRequest::get().uri(&format!("/api/v1/users/{}/report?range=someday", MEMBER));
+        assert_eq!(call(&keys, &ctxt, own, Some(&login)).await.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn unregistered_telegram_users_are_refused() {
+        let (keys, ctxt) = (keys(), context());
+
+        let request = test::TestRequest::get().uri(&format!("/api/v1/users/{}/report", MEMBER));
+        let response = call(&keys, &ctxt, request, Some(&telegram_login(300))).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(error(response).await, "the Telegram user is not registered with the bot");
+    }
+}
@@ -0,0 +1,517 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+
+use actix_web::cookie::{time, Cookie, SameSite};
+use actix_web::http::header::LOCATION;
+use actix_web::http::StatusCode;
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use chrono::{Duration, NaiveDate, Utc};
+use teloxide::prelude::*;
+
+use crate::controller::api::ApiError;
+use crate::controller::events::{self, EventQuery};
+use crate::auth::{self, ApiKeys, AuthError, Caller, Scope};
+use crate::context;
+use crate::draft::{self, Delivery, DeliveryStatus};
+use crate::events::EventBus;
+use crate::qa::Qa;
+use crate::report::ReportWriter;
+use crate::scheduler;
+use crate::shutdown::Shutdown;
+use crate::standup;
+use crate::webhook::Webhook;
+
+// holds the hex encoded Telegram Login Widget payload, which is checked on every request
+const LOGIN_COOKIE: &str = "digireport_login";
+// days shown in the activity charts
+const ACTIVITY_DAYS: i64 = 14;
+// reports listed per team
+const RECENT_REPORTS: usize = 10;
+// days the delivery status counts cover
+const STATUS_DAYS: i64 = 7;
+// events kept in the live feed
+const LIVE_EVENTS: usize = 20;
+
+const STYLE: &str = "body{font-family:sans-serif;margin:2em auto;max-width:60em;color:#222}\
+table{border-collapse:collapse;width:100%;margin-bottom:1.5em}\
+th,td{text-align:left;padding:.3em .6em;border-bottom:1px solid #ddd}\
+.chart{display:flex;align-items:flex-end;gap:2px;height:40px}\
+.bar{width:14px;background:#4a8fd8}\
+.failed{color:#c0392b}.muted{color:#888}";
+
+/// the dashboard, `/dashboard/login` must be the Login Widget redirect and the
+/// domain has to be set for the bot with BotFather's `/setdomain`
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(dashboard).service(event_stream).service(login).service(logout);
+}
+
+#[get("/dashboard")]
+async fn dashboard(
+    request: HttpRequest,
+    state: web::Data<Arc<RwLock<context::Context>>>,
+    bot: web::Data<Bot>,
+    writer: web::Data<ReportWriter>,
+    qa: web::Data<Qa>,
+    keys: web::Data<ApiKeys>,
+) -> HttpResponse {
+    let caller = match cookie_login(&request, &state, &bot) {
+        Some(Ok(caller)) => caller,
+        Some(Err(err)) => return login_page(&state, StatusCode::UNAUTHORIZED, Some(&err.to_string())),
+        None => return login_page(&state, StatusCode::OK, None),
+    };
+
+    let mut body = String::new();
+    {
+        let ctxt = state.read().unwrap();
+        let names = member_names(&ctxt);
+        let deliveries = visible_deliveries(&ctxt, &caller);
+        let today = Utc::now().date_naive();
+
+        body.push_str(&format!(
+            "<p class=\"muted\">Signed in as {} &middot; <a href=\"/dashboard/logout\">sign out</a></p>",
+            escape(&caller.to_string())
+        ));
+        render_status(&mut body, &deliveries, today);
+        render_teams(&mut body, &deliveries, &names);
+        render_activity(&mut body, &deliveries, &names, today);
+        render_live(&mut body);
+        if caller.allows(Scope::Admin) {
+            let webhook = request.app_data::<web::Data<Webhook>>().is_some();
+            render_config(&mut body, &writer, &qa, &keys, webhook);
+        }
+    }
+
+    page(StatusCode::OK, &body)
+}
+
+/// the caller signed in with the login cookie, None without one
+fn cookie_login(
+    request: &HttpRequest,
+    state: &RwLock<context::Context>,
+    bot: &Bot,
+) -> Option<Result<Caller, AuthError>> {
+    let payload = request
+        .cookie(LOGIN_COOKIE)
+        .and_then(|cookie| hex::decode(cookie.value()).ok())
+        .and_then(|payload| String::from_utf8(payload).ok())?;
+
+    Some(auth::login(state, &payload, bot.token()))
+}
+
+/// the live event stream for the dashboard, authenticated with the login cookie since
+/// `EventSource` cannot send the `Authorization` header `/api/v1/events` needs
+#[get("/dashboard/events")]
+async fn event_stream(
+    request: HttpRequest,
+    state: web::Data<Arc<RwLock<context::Context>>>,
+    bot: web::Data<Bot>,
+    bus: web::Data<EventBus>,
+    shutdown: web::Data<Shutdown>,
+    query: web::Query<EventQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let caller = cookie_login(&request, &state, &bot).unwrap_or(Err(AuthError::MissingCredentials))?;
+
+    events::open(&request, &state, &bus, &shutdown, &caller, &query)
+}
+
+/// where the Login Widget sends the signed payload
+#[get("/dashboard/login")]
+async fn login(
+    request: HttpRequest,
+    state: web::Data<Arc<RwLock<context::Context>>>,
+    bot: web::Data<Bot>,
+) -> HttpResponse {
+    let payload = request.query_string();
+    let caller: Caller = match auth::login(&state, payload, bot.token()) {
+        Ok(caller) => caller,
+        Err(err) => {
+            log::warn!(target: "audit", "refused dashboard login from {:?}: {}", request.peer_addr(), err);
+            return login_page(&state, StatusCode::UNAUTHORIZED, Some(&err.to_string()));
+        }
+    };
+    log::info!(target: "audit", "{} signed in to the dashboard", caller);
+
+    let cookie = Cookie::build(LOGIN_COOKIE, hex::encode(payload))
+        .path("/dashboard")
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .secure(request.connection_info().scheme() == "https")
+        .max_age(time::Duration::days(1))
+        .finish();
+
+    HttpResponse::SeeOther()
+        .insert_header((LOCATION, "/dashboard"))
+        .cookie(cookie)
+        .finish()
+}
+
+#[get("/dashboard/logout")]
+async fn logout() -> HttpResponse {
+    let mut cookie = Cookie::build(LOGIN_COOKIE, "").path("/dashboard").finish();
+    cookie.make_removal();
+
+    HttpResponse::SeeOther()
+        .insert_header((LOCATION, "/dashboard"))
+        .cookie(cookie)
+        .finish()
+}
+
+fn page(status: StatusCode, body: &str) -> HttpResponse {
+    HttpResponse::build(status).content_type("text/html; charset=utf-8").body(format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>digireport</title>\
+         <style>{}</style></head><body><h1>digireport</h1>{}</body></html>",
+        STYLE, body
+    ))
+}
+
+fn login_page(state: &RwLock<context::Context>, status: StatusCode, error: Option<&str>) -> HttpResponse {
+    let username = state.read().unwrap().get_bot().username().to_string();
+
+    let mut body = String::new();
+    if let Some(error) = error {
+        body.push_str(&format!("<p class=\"failed\">{}</p>", escape(error)));
+    }
+    body.push_str(&format!(
+        "<p>Sign in with Telegram to see the team reports.</p>\
+         <script async src=\"https://telegram.org/js/telegram-widget.js?22\" data-telegram-login=\"{}\" \
+         data-size=\"large\" data-auth-url=\"/dashboard/login\"></script>",
+        escape(&username)
+    ));
+
+    page(status, &body)
+}
+
+/// names of the stand-up members, the only names the bot knows
+/// admins see every team, other people the channels they report to
+fn visible_deliveries(ctxt: &context::Context, caller: &Caller) -> Vec<Delivery> {
+    let deliveries = ctxt.deliveries();
+    if caller.allows(Scope::Admin) {
+        return deliveries;
+    }
+    let user_id = match caller.user_id() {
+        Some(user_id) => user_id,
+        None => return Vec::new(),
+    };
+
+    let mut channels: HashSet<ChatId> = deliveries
+        .iter()
+        .filter(|delivery| delivery.author == user_id)
+        .map(|delivery| delivery.channel)
+        .collect();
+    channels.extend(ctxt.get_channel(user_id));
+    deliveries
+        .into_iter()
+        .filter(|delivery| channels.contains(&delivery.channel))
+        .collect()
+}
+
+fn member_names(ctxt: &context::Context) -> HashMap<UserId, String> {
+    ctxt.standup_teams()
+        .into_iter()
+        .flat_map(|(_, members)| members)
+        .map(|member| (member.user_id, member.name))
+        .collect()
+}
+
+fn member_name(names: &HashMap<UserId, String>, user_id: UserId) -> String {
+    names
+        .get(&user_id)
+        .cloned()
+        .unwrap_or_else(|| format!("user {}", user_id))
+}
+
+/// how the scheduled reports of the last days ended
+fn render_status(body: &mut String, deliveries: &[Delivery], today: NaiveDate) {
+    let since = today - Duration::days(STATUS_DAYS - 1);
+    let statuses = [
+        DeliveryStatus::Published,
+        DeliveryStatus::AwaitingReview,
+        DeliveryStatus::Skipped,
+        DeliveryStatus::Failed,
+    ];
+
+    body.push_str(&format!("<h2>Delivery status, last {} days</h2><table><tr>", STATUS_DAYS));
+    for status in &statuses {
+        body.push_str(&format!("<th>{}</th>", status.name()));
+    }
+    body.push_str("</tr><tr>");
+    for status in &statuses {
+        let count = deliveries
+            .iter()
+            .filter(|delivery| delivery.date >= since && delivery.status == *status)
+            .count();
+        body.push_str(&format!("<td>{}</td>", count));
+    }
+    body.push_str("</tr></table>");
+}
+
+/// the latest reports of every team channel
+fn render_teams(body: &mut String, deliveries: &[Delivery], names: &HashMap<UserId, String>) {
+    let mut teams: BTreeMap<i64, Vec<&Delivery>> = BTreeMap::new();
+    for delivery in deliveries {
+        let reports = teams.entry(delivery.channel.0).or_default();
+        if reports.len() < RECENT_REPORTS {
+            reports.push(delivery);
+        }
+    }
+
+    body.push_str("<h2>Recent reports</h2>");
+    if teams.is_empty() {
+        body.push_str("<p class=\"muted\">No scheduled report has been sent yet.</p>");
+        return;
+    }
+    for (channel, reports) in teams {
+        body.push_str(&format!(
+            "<h3>Channel {}</h3><table><tr><th>Date</th><th>Member</th><th>Commits</th><th>Status</th><th>Updated</th></tr>",
+            channel
+        ));
+        for delivery in reports {
+            body.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td{}>{}</td><td>{}</td></tr>",
+                delivery.date,
+                escape(&member_name(names, delivery.author)),
+                delivery.commits,
+                if delivery.status == DeliveryStatus::Failed { " class=\"failed\"" } else { "" },
+                delivery.status.name(),
+                delivery.updated_at.format("%Y-%m-%d %H:%M UTC"),
+            ));
+        }
+        body.push_str("</table>");
+    }
+}
+
+/// a bar per day with the commits of each member's daily report
+fn render_activity(body: &mut String, deliveries: &[Delivery], names: &HashMap<UserId, String>, today: NaiveDate) {
+    let since = today - Duration::days(ACTIVITY_DAYS - 1);
+    // deliveries are most recent first, so the first one seen for a day wins
+    let mut activity: BTreeMap<String, BTreeMap<NaiveDate, usize>> = BTreeMap::new();
+    for delivery in deliveries.iter().filter(|delivery| delivery.date >= since) {
+        activity
+            .entry(member_name(names, delivery.author))
+            .or_default()
+            .entry(delivery.date)
+            .or_insert(delivery.commits);
+    }
+
+    body.push_str(&format!("<h2>Activity, last {} days</h2>", ACTIVITY_DAYS));
+    if activity.is_empty() {
+        body.push_str("<p class=\"muted\">No activity recorded yet.</p>");
+        return;
+    }
+    let max = activity
+        .values()
+        .flat_map(|days| days.values())
+        .copied()
+        .max()
+        .unwrap_or(0)
+        .max(1);
+
+    body.push_str("<table><tr><th>Member</th><th>Commits per day</th><th>Total</th></tr>");
+    for (name, days) in &activity {
+        let mut chart = String::new();
+        for offset in 0..ACTIVITY_DAYS {
+            let date = since + Duration::days(offset);
+            let commits = days.get(&date).copied().unwrap_or(0);
+            chart.push_str(&format!(
+                "<div class=\"bar\" style=\"height:{}px\" title=\"{}: {} commits\"></div>",
+                commits * 40 / max,
+                date,
+                commits
+            ));
+        }
+        body.push_str(&format!(
+            "<tr><td>{}</td><td><div class=\"chart\">{}</div></td><td>{}</td></tr>",
+            escape(name),
+            chart,
+            days.values().sum::<usize>()
+        ));
+    }
+    body.push_str("</table>");
+}
+
+/// the latest events, filled in by the browser from `/dashboard/events`
+fn render_live(body: &mut String) {
+    body.push_str(&format!(
+        "<h2>Live</h2><ul id=\"live\" class=\"muted\"></ul><script>\
+         const live=document.getElementById('live'),source=new EventSource('/dashboard/events');\
+         for(const kind of ['push','merge_request','pipeline'])source.addEventListener(kind,message=>{{\
+         const event=JSON.parse(message.data),item=document.createElement('li');\
+         item.textContent=event.project+': '+event.title+(event.status?' ('+event.status+')':'');\
+         live.prepend(item);while(live.children.length>{})live.lastChild.remove();}});</script>",
+        LIVE_EVENTS
+    ));
+}
+
+/// the settings the bot runs with, secrets left out
+fn render_config(body: &mut String, writer: &ReportWriter, qa: &Qa, keys: &ApiKeys, webhook: bool) {
+    let enabled = |enabled: bool| if enabled { "enabled" } else { "disabled" };
+    let settings = [
+        ("Daily reports", format!("{} UTC", scheduler::report_time().format("%H:%M"))),
+        ("Stand-up", format!("{} UTC", standup::standup_time().format("%H:%M"))),
+        ("Draft review window", format!("{} minutes", draft::draft_timeout().as_secs() / 60)),
+        ("Updates", if webhook { "webhook" } else { "polling" }.to_string()),
+        ("Summaries", enabled(writer.summarizer.is_enabled()).to_string()),
+        (
+            "Commit categories",
+            format!(
+                "{} ({})",
+                writer.classifier.categories().join(", "),
+                if writer.classifier.has_model() { "model" } else { "Conventional Commits only" }
+            ),
+        ),
+        ("LLM", enabled(writer.llm.is_enabled()).to_string()),
+        ("QA model", if qa.is_ready() { "ready" } else { "not ready" }.to_string()),
+        ("API keys", keys.list().len().to_string()),
+    ];
+
+    body.push_str("<h2>Configuration</h2><table>");
+    for (name, value) in settings {
+        body.push_str(&format!("<tr><th>{}</th><td>{}</td></tr>", name, escape(&value)));
+    }
+    body.push_str("</table>");
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Identity;
+
+    fn today() -> NaiveDate {
+        NaiveDate::from_ymd_opt(2023, 7, 14).unwrap()
+    }
+
+    fn delivery(author: u64, channel: i64, days_ago: i64, commits: usize, status: DeliveryStatus) -> Delivery {
+        Delivery {
+            draft: None,
+            author: UserId(author),
+            channel: ChatId(channel),
+            date: today() - Duration::days(days_ago),
+            commits,
+            status,
+            updated_at: Utc::now(),
+        }
+    }
+
+    fn member(user_id: u64) -> Caller {
+        Caller {
+            identity: Identity::Telegram(UserId(user_id)),
+            scopes: vec![Scope::ReportsRead],
+        }
+    }
+
+    fn context(deliveries: Vec<Delivery>) -> context::Context {
+        let mut ctxt = context::Context::new();
+        // oldest first, as the scheduler records them
+        for delivery in deliveries.into_iter().rev() {
+            ctxt.add_delivery(delivery);
+        }
+        ctxt
+    }
+
+    #[test]
+    fn escapes_html() {
+        assert_eq!(
+            escape(r#"<a href="x">Tom & 'Jerry'</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; &#39;Jerry&#39;&lt;/a&gt;"
+        );
+    }
+
+    #[test]
+    fn members_only_see_the_channels_they_report_to() {
+        let mut ctxt = context(vec![
+            delivery(1, -10, 0, 3, DeliveryStatus::Published),
+            delivery(2, -10, 0, 4, DeliveryStatus::Published),
+            delivery(3, -20, 0, 5, DeliveryStatus::Published),
+            delivery(4, -30, 0, 6, DeliveryStatus::Published),
+        ]);
+        // a linked channel counts before the first report reaches it
+        ctxt.set_channel(UserId(5), ChatId(-30));
+
+        let channels = |caller: &Caller| {
+            let mut channels: Vec<i64> = visible_deliveries(&ctxt, caller)
+                .iter()
+                .map(|delivery| delivery.channel.0)
+                .collect();
+            channels.sort_unstable();
+            channels
+        };
+        assert_eq!(channels(&member(2)), vec![-10, -10]);
+        assert_eq!(channels(&member(5)), vec![-30]);
+        assert!(channels(&member(6)).is_empty());
+
+        let admin = Caller {
+            identity: Identity::Key {
+                id: "k".to_string(),
+                name: "tv".to_string(),
+            },
+            scopes: vec![Scope::Admin],
+        };
+        assert_eq!(channels(&admin).len(), 4);
+    }
+
+    #[test]
+    fn counts_the_statuses_of_the_last_days() {
+        let deliveries = [
+            delivery(1, -10, 0, 1, DeliveryStatus::Published),
+            delivery(1, -10, STATUS_DAYS - 1, 1, DeliveryStatus::Published),
+            delivery(1, -10, STATUS_DAYS, 1, DeliveryStatus::Published),
+            delivery(2, -10, 1, 0, DeliveryStatus::Failed),
+        ];
+
+        let mut body = String::new();
+        render_status(&mut body, &deliveries, today());
+        assert!(body.ends_with("<tr><td>2</td><td>0</td><td>0</td><td>1</td></tr></table>"), "{}", body);
+    }
+
+    #[test]
+    fn lists_recent_reports_per_channel_with_escaped_names() {
+        let deliveries: Vec<Delivery> = (0..RECENT_REPORTS as i64 + 2)
+            .map(|days_ago| delivery(1, -10, days_ago, 2, DeliveryStatus::Published))
+            .chain([delivery(2, -20, 0, 1, DeliveryStatus::Failed)])
+            .collect();
+        let names = HashMap::from([(UserId(1), "<b>Budi</b>".to_string())]);
+
+        let mut body = String::new();
+        render_teams(&mut body, &deliveries, &names);
+        assert_eq!(body.matches("<h3>").count(), 2);
+        assert_eq!(body.matches("&lt;b&gt;Budi&lt;/b&gt;").count(), RECENT_REPORTS);
+        assert!(!body.contains("<b>Budi"));
+        assert!(body.contains("<td>user 2</td><td>1</td><td class=\"failed\">failed</td>"));
+    }
+
+    #[test]
+    fn says_when_nothing_was_sent() {
+        let mut body = String::new();
+        render_teams(&mut body, &[], &HashMap::new());
+        render_activity(&mut body, &[], &HashMap::new(), today());
+
+        assert!(body.contains("No scheduled report has been sent yet."));
+        assert!(body.contains("No activity recorded yet."));
+    }
+
+    #[test]
+    fn scales_activity_bars_to_the_busiest_day() {
+        let deliveries = [
+            delivery(1, -10, 0, 4, DeliveryStatus::Published),
+            // a report sent again for the same day does not count twice
+            delivery(1, -10, 0, 9, DeliveryStatus::Failed),
+            delivery(1, -10, 1, 2, DeliveryStatus::Published),
+            delivery(1, -10, ACTIVITY_DAYS, 8, DeliveryStatus::Published),
+        ];
+
+        let mut body = String::new();
+        render_activity(&mut body, &deliveries, &HashMap::new(), today());
+        assert_eq!(body.matches("class=\"bar\"").count(), ACTIVITY_DAYS as usize);
+        assert!(body.contains(&format!("style=\"height:40px\" title=\"{}: 4 commits\"", today())));
+        assert!(body.contains("style=\"height:20px\""));
+        assert!(body.ends_with("<td>6</td></tr></table>"), "{}", body);
+    }
+}
@@ -0,0 +1,332 @@
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use actix_web::http::header::CACHE_CONTROL;
+use actix_web::web::Bytes;
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use teloxide::types::ChatId;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::StreamExt;
+use utoipa::IntoParams;
+
+use crate::auth::{Caller, Scope};
+use crate::context;
+use crate::controller::api::ApiError;
+use crate::events::{self, Event, EventBus};
+use crate::shutdown::Shutdown;
+
+// a comment is sent this often so proxies keep idle streams open
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+// milliseconds clients wait before reconnecting
+const RETRY_MS: u64 = 5000;
+
+#[derive(Deserialize, IntoParams)]
+pub struct EventQuery {
+    /// comma separated projects, as `group/project` or only the name
+    project: Option<String>,
+    /// telegram chat ID of a team, streams the addresses the team is subscribed to
+    team: Option<i64>,
+    /// replaces the `Last-Event-ID` header for clients that cannot set it
+    last_event_id: Option<u64>,
+}
+
+/// streams pushes, merge request updates and pipeline results as server-sent events,
+/// reconnecting clients get the events they missed after `Last-Event-ID`. Browsers, whose
+/// `EventSource` cannot send the `Authorization` header, use `/dashboard/events` instead
+#[utoipa::path(
+    context_path = "/api/v1",
+    params(EventQuery, ("Last-Event-ID" = Option<u64>, Header, description = "ID of the last event received")),
+    responses(
+        (status = 200, description = "`text/event-stream` of events named after their kind, the data is an Event", body = Event),
+        (status = 401, description = "missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "the `reports:read` scope is required", body = ErrorBody),
+        (status = 404, description = "the team is not subscribed to any address", body = ErrorBody),
+    ),
+    security(("api_key" = []), ("telegram_login" = [])),
+)]
+#[get("/events")]
+pub async fn stream(
+    request: HttpRequest,
+    state: web::Data<Arc<RwLock<context::Context>>>,
+    bus: web::Data<EventBus>,
+    shutdown: web::Data<Shutdown>,
+    caller: Caller,
+    query: web::Query<EventQuery>,
+) -> Result<HttpResponse, ApiError> {
+    open(&request, &state, &bus, &shutdown, &caller, &query)
+}
+
+/// starts the event stream of an authenticated caller
+pub fn open(
+    request: &HttpRequest,
+    state: &RwLock<context::Context>,
+    bus: &EventBus,
+    shutdown: &Shutdown,
+    caller: &Caller,
+    query: &EventQuery,
+) -> Result<HttpResponse, ApiError> {
+    caller.require(Scope::ReportsRead)?;
+
+    let mut projects: HashSet<String> = query
+        .project
+        .iter()
+        .flat_map(|projects| projects.split(','))
+        .map(str::trim)
+        .filter(|project| !project.is_empty())
+        .map(str::to_string)
+        .collect();
+    if let Some(team) = query.team {
+        let ctxt = state.read().unwrap();
+        let addrs = ctxt
+            .addrs(&ChatId(team))
+            .filter(|addrs| !addrs.is_empty())
+            .ok_or(ApiError::UnknownSubscription)?;
+        projects.extend(addrs.iter().cloned());
+    }
+    let projects: Vec<String> = projects.into_iter().collect();
+
+    let last_id = request
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .or(query.last_event_id)
+        .unwrap_or(0);
+    log::info!(target: "audit", "{} opened the event stream after event {}", caller, last_id);
+
+    let (tx, rx) = mpsc::channel::<Bytes>(64);
+    actix_web::rt::spawn(forward(bus.clone(), shutdown.clone(), projects, last_id, tx));
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((CACHE_CONTROL, "no-cache"))
+        // keeps nginx from buffering the stream
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(ReceiverStream::new(rx).map(Ok::<_, Infallible>)))
+}
+
+/// sends the missed events then the new ones until the client goes away or the server
+/// shuts down, clients reconnect to another instance with `Last-Event-ID`
+async fn forward(
+    bus: EventBus,
+    shutdown: Shutdown,
+    projects: Vec<String>,
+    mut last_id: u64,
+    tx: mpsc::Sender<Bytes>,
+) {
+    // subscribing first so nothing published while reading the history is lost
+    let mut receiver = bus.subscribe();
+    // an ID from the future was not given by this bus, e.g. the clock went back
+    // between restarts, the client gets the whole history instead of nothing
+    if last_id > bus.last_id() {
+        last_id = 0;
+    }
+    if tx.send(Bytes::from(format!("retry: {}\n\n", RETRY_MS))).await.is_err() {
+        return;
+    }
+    if !send_all(&tx, bus.since(last_id), &projects, &mut last_id).await {
+        return;
+    }
+
+    let mut keep_alive = tokio::time::interval(KEEP_ALIVE);
+    keep_alive.tick().await;
+    loop {
+        let sent = tokio::select! {
+            received = receiver.recv() => match received {
+                Ok(event) => send_all(&tx, vec![event], &projects, &mut last_id).await,
+                // the client was too slow, the history still has what it missed
+                Err(RecvError::Lagged(_)) => send_all(&tx, bus.since(last_id), &projects, &mut last_id).await,
+                Err(RecvError::Closed) => false,
+            },
+            _ = keep_alive.tick() => tx.send(Bytes::from_static(b": keep-alive\n\n")).await.is_ok(),
+            _ = shutdown.wait() => false,
+        };
+        if !sent {
+            return;
+        }
+    }
+}
+
+/// false once the client is gone
+async fn send_all(tx: &mpsc::Sender<Bytes>, events: Vec<Event>, projects: &[String], last_id: &mut u64) -> bool {
+    for event in events {
+        if event.id <= *last_id {
+            continue;
+        }
+        *last_id = event.id;
+        if !event.matches(projects) {
+            continue;
+        }
+        let data = match serde_json::to_string(&event) {
+            Ok(data) => data,
+            Err(err) => {
+                log::error!("failed to serialize event {}: {}", event.id, err);
+                continue;
+            }
+        };
+        let frame = format!("id: {}\nevent: {}\ndata: {}\n\n", event.id, event.kind.name(), data);
+        if tx.send(Bytes::from(frame)).await.is_err() {
+            return false;
+        }
+    }
+
+    true
+}
+
+/// receives gitlab push, merge request and pipeline webhooks, the webhook secret
+/// must be `GITLAB_WEBHOOK_TOKEN`
+#[post("/hooks/gitlab")]
+pub async fn gitlab_hook(bus: web::Data<EventBus>, request: HttpRequest, body: web::Bytes) -> HttpResponse {
+    let header = |name: &str| {
+        request
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    };
+    match bus.accepts_gitlab(header("X-Gitlab-Token").as_deref()) {
+        None => return HttpResponse::NotFound().finish(),
+        Some(false) => {
+            log::warn!("rejected gitlab webhook request from {:?}", request.peer_addr());
+            return HttpResponse::Unauthorized().finish();
+        }
+        Some(true) => {}
+    }
+
+    let kind = header("X-Gitlab-Event").unwrap_or_default();
+    match events::from_gitlab(&kind, &body) {
+        Ok(Some(event)) => {
+            let event = bus.publish(event);
+            log::debug!("gitlab {} became event {}", kind, event.id);
+        }
+        Ok(None) => log::debug!("ignoring gitlab event `{}`", kind),
+        Err(err) => {
+            log::error!("malformed gitlab event `{}`: {}", kind, err);
+            return HttpResponse::BadRequest().finish();
+        }
+    }
+
+    HttpResponse::Ok().finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::dashboard;
+    use crate::events::{EventKind, NewEvent};
+    use crate::gitlab::GitlabUser;
+    use actix_web::dev::Service;
+    use actix_web::http::header::CONTENT_TYPE;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use hmac::{Hmac, Mac};
+    use sha2::{Digest, Sha256};
+    use teloxide::types::UserId;
+    use teloxide::Bot;
+
+    const BOT_TOKEN: &str = "1:token";
+
+    fn event(project: &str) -> NewEvent {
+        NewEvent {
+            kind: EventKind::Push,
+            project: project.to_string(),
+            author: "Budi".to_string(),
+            title: "main: fix login".to_string(),
+            status: None,
+            url: None,
+        }
+    }
+
+    /// the IDs of the next `count` events sent to the client, after the retry delay
+    async fn next_ids(rx: &mut mpsc::Receiver<Bytes>, count: usize) -> Vec<u64> {
+        let mut ids = Vec::new();
+        while ids.len() < count {
+            let frame = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+                .await
+                .expect("no event was sent")
+                .expect("the stream ended");
+            let frame = String::from_utf8(frame.to_vec()).unwrap();
+            if let Some(id) = frame.strip_prefix("id: ") {
+                ids.push(id.lines().next().unwrap().parse().unwrap());
+            } else {
+                assert_eq!(frame, format!("retry: {}\n\n", RETRY_MS));
+            }
+        }
+        ids
+    }
+
+    #[actix_rt::test]
+    async fn replays_missed_events_of_the_projects_then_streams_new_ones() {
+        let (bus, shutdown) = (EventBus::from_env(), Shutdown::new());
+        let seen = bus.publish(event("team/app")).id;
+        bus.publish(event("team/other"));
+        let missed = bus.publish(event("team/app")).id;
+
+        let (tx, mut rx) = mpsc::channel(16);
+        let projects = vec!["app".to_string()];
+        let forwarding = actix_web::rt::spawn(forward(bus.clone(), shutdown.clone(), projects, seen, tx));
+        assert_eq!(next_ids(&mut rx, 1).await, vec![missed]);
+
+        let new = bus.publish(event("team/app")).id;
+        assert_eq!(next_ids(&mut rx, 1).await, vec![new]);
+
+        shutdown.trigger();
+        forwarding.await.unwrap();
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[actix_rt::test]
+    async fn ids_from_the_future_get_the_whole_history() {
+        let (bus, shutdown) = (EventBus::from_env(), Shutdown::new());
+        let ids = vec![bus.publish(event("team/app")).id, bus.publish(event("team/app")).id];
+
+        let (tx, mut rx) = mpsc::channel(16);
+        actix_web::rt::spawn(forward(bus.clone(), shutdown.clone(), Vec::new(), bus.last_id() + 100, tx));
+        assert_eq!(next_ids(&mut rx, 2).await, ids);
+        shutdown.trigger();
+    }
+
+    /// the hex encoded login cookie of a registered user
+    fn login_cookie(user_id: u64) -> String {
+        let auth_date = chrono::Utc::now().timestamp();
+        let mut mac = Hmac::<Sha256>::new_from_slice(&Sha256::digest(BOT_TOKEN.as_bytes())).unwrap();
+        mac.update(format!("auth_date={}\nid={}", auth_date, user_id).as_bytes());
+        let hash = hex::encode(mac.finalize().into_bytes());
+
+        hex::encode(format!("id={}&auth_date={}&hash={}", user_id, auth_date, hash))
+    }
+
+    #[actix_rt::test]
+    async fn browsers_open_the_stream_with_the_login_cookie() {
+        let mut ctxt = context::Context::new();
+        ctxt.register_gitlab_user(UserId(100), GitlabUser::new("glpat-secret".to_string()));
+        let shutdown = Shutdown::new();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(Arc::new(RwLock::new(ctxt))))
+                .app_data(web::Data::new(Bot::new(BOT_TOKEN)))
+                .app_data(web::Data::new(EventBus::from_env()))
+                .app_data(web::Data::new(shutdown.clone()))
+                .configure(dashboard::configure),
+        )
+        .await;
+
+        let request = test::TestRequest::get().uri("/dashboard/events?project=app").to_request();
+        let response = app.call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let request = test::TestRequest::get()
+            .uri("/dashboard/events?project=app")
+            .cookie(actix_web::cookie::Cookie::new("digireport_login", login_cookie(100)))
+            .to_request();
+        let response = app.call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get(CONTENT_TYPE).unwrap(), "text/event-stream");
+        shutdown.trigger();
+    }
+}
@@ -0,0 +1,97 @@
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use actix_web::{get, web, HttpResponse};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::auth::{ApiKeys, Authentication};
+use crate::context;
+use crate::feedback;
+use crate::metrics;
+use crate::qa::Qa;
+
+#[derive(Serialize, ToSchema)]
+pub struct Readiness {
+    // the bot identified itself with `get_me`
+    telegram: bool,
+    // the shared state is usable and the state files can be written
+    storage: bool,
+    // the QA model finished loading, or none is configured
+    qa: bool,
+}
+
+/// registers the probes, which stay open for the orchestrator, and `/metrics`,
+/// which needs credentials like the API
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(healthz).service(readyz).service(
+        web::resource("/metrics")
+            .wrap(Authentication)
+            .route(web::get().to(export_metrics)),
+    );
+}
+
+/// the process is up and serving requests
+#[utoipa::path(responses((status = 200, description = "the process is alive", body = String)))]
+#[get("/healthz")]
+async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().body("ok")
+}
+
+/// whether the bot can serve, for the readiness probe
+#[utoipa::path(responses(
+    (status = 200, description = "every dependency is ready", body = Readiness),
+    (status = 503, description = "at least one dependency is not ready", body = Readiness),
+))]
+#[get("/readyz")]
+async fn readyz(
+    state: web::Data<Arc<RwLock<context::Context>>>,
+    keys: web::Data<ApiKeys>,
+    qa: web::Data<Qa>,
+) -> HttpResponse {
+    let (telegram, storage) = match state.read() {
+        Ok(ctxt) => {
+            let files = [keys.path().to_path_buf(), feedback::log_path()];
+            (ctxt.get_bot().user.id.0 != 0, files.iter().all(|path| writable_dir(path)))
+        }
+        Err(_) => (false, false),
+    };
+    let readiness = Readiness {
+        telegram,
+        storage,
+        qa: qa.is_ready(),
+    };
+
+    if readiness.telegram && readiness.storage && readiness.qa {
+        HttpResponse::Ok().json(readiness)
+    } else {
+        HttpResponse::ServiceUnavailable().json(readiness)
+    }
+}
+
+/// every metric in the Prometheus text format
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    responses(
+        (status = 200, description = "Prometheus text exposition format", body = String, content_type = "text/plain"),
+        (status = 401, description = "missing or invalid credentials"),
+    ),
+    security(("api_key" = [])),
+)]
+async fn export_metrics() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics::render())
+}
+
+/// whether the directory the file lives in exists and is not read-only
+fn writable_dir(file: &Path) -> bool {
+    let dir = match file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    fs::metadata(dir).is_ok_and(|meta| meta.is_dir() && !meta.permissions().readonly())
+}
@@ -1,17 +1,53 @@
-use std::sync::{Arc, RwLock};
+pub mod api;
+pub mod dashboard;
+pub mod events;
+pub mod health;
+pub mod notify;
+pub mod openapi;
 
-use actix_web::{get, HttpResponse, Responder, web};
+use actix_web::http::header::LOCATION;
+use actix_web::{get, post, HttpRequest, HttpResponse, Responder, web};
+use teloxide::types::Update;
 
-use crate::context;
+use crate::webhook::{Webhook, SECRET_TOKEN_HEADER};
 
+/// the root page only points to the dashboard
 #[get("/")]
-pub async fn index(
-    state: web::Data<Arc<RwLock<context::Context>>>,
-) -> impl Responder {
-    let ctxt = state.read().unwrap();
-    let bot = ctxt.get_bot();
+pub async fn index() -> impl Responder {
+    HttpResponse::SeeOther()
+        .insert_header((LOCATION, "/dashboard"))
+        .finish()
+}
 
-    let body = format!("Hello world! {:?}", bot.username()).to_string();
+/// receives telegram updates in webhook mode
+#[post("/webhook/{path}")]
+pub async fn telegram_webhook(
+    webhook: web::Data<Webhook>,
+    path: web::Path<String>,
+    request: HttpRequest,
+    body: web::Bytes,
+) -> impl Responder {
+    let secret_token = request
+        .headers()
+        .get(SECRET_TOKEN_HEADER)
+        .and_then(|value| value.to_str().ok());
+    if !webhook.accepts(&path, secret_token) {
+        log::warn!("rejected webhook request from {:?}", request.peer_addr());
+        return HttpResponse::Unauthorized().finish();
+    }
 
-    HttpResponse::Ok().body(body)
+    // telegram retries updates it could not deliver, so only fail when nobody is listening
+    match serde_json::from_slice::<Update>(&body) {
+        Ok(update) => {
+            if webhook.push(update) {
+                HttpResponse::Ok().finish()
+            } else {
+                HttpResponse::ServiceUnavailable().finish()
+            }
+        }
+        Err(err) => {
+            log::error!("ignoring malformed update: {}", err);
+            HttpResponse::Ok().finish()
+        }
+    }
 }
@@ -0,0 +1,149 @@
+use std::sync::{Arc, RwLock};
+
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::http::StatusCode;
+use actix_web::{post, web, HttpRequest, HttpResponse, ResponseError};
+use chrono::Utc;
+use serde::Serialize;
+use teloxide::prelude::*;
+use utoipa::ToSchema;
+
+use crate::context;
+use crate::controller::api::ErrorBody;
+use crate::notify::{Notification, Notifier, SignatureError};
+
+#[derive(Debug, thiserror::Error)]
+pub enum NotifyError {
+    #[error("notifications are disabled")]
+    Disabled,
+    #[error("{0}")]
+    Signature(#[from] SignatureError),
+    #[error("no chat is subscribed to this channel")]
+    UnknownChannel,
+    #[error("invalid notification: {0}")]
+    InvalidPayload(String),
+}
+
+impl ResponseError for NotifyError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            NotifyError::Disabled | NotifyError::UnknownChannel => StatusCode::NOT_FOUND,
+            NotifyError::Signature(_) => StatusCode::UNAUTHORIZED,
+            NotifyError::InvalidPayload(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(ErrorBody::new(self.to_string()))
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct FailedDelivery {
+    chat_id: i64,
+    error: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct NotifyView {
+    delivered: Vec<i64>,
+    failed: Vec<FailedDelivery>,
+}
+
+/// sends a message to every chat subscribed to the channel token, for deploy scripts and
+/// other tools without an API key: the body is signed with `NOTIFY_SECRET` instead
+#[utoipa::path(
+    context_path = "/api/v1",
+    params(
+        ("channel_token" = String, Path, description = "address the chats are subscribed to"),
+        ("X-Notify-Timestamp" = i64, Header, description = "Unix time the request was signed at"),
+        ("X-Notify-Signature" = String, Header, description = "`sha256=` and the hex HMAC-SHA256 of `<timestamp>.<body>`"),
+    ),
+    request_body(content = Notification, description = "JSON, or the message itself as `text/plain`"),
+    responses(
+        (status = 200, description = "the message reached at least one chat", body = NotifyView),
+        (status = 400, description = "invalid notification", body = ErrorBody),
+        (status = 401, description = "missing, invalid or expired signature", body = ErrorBody),
+        (status = 404, description = "notifications are disabled or no chat is subscribed", body = ErrorBody),
+        (status = 502, description = "no chat could be reached", body = NotifyView),
+    ),
+)]
+#[post("/api/v1/notify/{channel_token}")]
+pub async fn notify(
+    request: HttpRequest,
+    state: web::Data<Arc<RwLock<context::Context>>>,
+    bot: web::Data<Bot>,
+    notifier: web::Data<Notifier>,
+    channel_token: web::Path<String>,
+    body: web::Bytes,
+) -> Result<HttpResponse, NotifyError> {
+    if !notifier.is_enabled() {
+        return Err(NotifyError::Disabled);
+    }
+    let header = |name: &str| request.headers().get(name).and_then(|value| value.to_str().ok());
+    if let Err(err) = notifier.verify(
+        header("X-Notify-Timestamp"),
+        header("X-Notify-Signature"),
+        &body,
+        Utc::now(),
+    ) {
+        log::warn!(target: "audit", "refused notification from {:?}: {}", request.peer_addr(), err);
+        return Err(err.into());
+    }
+
+    let is_json = header(CONTENT_TYPE.as_str())
+        .is_some_and(|content_type| content_type.starts_with("application/json"));
+    let notification = if is_json {
+        serde_json::from_slice::<Notification>(&body)
+            .map_err(|err| NotifyError::InvalidPayload(err.to_string()))?
+    } else {
+        let text = String::from_utf8(body.to_vec())
+            .map_err(|_| NotifyError::InvalidPayload("the text is not UTF-8".to_string()))?;
+        Notification::plain(text)
+    };
+    if notification.text.trim().is_empty() {
+        return Err(NotifyError::InvalidPayload("the text is empty".to_string()));
+    }
+    let keyboard = notification.keyboard().map_err(NotifyError::InvalidPayload)?;
+
+    let mut chat_ids: Vec<ChatId> = state
+        .read()
+        .unwrap()
+        .chat_ids(&channel_token)
+        .map(|chat_ids| chat_ids.iter().copied().collect())
+        .unwrap_or_default();
+    if chat_ids.is_empty() {
+        return Err(NotifyError::UnknownChannel);
+    }
+    chat_ids.sort_unstable_by_key(|chat_id| chat_id.0);
+
+    let mut view = NotifyView {
+        delivered: Vec::new(),
+        failed: Vec::new(),
+    };
+    for chat_id in chat_ids {
+        match notifier.deliver(&bot, chat_id, &notification, keyboard.as_ref()).await {
+            Ok(()) => view.delivered.push(chat_id.0),
+            Err(err) => {
+                log::error!("failed to notify {} on channel {}: {}", chat_id, channel_token, err);
+                view.failed.push(FailedDelivery {
+                    chat_id: chat_id.0,
+                    error: err.to_string(),
+                });
+            }
+        }
+    }
+    log::info!(
+        target: "audit",
+        "notification on channel {} reached {} of {} chats",
+        channel_token,
+        view.delivered.len(),
+        view.delivered.len() + view.failed.len()
+    );
+
+    if view.delivered.is_empty() {
+        Ok(HttpResponse::BadGateway().json(view))
+    } else {
+        Ok(HttpResponse::Ok().json(view))
+    }
+}
@@ -0,0 +1,96 @@
+use actix_web::{get, HttpResponse};
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+
+use crate::controller::{api, events, health, notify};
+use crate::events::{Event, EventKind};
+use crate::gitlab::{Commit, MergeRequest, MergeRequestAuthor, Repository};
+use crate::notify::{Button, Format, Notification};
+use crate::report::{DateRange, Report, RepositoryReport};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "digireport", description = "Reports, subscriptions and deliveries of the digireport bot"),
+    paths(
+        api::users,
+        api::subscriptions,
+        api::subscribe,
+        api::unsubscribe,
+        api::user_report,
+        api::deliver,
+        events::stream,
+        notify::notify,
+        health::healthz,
+        health::readyz,
+        health::export_metrics,
+    ),
+    components(schemas(
+        api::ErrorBody,
+        api::UserView,
+        api::SubscriptionView,
+        api::Subscription,
+        api::ReportView,
+        api::DeliveryView,
+        health::Readiness,
+        Event,
+        EventKind,
+        notify::NotifyView,
+        notify::FailedDelivery,
+        Notification,
+        Format,
+        Button,
+        Report,
+        RepositoryReport,
+        DateRange,
+        Commit,
+        MergeRequest,
+        MergeRequestAuthor,
+        Repository,
+    )),
+    modifiers(&Security),
+)]
+struct ApiDoc;
+
+/// the two ways to authenticate, see `auth`
+struct Security;
+
+impl Modify for Security {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .description(Some("a key created with the `/api_key create` bot command"))
+                    .build(),
+            ),
+        );
+        components.add_security_scheme(
+            "telegram_login",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::with_description(
+                "Authorization",
+                "`Telegram ` followed by the Telegram Login Widget payload as a query string",
+            ))),
+        );
+    }
+}
+
+/// the OpenAPI 3 document of the HTTP API
+#[get("/api/openapi.json")]
+pub async fn openapi_json() -> HttpResponse {
+    HttpResponse::Ok().json(ApiDoc::openapi())
+}
+
+/// interactive documentation rendering `/api/openapi.json`
+#[get("/api/docs")]
+pub async fn docs() -> HttpResponse {
+    HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>digireport API</title>\
+         <link rel=\"stylesheet\" href=\"https://unpkg.com/swagger-ui-dist@5/swagger-ui.css\"></head>\
+         <body><div id=\"docs\"></div>\
+         <script src=\"https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js\"></script>\
+         <script>SwaggerUIBundle({url: '/api/openapi.json', dom_id: '#docs'});</script>\
+         </body></html>",
+    )
+}
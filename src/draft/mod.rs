@@ -0,0 +1,349 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use teloxide::prelude::*;
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup, UserId};
+use tokio::task::JoinHandle;
+
+use crate::context;
+use crate::i18n::{Lang, Text};
+use crate::report;
+use crate::shutdown::Shutdown;
+
+pub type DraftId = u64;
+
+// minutes to wait for the author before a draft is published as is
+const DEFAULT_DRAFT_TIMEOUT: u64 = 60;
+const DEFAULT_DELIVERIES_PATH: &str = "deliveries.json";
+
+#[derive(Clone, Debug)]
+pub struct Draft {
+    pub author: UserId,
+    pub channel: ChatId,
+    pub text: String,
+    // free-form notes/blockers paragraph appended below the report
+    pub notes: Option<String>,
+}
+
+impl Draft {
+    pub fn new(author: UserId, channel: ChatId, text: String) -> Draft {
+        Draft {
+            author,
+            channel,
+            text,
+            notes: None,
+        }
+    }
+
+    pub fn render(&self, lang: Lang) -> String {
+        match &self.notes {
+            Some(notes) => format!(
+                "{}\n{}\n{}\n",
+                self.text.trim_end(),
+                Text::DraftNotes.get(lang),
+                notes
+            ),
+            None => self.text.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    // sent to the author, waiting for approval
+    AwaitingReview,
+    Published,
+    Skipped,
+    Failed,
+}
+
+impl DeliveryStatus {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DeliveryStatus::AwaitingReview => "awaiting review",
+            DeliveryStatus::Published => "published",
+            DeliveryStatus::Skipped => "skipped",
+            DeliveryStatus::Failed => "failed",
+        }
+    }
+}
+
+/// what happened to one scheduled or API-requested report, kept for the dashboard
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Delivery {
+    // None when the report failed before a draft was made or was sent without review
+    pub draft: Option<DraftId>,
+    pub author: UserId,
+    pub channel: ChatId,
+    pub date: NaiveDate,
+    pub commits: usize,
+    pub status: DeliveryStatus,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// where the deliveries are saved so the dashboard survives restarts, `DELIVERIES_PATH`
+pub fn deliveries_path() -> PathBuf {
+    PathBuf::from(env::var("DELIVERIES_PATH").unwrap_or_else(|_| DEFAULT_DELIVERIES_PATH.to_string()))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DraftAction {
+    Approve,
+    Edit,
+    Skip,
+}
+
+impl DraftAction {
+    fn name(&self) -> &'static str {
+        match self {
+            DraftAction::Approve => "approve",
+            DraftAction::Edit => "edit",
+            DraftAction::Skip => "skip",
+        }
+    }
+
+    pub fn callback_data(&self, id: DraftId) -> String {
+        format!("draft:{}:{}", self.name(), id)
+    }
+
+    /// parses callback data produced by `callback_data`
+    pub fn parse(data: &str) -> Option<(DraftAction, DraftId)> {
+        let mut parts = data.splitn(3, ':');
+        if parts.next() != Some("draft") {
+            return None;
+        }
+        let action = match parts.next()? {
+            "approve" => DraftAction::Approve,
+            "edit" => DraftAction::Edit,
+            "skip" => DraftAction::Skip,
+            _ => return None,
+        };
+        let id = parts.next()?.parse().ok()?;
+
+        Some((action, id))
+    }
+}
+
+/// reads `DRAFT_TIMEOUT_MINUTES`, after which a pending draft is auto-approved
+pub fn draft_timeout() -> Duration {
+    let minutes = env::var("DRAFT_TIMEOUT_MINUTES")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_DRAFT_TIMEOUT);
+
+    Duration::from_secs(minutes * 60)
+}
+
+pub fn keyboard(id: DraftId, lang: Lang) -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(vec![vec![
+        InlineKeyboardButton::callback(Text::DraftApprove.get(lang), DraftAction::Approve.callback_data(id)),
+        InlineKeyboardButton::callback(Text::DraftEdit.get(lang), DraftAction::Edit.callback_data(id)),
+        InlineKeyboardButton::callback(Text::DraftSkip.get(lang), DraftAction::Skip.callback_data(id)),
+    ]])
+}
+
+/// sends the draft to its author privately with the approval buttons
+pub async fn send_preview(bot: &Bot, id: DraftId, draft: &Draft, lang: Lang) -> ResponseResult<()> {
+    let mut parts = report::split_message(&draft.render(lang));
+    let last = parts.pop().unwrap_or_default();
+
+    for part in parts {
+        bot.send_message(draft.author, part).await?;
+    }
+    bot.send_message(draft.author, last)
+        .reply_markup(keyboard(id, lang))
+        .await?;
+
+    Ok(())
+}
+
+/// the auto-approve timer of every pending draft, stopped when the author acts on
+/// the draft and on shutdown
+#[derive(Clone)]
+pub struct DraftTimers {
+    timers: Arc<Mutex<HashMap<DraftId, JoinHandle<()>>>>,
+    // held by drafts being auto-approved, the shutdown waits for them
+    publishing: Arc<tokio::sync::RwLock<()>>,
+    shutdown: Shutdown,
+}
+
+impl DraftTimers {
+    pub fn new(shutdown: Shutdown) -> DraftTimers {
+        DraftTimers {
+            timers: Arc::new(Mutex::new(HashMap::new())),
+            publishing: Arc::new(tokio::sync::RwLock::new(())),
+            shutdown,
+        }
+    }
+
+    /// publishes the draft on its author's behalf once the review window is over,
+    /// restarting the window if it was already running
+    pub fn start(&self, bot: &Bot, ctxt: &Arc<RwLock<context::Context>>, id: DraftId) {
+        let bot = bot.clone();
+        let ctxt = Arc::clone(ctxt);
+        let timers = self.clone();
+        let handle = tokio::spawn(async move {
+            tokio::select! {
+                _ = tokio::time::sleep(draft_timeout()) => {}
+                // the draft stays pending, nothing is published while shutting down
+                _ = timers.shutdown.wait() => {
+                    timers.timers.lock().unwrap().remove(&id);
+                    return;
+                }
+            }
+            let _publishing = timers.publishing.read().await;
+            // dropping the own handle detaches it, so the publication below cannot be cancelled
+            timers.timers.lock().unwrap().remove(&id);
+            // the draft is gone if the author already approved or skipped it
+            if let Err(err) = publish(&bot, &ctxt, id).await {
+                log::error!("failed to auto-approve draft {}: {}", id, err);
+            }
+        });
+
+        if let Some(previous) = self.timers.lock().unwrap().insert(id, handle) {
+            previous.abort();
+        }
+    }
+
+    /// stops the draft's timer once it is published or skipped
+    pub fn cancel(&self, id: DraftId) {
+        if let Some(timer) = self.timers.lock().unwrap().remove(&id) {
+            timer.abort();
+        }
+    }
+
+    /// returns once the drafts being auto-approved are published, after the shutdown
+    /// stopped the other timers
+    pub async fn drain(&self) {
+        let _ = self.publishing.write().await;
+    }
+}
+
+/// stores a new draft, asks the author to review it and publishes it
+/// on their behalf if they do not answer in time, returns the draft ID
+pub async fn propose(
+    bot: &Bot,
+    ctxt: &Arc<RwLock<context::Context>>,
+    timers: &DraftTimers,
+    draft: Draft,
+) -> ResponseResult<DraftId> {
+    let (id, lang) = {
+        let mut ctxt = ctxt.write().unwrap();
+        (ctxt.add_draft(draft.clone()), ctxt.user_lang(draft.author))
+    };
+    // a draft its author never saw would only ever be auto-approved
+    if let Err(err) = send_preview(bot, id, &draft, lang).await {
+        ctxt.write().unwrap().take_draft(id);
+        return Err(err);
+    }
+    timers.start(bot, ctxt, id);
+
+    Ok(id)
+}
+
+/// posts the draft to its channel, returns false if it was no longer pending
+pub async fn publish(bot: &Bot, ctxt: &Arc<RwLock<context::Context>>, id: DraftId) -> ResponseResult<bool> {
+    let (draft, lang) = {
+        let mut ctxt = ctxt.write().unwrap();
+        match ctxt.take_draft(id) {
+            Some(draft) => {
+                let lang = ctxt.user_lang(draft.author);
+                (draft, lang)
+            }
+            None => return Ok(false),
+        }
+    };
+
+    for part in report::split_message(&draft.render(lang)) {
+        if let Err(err) = bot.send_message(draft.channel, part).await {
+            ctxt.write().unwrap().set_delivery_status(id, DeliveryStatus::Failed);
+            return Err(err);
+        }
+    }
+    ctxt.write().unwrap().set_delivery_status(id, DeliveryStatus::Published);
+
+    Ok(true)
+}
+
+/// skips the drafts still waiting for review and tells their authors, drafts only live
+/// in memory and would otherwise be lost silently on exit
+pub async fn drop_pending(bot: &Bot, ctxt: &Arc<RwLock<context::Context>>) {
+    let drafts = ctxt.write().unwrap().take_drafts();
+    for (id, draft) in drafts {
+        let lang = {
+            let mut ctxt = ctxt.write().unwrap();
+            ctxt.set_delivery_status(id, DeliveryStatus::Skipped);
+            ctxt.user_lang(draft.author)
+        };
+        if let Err(err) = bot.send_message(draft.author, Text::DraftDropped.get(lang)).await {
+            log::warn!("failed to tell {} their draft {} was dropped: {}", draft.author, id, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_its_own_callback_data() {
+        for action in [DraftAction::Approve, DraftAction::Edit, DraftAction::Skip] {
+            assert_eq!(DraftAction::parse(&action.callback_data(7)), Some((action, 7)));
+        }
+    }
+
+    #[test]
+    fn rejects_other_callback_data() {
+        assert_eq!(DraftAction::parse("feedback:up:7"), None);
+        assert_eq!(DraftAction::parse("draft:publish:7"), None);
+        assert_eq!(DraftAction::parse("draft:approve:"), None);
+        assert_eq!(DraftAction::parse("draft:approve:x"), None);
+        assert_eq!(DraftAction::parse("draft:approve"), None);
+    }
+
+    #[test]
+    fn renders_the_report_alone_without_notes() {
+        let draft = Draft::new(UserId(1), ChatId(-100), "report\n".to_string());
+
+        assert_eq!(draft.render(Lang::En), "report\n");
+    }
+
+    #[test]
+    fn renders_notes_below_the_report() {
+        let mut draft = Draft::new(UserId(1), ChatId(-100), "report\n\n".to_string());
+        draft.notes = Some("waiting for review".to_string());
+
+        assert_eq!(
+            draft.render(Lang::Id),
+            format!("report\n{}\nwaiting for review\n", Text::DraftNotes.get(Lang::Id))
+        );
+    }
+
+    #[tokio::test]
+    async fn pending_drafts_are_skipped_on_shutdown() {
+        let mut ctxt = context::Context::new();
+        let id = ctxt.add_draft(Draft::new(UserId(1), ChatId(-100), "report\n".to_string()));
+        ctxt.add_delivery(Delivery {
+            draft: Some(id),
+            author: UserId(1),
+            channel: ChatId(-100),
+            date: Utc::now().date_naive(),
+            commits: 1,
+            status: DeliveryStatus::AwaitingReview,
+            updated_at: Utc::now(),
+        });
+        let ctxt = Arc::new(RwLock::new(ctxt));
+        // nothing listens there, the author cannot be told but the draft is still dropped
+        let bot = Bot::new("1:token").set_api_url(reqwest::Url::parse("http://127.0.0.1:1").unwrap());
+
+        drop_pending(&bot, &ctxt).await;
+        let mut ctxt = ctxt.write().unwrap();
+        assert!(ctxt.take_draft(id).is_none());
+        assert_eq!(ctxt.deliveries()[0].status, DeliveryStatus::Skipped);
+    }
+}
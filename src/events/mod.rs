@@ -0,0 +1,472 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
+use std::env;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use utoipa::ToSchema;
+
+use crate::context;
+use crate::shutdown::Shutdown;
+
+// events kept for clients reconnecting with `Last-Event-ID`
+const HISTORY_SIZE: usize = 1000;
+// keys of polled changes remembered so they are only announced once
+const SEEN_SIZE: usize = 10000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Push,
+    MergeRequest,
+    Pipeline,
+}
+
+impl EventKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::Push => "push",
+            EventKind::MergeRequest => "merge_request",
+            EventKind::Pipeline => "pipeline",
+        }
+    }
+}
+
+/// something that happened in a repository, the same whether it came from a forge
+/// webhook or from polling
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct Event {
+    // increasing, also across restarts, sent as the SSE event ID
+    pub id: u64,
+    pub kind: EventKind,
+    // `group/project` when known, the repository name otherwise
+    pub project: String,
+    pub author: String,
+    pub title: String,
+    // merge request state or action, pipeline status
+    pub status: Option<String>,
+    pub url: Option<String>,
+    pub at: DateTime<Utc>,
+}
+
+impl Event {
+    /// whether the event belongs to one of the projects, given as path or name
+    pub fn matches(&self, projects: &[String]) -> bool {
+        projects.is_empty()
+            || projects.iter().any(|project| {
+                self.project.eq_ignore_ascii_case(project)
+                    || self
+                        .project
+                        .to_lowercase()
+                        .ends_with(&format!("/{}", project.to_lowercase()))
+            })
+    }
+}
+
+/// an event before it is numbered
+#[derive(Clone, Debug)]
+pub struct NewEvent {
+    pub kind: EventKind,
+    pub project: String,
+    pub author: String,
+    pub title: String,
+    pub status: Option<String>,
+    pub url: Option<String>,
+}
+
+/// numbers events, keeps the recent ones and hands them to every listener
+#[derive(Clone)]
+pub struct EventBus {
+    history: Arc<Mutex<VecDeque<Event>>>,
+    next_id: Arc<Mutex<u64>>,
+    sender: broadcast::Sender<Event>,
+    // token gitlab sends in `X-Gitlab-Token`, None disables the forge webhook
+    gitlab_token: Option<String>,
+}
+
+impl EventBus {
+    /// reads `GITLAB_WEBHOOK_TOKEN`, the secret configured on the gitlab webhooks
+    pub fn from_env() -> EventBus {
+        let (sender, _) = broadcast::channel(HISTORY_SIZE);
+        // the history is lost on restart, numbering from the start time keeps the IDs of
+        // this process above those a reconnecting client got from the previous one
+        let epoch = Utc::now().timestamp_micros().max(0) as u64;
+
+        EventBus {
+            history: Arc::new(Mutex::new(VecDeque::new())),
+            next_id: Arc::new(Mutex::new(epoch)),
+            sender,
+            gitlab_token: env::var("GITLAB_WEBHOOK_TOKEN").ok().filter(|token| !token.is_empty()),
+        }
+    }
+
+    /// None when no token is configured, otherwise whether the token matches
+    pub fn accepts_gitlab(&self, token: Option<&str>) -> Option<bool> {
+        self.gitlab_token.as_deref().map(|expected| token == Some(expected))
+    }
+
+    pub fn publish(&self, event: NewEvent) -> Event {
+        // held until the event is sent so listeners get the events in ID order
+        let mut next_id = self.next_id.lock().unwrap();
+        *next_id += 1;
+        let event = Event {
+            id: *next_id,
+            kind: event.kind,
+            project: event.project,
+            author: event.author,
+            title: event.title,
+            status: event.status,
+            url: event.url,
+            at: Utc::now(),
+        };
+
+        {
+            let mut history = self.history.lock().unwrap();
+            history.push_back(event.clone());
+            while history.len() > HISTORY_SIZE {
+                history.pop_front();
+            }
+        }
+        // nobody listening is fine
+        let _ = self.sender.send(event.clone());
+        event
+    }
+
+    /// the ID of the latest event, or the one before the first
+    pub fn last_id(&self) -> u64 {
+        *self.next_id.lock().unwrap()
+    }
+
+    /// the remembered events after `last_id`, oldest first
+    pub fn since(&self, last_id: u64) -> Vec<Event> {
+        self.history
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|event| event.id > last_id)
+            .cloned()
+            .collect()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct HookProject {
+    path_with_namespace: String,
+    web_url: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct HookUser {
+    name: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct HookCommit {
+    title: String,
+    url: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct PushHook {
+    #[serde(rename = "ref")]
+    git_ref: String,
+    user_name: String,
+    total_commits_count: usize,
+    commits: Vec<HookCommit>,
+    project: HookProject,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct MergeRequestAttributes {
+    iid: u64,
+    title: String,
+    state: String,
+    // open, update, merge, close, ...
+    action: Option<String>,
+    url: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct MergeRequestHook {
+    user: HookUser,
+    project: HookProject,
+    object_attributes: MergeRequestAttributes,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct PipelineAttributes {
+    id: u64,
+    #[serde(rename = "ref")]
+    git_ref: String,
+    status: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct PipelineHook {
+    user: HookUser,
+    project: HookProject,
+    object_attributes: PipelineAttributes,
+}
+
+/// turns a gitlab webhook payload into an event, `kind` is the `X-Gitlab-Event` header,
+/// None for events that are not streamed
+pub fn from_gitlab(kind: &str, body: &[u8]) -> Result<Option<NewEvent>, serde_json::Error> {
+    let event = match kind {
+        "Push Hook" => {
+            let push: PushHook = serde_json::from_slice(body)?;
+            let branch = push.git_ref.trim_start_matches("refs/heads/");
+            let title = match push.commits.last() {
+                Some(commit) if push.total_commits_count == 1 => format!("{}: {}", branch, commit.title),
+                _ => format!("{} commits to {}", push.total_commits_count, branch),
+            };
+            NewEvent {
+                kind: EventKind::Push,
+                project: push.project.path_with_namespace,
+                author: push.user_name,
+                title,
+                status: None,
+                url: push.commits.last().map(|commit| commit.url.clone()),
+            }
+        }
+        "Merge Request Hook" => {
+            let hook: MergeRequestHook = serde_json::from_slice(body)?;
+            let merge_request = hook.object_attributes;
+            NewEvent {
+                kind: EventKind::MergeRequest,
+                project: hook.project.path_with_namespace,
+                author: hook.user.name,
+                title: format!("!{} {}", merge_request.iid, merge_request.title),
+                status: Some(merge_request.action.unwrap_or(merge_request.state)),
+                url: Some(merge_request.url),
+            }
+        }
+        "Pipeline Hook" => {
+            let hook: PipelineHook = serde_json::from_slice(body)?;
+            let pipeline = hook.object_attributes;
+            NewEvent {
+                kind: EventKind::Pipeline,
+                project: hook.project.path_with_namespace,
+                author: hook.user.name,
+                title: format!("pipeline #{} on {}", pipeline.id, pipeline.git_ref),
+                status: Some(pipeline.status),
+                url: Some(format!("{}/-/pipelines/{}", hook.project.web_url, pipeline.id)),
+            }
+        }
+        _ => return Ok(None),
+    };
+
+    Ok(Some(event))
+}
+
+/// reads `EVENTS_POLL_SECS`, polling is off unless it is set
+fn poll_interval() -> Option<Duration> {
+    env::var("EVENTS_POLL_SECS")
+        .ok()
+        .and_then(|value| value.parse::<u64>().ok())
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs)
+}
+
+/// for forges that cannot send webhooks: asks gitlab for new commits, merge requests
+/// and pipelines of every linked user's repositories and publishes them, until shutdown
+pub async fn poll(ctxt: Arc<RwLock<context::Context>>, bus: EventBus, shutdown: Shutdown) {
+    let interval = match poll_interval() {
+        Some(interval) => interval,
+        None => return,
+    };
+    log::info!("Polling gitlab for events every {} seconds", interval.as_secs());
+
+    let mut seen: VecDeque<String> = VecDeque::new();
+    let mut seen_set: HashSet<String> = HashSet::new();
+    let mut since = Utc::now();
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = shutdown.wait() => break,
+        }
+        let now = Utc::now();
+
+        let users = ctxt.read().unwrap().gitlab_users();
+        let mut found: Vec<(String, NewEvent)> = Vec::new();
+        for user in users {
+            let repositories = match user.get_repositories().await {
+                Ok(repositories) => repositories,
+                Err(err) => {
+                    log::warn!("failed to poll the repositories: {}", err);
+                    continue;
+                }
+            };
+            for repo in repositories {
+                let project = repo.path_with_namespace.clone().unwrap_or_else(|| repo.name.clone());
+                if let Ok(commits) = user.get_commits_between(repo.id, since, now, None).await {
+                    for commit in commits {
+                        found.push((
+                            format!("commit:{}", commit.id),
+                            NewEvent {
+                                kind: EventKind::Push,
+                                project: project.clone(),
+                                author: commit.author_name,
+                                title: commit.title,
+                                status: None,
+                                url: commit.web_url,
+                            },
+                        ));
+                    }
+                }
+                if let Ok(merge_requests) = user.get_merge_requests(repo.id, since, now, None).await {
+                    for merge_request in merge_requests {
+                        found.push((
+                            format!("mr:{}:{}:{}", repo.id, merge_request.iid, merge_request.state),
+                            NewEvent {
+                                kind: EventKind::MergeRequest,
+                                project: project.clone(),
+                                author: merge_request.author.name,
+                                title: format!("!{} {}", merge_request.iid, merge_request.title),
+                                status: Some(merge_request.state),
+                                url: Some(merge_request.web_url),
+                            },
+                        ));
+                    }
+                }
+                if let Ok(pipelines) = user.get_pipelines(repo.id, since).await {
+                    for pipeline in pipelines {
+                        found.push((
+                            format!("pipeline:{}:{}", pipeline.id, pipeline.status),
+                            NewEvent {
+                                kind: EventKind::Pipeline,
+                                project: project.clone(),
+                                author: String::new(),
+                                title: format!("pipeline #{} on {}", pipeline.id, pipeline.git_ref),
+                                status: Some(pipeline.status),
+                                url: Some(pipeline.web_url),
+                            },
+                        ));
+                    }
+                }
+            }
+        }
+
+        // users share repositories, announce every change once
+        for (key, event) in found {
+            if !seen_set.insert(key.clone()) {
+                continue;
+            }
+            seen.push_back(key);
+            while seen.len() > SEEN_SIZE {
+                if let Some(old) = seen.pop_front() {
+                    seen_set.remove(&old);
+                }
+            }
+            bus.publish(event);
+        }
+        since = now;
+    }
+    log::info!("Event polling stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(project: &str) -> NewEvent {
+        NewEvent {
+            kind: EventKind::Push,
+            project: project.to_string(),
+            author: "Budi".to_string(),
+            title: "main: fix login".to_string(),
+            status: None,
+            url: None,
+        }
+    }
+
+    fn projects(projects: &[&str]) -> Vec<String> {
+        projects.iter().map(|project| project.to_string()).collect()
+    }
+
+    #[test]
+    fn matches_projects_by_path_or_name() {
+        let event = EventBus::from_env().publish(event("Team/Payment-Service"));
+
+        assert!(event.matches(&[]));
+        assert!(event.matches(&projects(&["team/payment-service"])));
+        assert!(event.matches(&projects(&["other", "payment-service"])));
+        assert!(!event.matches(&projects(&["service"])));
+        assert!(!event.matches(&projects(&["payment"])));
+        assert!(!event.matches(&projects(&["other/payment-service"])));
+    }
+
+    #[test]
+    fn numbers_events_in_order() {
+        let bus = EventBus::from_env();
+        let before = bus.last_id();
+
+        let first = bus.publish(event("a"));
+        let second = bus.publish(event("b"));
+        assert_eq!(first.id, before + 1);
+        assert_eq!(second.id, before + 2);
+        assert_eq!(bus.last_id(), second.id);
+    }
+
+    #[test]
+    fn replays_the_events_after_an_id() {
+        let bus = EventBus::from_env();
+        let ids: Vec<u64> = ["a", "b", "c"].iter().map(|project| bus.publish(event(project)).id).collect();
+
+        let replayed: Vec<u64> = bus.since(ids[0]).iter().map(|event| event.id).collect();
+        assert_eq!(replayed, ids[1..]);
+        assert_eq!(bus.since(0).len(), 3);
+        assert!(bus.since(ids[2]).is_empty());
+    }
+
+    #[test]
+    fn keeps_only_the_recent_history() {
+        let bus = EventBus::from_env();
+        let first = bus.publish(event("a")).id;
+        for _ in 0..HISTORY_SIZE {
+            bus.publish(event("a"));
+        }
+
+        let history = bus.since(0);
+        assert_eq!(history.len(), HISTORY_SIZE);
+        assert_eq!(history[0].id, first + 1);
+    }
+
+    #[test]
+    fn ids_continue_above_those_of_an_earlier_process() {
+        let earlier = EventBus::from_env();
+        let last = earlier.publish(event("a")).id;
+
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        assert!(EventBus::from_env().last_id() > last);
+    }
+
+    #[test]
+    fn reads_gitlab_push_hooks() {
+        let body = br#"{
+            "ref": "refs/heads/main",
+            "user_name": "Budi",
+            "total_commits_count": 1,
+            "commits": [{ "title": "fix login", "url": "https://gitlab.com/team/app/-/commit/1" }],
+            "project": { "path_with_namespace": "team/app" }
+        }"#;
+
+        let event = from_gitlab("Push Hook", body).unwrap().unwrap();
+        assert_eq!(event.kind, EventKind::Push);
+        assert_eq!(event.project, "team/app");
+        assert_eq!(event.title, "main: fix login");
+        assert!(from_gitlab("Note Hook", body).unwrap().is_none());
+    }
+}
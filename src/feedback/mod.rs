@@ -0,0 +1,161 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::env;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup, UserId};
+
+const DEFAULT_LOG_PATH: &str = "feedback.jsonl";
+
+pub type AnswerId = u64;
+
+/// an answer the asker can still rate
+#[derive(Clone, Debug, Serialize)]
+pub struct AnsweredQuestion {
+    pub question: String,
+    pub answer: String,
+    // None for generated answers
+    pub score: Option<f64>,
+    // knowledge base documents the context was taken from
+    pub sources: Vec<String>,
+    // the chunk the answer was found in, or the whole context of a generated answer
+    pub context: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Rating {
+    Up,
+    Down,
+}
+
+impl Rating {
+    fn name(&self) -> &'static str {
+        match self {
+            Rating::Up => "up",
+            Rating::Down => "down",
+        }
+    }
+
+    pub fn callback_data(&self, id: AnswerId) -> String {
+        format!("feedback:{}:{}", self.name(), id)
+    }
+
+    /// parses callback data produced by `callback_data`
+    pub fn parse(data: &str) -> Option<(Rating, AnswerId)> {
+        let mut parts = data.splitn(3, ':');
+        if parts.next() != Some("feedback") {
+            return None;
+        }
+        let rating = match parts.next()? {
+            "up" => Rating::Up,
+            "down" => Rating::Down,
+            _ => return None,
+        };
+        let id = parts.next()?.parse().ok()?;
+
+        Some((rating, id))
+    }
+}
+
+pub fn keyboard(id: AnswerId) -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(vec![vec![
+        InlineKeyboardButton::callback("👍", Rating::Up.callback_data(id)),
+        InlineKeyboardButton::callback("👎", Rating::Down.callback_data(id)),
+    ]])
+}
+
+#[derive(Serialize)]
+struct FeedbackEntry<'a> {
+    timestamp: DateTime<Utc>,
+    user_id: u64,
+    rating: Rating,
+    #[serde(flatten)]
+    answer: &'a AnsweredQuestion,
+}
+
+/// where ratings are appended as JSON lines, `FEEDBACK_LOG_PATH`
+pub fn log_path() -> PathBuf {
+    PathBuf::from(env::var("FEEDBACK_LOG_PATH").unwrap_or_else(|_| DEFAULT_LOG_PATH.to_string()))
+}
+
+/// appends a rating to the feedback log
+pub fn record(user_id: UserId, rating: Rating, answer: &AnsweredQuestion) -> io::Result<()> {
+    append(&log_path(), user_id, rating, answer)
+}
+
+fn append(path: &Path, user_id: UserId, rating: Rating, answer: &AnsweredQuestion) -> io::Result<()> {
+    let entry = FeedbackEntry {
+        timestamp: Utc::now(),
+        user_id: user_id.0,
+        rating,
+        answer,
+    };
+    let line = serde_json::to_string(&entry)?;
+
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", line)
+}
+
+/// users allowed to export the feedback log, from the comma separated `ADMIN_USER_IDS`
+pub fn is_admin(user_id: UserId) -> bool {
+    env::var("ADMIN_USER_IDS")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|id| id.trim().parse::<u64>().ok())
+        .any(|id| id == user_id.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use std::fs;
+
+    fn answered() -> AnsweredQuestion {
+        AnsweredQuestion {
+            question: "how do we deploy?".to_string(),
+            answer: "with docker".to_string(),
+            score: Some(0.42),
+            sources: vec!["knowledge/deploy.md".to_string()],
+            context: "we deploy with docker compose".to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_its_own_callback_data() {
+        assert_eq!(Rating::parse(&Rating::Up.callback_data(7)), Some((Rating::Up, 7)));
+        assert_eq!(Rating::parse(&Rating::Down.callback_data(8)), Some((Rating::Down, 8)));
+    }
+
+    #[test]
+    fn rejects_other_callback_data() {
+        assert_eq!(Rating::parse("draft:publish:7"), None);
+        assert_eq!(Rating::parse("feedback:meh:7"), None);
+        assert_eq!(Rating::parse("feedback:up:"), None);
+        assert_eq!(Rating::parse("feedback:up"), None);
+    }
+
+    #[test]
+    fn appends_one_json_line_per_rating() {
+        let path = env::temp_dir().join(format!("digireport-feedback-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        append(&path, UserId(5), Rating::Up, &answered()).unwrap();
+        append(&path, UserId(6), Rating::Down, &answered()).unwrap();
+
+        let log = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let entries: Vec<Value> = log.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0]["user_id"], 5);
+        assert_eq!(entries[0]["rating"], "up");
+        assert_eq!(entries[1]["rating"], "down");
+        // the answer is flattened next to the rating so the log can be read as a table
+        assert_eq!(entries[0]["question"], "how do we deploy?");
+        assert_eq!(entries[0]["score"], 0.42);
+        assert_eq!(entries[0]["sources"][0], "knowledge/deploy.md");
+        assert_eq!(entries[0]["context"], "we deploy with docker compose");
+    }
+}
@@ -1,68 +1,315 @@
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, SecondsFormat, Utc};
 use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::error::Error;
+use utoipa::ToSchema;
+
+use crate::metrics;
+
+// the most gitlab returns per page
+const PER_PAGE: &str = "100";
+// a list longer than this many pages is cut, it would take too long to report anyway
+const MAX_PAGES: usize = 50;
 
 #[derive(Debug, Clone, Default)]
 pub struct GitlabUser {
     username: String,
     token: String,
+    // the token owner, None until `/user` could be read
+    account: Option<Account>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Account {
+    pub id: u64,
+    pub username: String,
+    pub name: String,
+    // only returned to the account owner
+    #[serde(default)]
+    pub email: Option<String>,
+}
+
+impl Account {
+    /// how git knows the owner as a commit author, the email when gitlab shares it
+    pub fn commit_author(&self) -> &str {
+        match self.email.as_deref() {
+            Some(email) if !email.is_empty() => email,
+            _ => &self.name,
+        }
+    }
 }
 
-#[derive(Debug, Deserialize, Default)]
+#[derive(Debug, Clone, Deserialize, Serialize, Default, ToSchema)]
 pub struct Commit {
-    id: String,
-    short_id: String,
-    title: String,
-    author_name: String,
+    pub id: String,
+    pub short_id: String,
+    pub title: String,
+    pub author_name: String,
+    pub created_at: Option<DateTime<Utc>>,
+    pub web_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, Default, ToSchema)]
+pub struct MergeRequestAuthor {
+    pub name: String,
+    pub username: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, Default, ToSchema)]
+pub struct MergeRequest {
+    pub iid: u32,
+    pub title: String,
+    pub description: Option<String>,
+    // opened, closed, locked or merged
+    pub state: String,
+    pub author: MergeRequestAuthor,
+    pub web_url: String,
 }
 
-#[derive(Debug, Deserialize, Serialize, Default)]
+#[derive(Debug, Deserialize)]
+pub struct TreeEntry {
+    pub path: String,
+    // `blob` for files, `tree` for directories
+    #[serde(rename = "type")]
+    pub kind: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default, ToSchema)]
 pub struct  Repository {
     pub id: u32,
     pub name: String,
+    // e.g. `group/project`, what forge webhooks call the project
+    #[serde(default)]
+    pub path_with_namespace: Option<String>,
     pub description: Option<String>,
     pub visibility: String,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct Pipeline {
+    pub id: u64,
+    // created, running, success, failed, canceled, ...
+    pub status: String,
+    #[serde(rename = "ref")]
+    pub git_ref: String,
+    pub web_url: String,
+}
+
 // get user commit message
 impl GitlabUser {
 
     pub fn new(token: String) -> GitlabUser {
         
-        GitlabUser { username: "".to_string(), token: token }
+        GitlabUser { username: "".to_string(), token, account: None }
     }
 
+    #[cfg(test)]
     pub fn set_user(&mut self, username: String) {
         self.username = username
     }
 
-    pub async fn get_commit(&self, repo_id: u32, date: DateTime<Utc>) -> Result<Vec<Commit>, Box<dyn Error>> {
-        let url = format!("https://gitlab.com/api/v4/projects/{}/repository/commits?since={}", repo_id, date);
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    /// the token with all but its last four characters hidden, safe to show outside the bot
+    pub fn redacted_token(&self) -> String {
+        let length = self.token.chars().count();
+        // short tokens would give away too much of themselves
+        if length <= 8 {
+            return "****".to_string();
+        }
+        format!("****{}", self.token.chars().skip(length - 4).collect::<String>())
+    }
+
+    /// the commits of a repository within the given period, only those of `author`
+    /// (a name or email, as git records them) when set
+    pub async fn get_commits_between(
+        &self,
+        repo_id: u32,
+        since: DateTime<Utc>,
+        until: DateTime<Utc>,
+        author: Option<&str>,
+    ) -> Result<Vec<Commit>, Box<dyn Error + Send + Sync>> {
+        let url = format!("https://gitlab.com/api/v4/projects/{}/repository/commits", repo_id);
+        let mut query = vec![
+            ("since", since.to_rfc3339_opts(SecondsFormat::Secs, true)),
+            ("until", until.to_rfc3339_opts(SecondsFormat::Secs, true)),
+        ];
+        if let Some(author) = author {
+            query.push(("author", author.to_string()));
+        }
+
+        self.get_all("commits", &url, &query).await
+    }
+
+    /// lists the merge requests of a repository updated within the given period, only
+    /// those opened by the Gitlab user `author_id` when set
+    pub async fn get_merge_requests(
+        &self,
+        repo_id: u32,
+        updated_after: DateTime<Utc>,
+        updated_before: DateTime<Utc>,
+        author_id: Option<u64>,
+    ) -> Result<Vec<MergeRequest>, Box<dyn Error + Send + Sync>> {
+        let url = format!("https://gitlab.com/api/v4/projects/{}/merge_requests", repo_id);
+        let mut query = vec![
+            ("updated_after", updated_after.to_rfc3339_opts(SecondsFormat::Secs, true)),
+            ("updated_before", updated_before.to_rfc3339_opts(SecondsFormat::Secs, true)),
+            ("scope", "all".to_string()),
+        ];
+        if let Some(author_id) = author_id {
+            query.push(("author_id", author_id.to_string()));
+        }
+
+        self.get_all("merge_requests", &url, &query).await
+    }
+
+    /// lists the pipelines of a repository updated after the given time
+    pub async fn get_pipelines(
+        &self,
+        repo_id: u32,
+        updated_after: DateTime<Utc>,
+    ) -> Result<Vec<Pipeline>, Box<dyn Error + Send + Sync>> {
+        let url = format!("https://gitlab.com/api/v4/projects/{}/pipelines", repo_id);
+        let query = [("updated_after", updated_after.to_rfc3339_opts(SecondsFormat::Secs, true))];
+
+        self.get_all("pipelines", &url, &query).await
+    }
+
+    /// the projects the token owner is a member of
+    pub async fn get_repositories(&self) -> Result<Vec<Repository>, Box<dyn Error + Send + Sync>> {
+        let query = [("membership", "true".to_string())];
+
+        self.get_all("projects", "https://gitlab.com/api/v4/projects", &query).await
+    }
+
+    /// the account the token belongs to
+    pub async fn get_account(&self) -> Result<Account, Box<dyn Error + Send + Sync>> {
         let client = reqwest::Client::new();
         let mut headers = HeaderMap::new();
         headers.insert(AUTHORIZATION, HeaderValue::from_str(&format!("Bearer {}", self.token))?);
 
-        let response = client.get(&url).headers(headers).send().await?;
-        let commits = response.json::<Vec<Commit>>().await?;
+        let request = client.get("https://gitlab.com/api/v4/user").headers(headers).send();
+        let response = metrics::gitlab_call("user", request).await?.error_for_status()?;
+        let account = response.json::<Account>().await?;
 
-        Ok(commits)
-        
+        Ok(account)
     }
 
-    pub async fn get_repositories(&self) -> Result<Vec<Repository>, Box<dyn Error + Send + Sync>> {
-        let url = "https://gitlab.com/api/v4/projects";
+    /// reads the token owner's account once, so reports can be limited to their own work
+    pub async fn load_account(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let account = self.get_account().await?;
+        self.username = account.username.clone();
+        self.account = Some(account);
+
+        Ok(())
+    }
+
+    /// the account read at registration, or read now if that failed
+    pub async fn account(&self) -> Result<Account, Box<dyn Error + Send + Sync>> {
+        match &self.account {
+            Some(account) => Ok(account.clone()),
+            None => self.get_account().await,
+        }
+    }
+
+    /// every page of a list endpoint, gitlab returns 20 items per page unless asked otherwise
+    async fn get_all<T: DeserializeOwned>(
+        &self,
+        endpoint: &str,
+        url: &str,
+        query: &[(&str, String)],
+    ) -> Result<Vec<T>, Box<dyn Error + Send + Sync>> {
+        let client = reqwest::Client::new();
+        let mut items = Vec::new();
+        let mut page = "1".to_string();
+
+        for _ in 0..MAX_PAGES {
+            let mut headers = HeaderMap::new();
+            headers.insert(AUTHORIZATION, HeaderValue::from_str(&format!("Bearer {}", self.token))?);
+
+            let request = client
+                .get(url)
+                .query(query)
+                .query(&[("per_page", PER_PAGE), ("page", page.as_str())])
+                .headers(headers)
+                .send();
+            let response = metrics::gitlab_call(endpoint, request).await?.error_for_status()?;
+            // empty on the last page
+            let next_page = response
+                .headers()
+                .get("X-Next-Page")
+                .and_then(|value| value.to_str().ok())
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+                .map(str::to_string);
+            items.extend(response.json::<Vec<T>>().await?);
+
+            match next_page {
+                Some(next_page) => page = next_page,
+                None => return Ok(items),
+            }
+        }
+
+        log::warn!("stopped reading {} after {} pages", url, MAX_PAGES);
+        Ok(items)
+    }
+
+    /// lists the files under `path` of the default branch, empty if the path does not exist
+    pub async fn get_tree(&self, repo_id: u32, path: &str) -> Result<Vec<TreeEntry>, Box<dyn Error + Send + Sync>> {
+        let url = format!("https://gitlab.com/api/v4/projects/{}/repository/tree", repo_id);
         let client = reqwest::Client::new();
         let mut headers = HeaderMap::new();
         headers.insert(AUTHORIZATION, HeaderValue::from_str(&format!("Bearer {}", self.token))?);
 
-        let response = client.get(url).headers(headers).send().await?;
-        let repositories = response.json::<Vec<Repository>>().await?;
+        let request = client
+            .get(&url)
+            .query(&[("path", path), ("recursive", "true"), ("per_page", "100")])
+            .headers(headers)
+            .send();
+        let response = metrics::gitlab_call("tree", request).await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(Vec::new());
+        }
+        let entries = response.error_for_status()?.json::<Vec<TreeEntry>>().await?;
 
-        Ok(repositories)
+        Ok(entries)
     }
 
-    pub fn set_token(&mut self, token: String) {
-        self.token = token
+    /// returns the raw content of a file on the default branch, None if it does not exist
+    pub async fn get_file(&self, repo_id: u32, path: &str) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        let url = format!(
+            "https://gitlab.com/api/v4/projects/{}/repository/files/{}/raw",
+            repo_id,
+            encode_path(path)
+        );
+        let client = reqwest::Client::new();
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_str(&format!("Bearer {}", self.token))?);
+
+        let request = client
+            .get(&url)
+            .query(&[("ref", "HEAD")])
+            .headers(headers)
+            .send();
+        let response = metrics::gitlab_call("file", request).await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let content = response.error_for_status()?.text().await?;
+
+        Ok(Some(content))
     }
 }
+
+// gitlab expects file paths as a single URL-encoded segment
+fn encode_path(path: &str) -> String {
+    path.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
@@ -0,0 +1,415 @@
+use chrono::{Datelike, NaiveDate, Weekday};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Lang {
+    #[default]
+    En,
+    Id,
+}
+
+impl Lang {
+    /// maps an IETF language tag as sent by telegram (`en`, `en-US`, `id`) to a language
+    pub fn from_code(code: &str) -> Option<Lang> {
+        let primary = code.split(['-', '_']).next().unwrap_or("").to_lowercase();
+
+        match primary.as_str() {
+            "en" => Some(Lang::En),
+            // `in` is the legacy code for Indonesian
+            "id" | "in" => Some(Lang::Id),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Lang::En => "English",
+            Lang::Id => "Bahasa Indonesia",
+        }
+    }
+
+    pub fn format_date(&self, date: NaiveDate) -> String {
+        match self {
+            Lang::En => date.format("%A, %-d %B %Y").to_string(),
+            Lang::Id => format!(
+                "{}, {} {} {}",
+                weekday_id(date.weekday()),
+                date.day(),
+                MONTHS_ID[date.month0() as usize],
+                date.year()
+            ),
+        }
+    }
+}
+
+const MONTHS_ID: [&str; 12] = [
+    "Januari", "Februari", "Maret", "April", "Mei", "Juni", "Juli", "Agustus", "September",
+    "Oktober", "November", "Desember",
+];
+
+fn weekday_id(weekday: Weekday) -> &'static str {
+    match weekday {
+        Weekday::Mon => "Senin",
+        Weekday::Tue => "Selasa",
+        Weekday::Wed => "Rabu",
+        Weekday::Thu => "Kamis",
+        Weekday::Fri => "Jumat",
+        Weekday::Sat => "Sabtu",
+        Weekday::Sun => "Minggu",
+    }
+}
+
+/// every message the bot sends, `{}` placeholders are filled in order by `Text::fmt`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Text {
+    NotUnderstood,
+    EmptyMessage,
+    UserNotFound,
+    PlainTextOnly,
+    TokenTextOnly,
+    AskFullName,
+    AskGitlabToken,
+    ProcessingToken,
+    TokenSaved,
+    RegisterTokenFirst,
+    RepositoryEntry,
+    RepositoriesNone,
+    RepositoriesFailed,
+    NoKnowledge,
+    ConversationReset,
+    AnswerSource,
+    AnswerConfidence,
+    AnswerAlternatives,
+    FeedbackThanks,
+    FeedbackExpired,
+    FeedbackEmpty,
+    FeedbackExportFailed,
+    AdminOnly,
+    ApiKeyUsage,
+    ApiKeyPrivateOnly,
+    ApiKeyInvalidScope,
+    ApiKeyCreated,
+    ApiKeyRevoked,
+    ApiKeyNotFound,
+    ApiKeyNone,
+    HistoryFailed,
+    HistoryEmpty,
+    HistoryMatches,
+    QaLoading,
+    QaDisabled,
+    QaBusy,
+    QaTimedOut,
+    LangUsage,
+    LangChanged,
+    SummaryUsage,
+    SummaryOn,
+    SummaryOff,
+    SummaryUnavailable,
+    ReportUsage,
+    InvalidDate,
+    ReversedRange,
+    ReportFailed,
+    ReportPublished,
+    NoLinkedChannel,
+    ReportHeading,
+    ReportRangeHeading,
+    NoCommits,
+    ReportUncategorized,
+    ReportAllocation,
+    CategoryFeature,
+    CategoryBugfix,
+    CategoryRefactor,
+    CategoryDocs,
+    CategoryInfra,
+    CategoryTest,
+    LinkChannelUsage,
+    ChannelNotFound,
+    NotChannelAdmin,
+    ChannelLinked,
+    DraftApprove,
+    DraftEdit,
+    DraftSkip,
+    DraftHandled,
+    DraftNotAuthor,
+    DraftEditPrompt,
+    DraftWaiting,
+    DraftSkipped,
+    DraftDropped,
+    DraftNotes,
+    StandupGroupOnly,
+    StandupJoined,
+    StandupAlreadyJoined,
+    StandupLeft,
+    StandupNotMember,
+    StandupPrompt,
+    StandupAskBlockers,
+    StandupThanks,
+    StandupHeading,
+    StandupDone,
+    StandupToday,
+    StandupBlockers,
+    StandupNoCommits,
+    StandupCommitsUnavailable,
+    StandupNoToken,
+    StandupNoAnswer,
+}
+
+impl Text {
+    pub fn get(self, lang: Lang) -> &'static str {
+        match lang {
+            Lang::En => self.en(),
+            Lang::Id => self.id(),
+        }
+    }
+
+    /// returns the text with its `{}` placeholders replaced by `args` in order
+    pub fn fmt(self, lang: Lang, args: &[&str]) -> String {
+        let mut result = String::new();
+        let mut args = args.iter();
+
+        let mut parts = self.get(lang).split("{}").peekable();
+        while let Some(part) = parts.next() {
+            result.push_str(part);
+            if parts.peek().is_some() {
+                result.push_str(args.next().copied().unwrap_or(""));
+            }
+        }
+
+        result
+    }
+
+    fn en(self) -> &'static str {
+        match self {
+            Text::NotUnderstood => "Sorry, I don't understand",
+            Text::EmptyMessage => "The message is empty",
+            Text::UserNotFound => "Error: User not found",
+            Text::PlainTextOnly => "Send me plain text.",
+            Text::TokenTextOnly => "Send me token text.",
+            Text::AskFullName => "Let's start! What's your full name?",
+            Text::AskGitlabToken => "Give me your gitlab token, {}?",
+            Text::ProcessingToken => "Processing token",
+            Text::TokenSaved => "Your token has been saved",
+            Text::RegisterTokenFirst => "Register your gitlab token first with /add_token <token>",
+            Text::RepositoryEntry => "id: {}\nname: {}\ndescription: {}\nvisibility: {}",
+            Text::RepositoriesNone => "You are not a member of any repository",
+            Text::RepositoriesFailed => "Failed to read your repositories from gitlab",
+            Text::NoKnowledge => "I don't know anything about that yet",
+            Text::ConversationReset => "Conversation cleared, ask me anything",
+            Text::AnswerSource => "Source: {}",
+            Text::AnswerConfidence => "Confidence: {}%",
+            Text::AnswerAlternatives => "Other possible answers:",
+            Text::FeedbackThanks => "Thanks for the feedback",
+            Text::FeedbackExpired => "This answer can no longer be rated",
+            Text::FeedbackEmpty => "No feedback has been recorded yet",
+            Text::FeedbackExportFailed => "Failed to export the feedback log",
+            Text::AdminOnly => "Only admins can do that",
+            Text::ApiKeyUsage => "Usage: /api_key create <name> [reports:read|admin,...], /api_key revoke <id> or /api_key list",
+            Text::ApiKeyPrivateOnly => "Create API keys in a private chat with me so the key stays secret",
+            Text::ApiKeyInvalidScope => "Unknown scope `{}`, use reports:read or admin",
+            Text::ApiKeyCreated => "API key {} created with the scopes {}. Keep it safe, it is not shown again:\n\n{}",
+            Text::ApiKeyRevoked => "API key {} revoked",
+            Text::ApiKeyNotFound => "There is no API key {}",
+            Text::ApiKeyNone => "No API keys have been created yet",
+            Text::HistoryFailed => "Failed to read the history from gitlab, try again later",
+            Text::HistoryEmpty => "I found no changes matching that between {} and {}",
+            Text::HistoryMatches => "Matching changes:",
+            Text::QaLoading => "I'm still loading my knowledge, try again in a moment",
+            Text::QaDisabled => "I can't answer questions right now",
+            Text::QaBusy => "I'm answering too many questions right now, try again shortly",
+            Text::QaTimedOut => "That took too long, please ask again",
+            Text::LangUsage => "Usage: /lang en|id",
+            Text::LangChanged => "I'll speak {} from now on",
+            Text::SummaryUsage => "Usage: /summary on|off",
+            Text::SummaryOn => "Reports start with a summary",
+            Text::SummaryOff => "Reports list every commit without a summary",
+            Text::SummaryUnavailable => "No summarisation model is configured, reports list every commit for now",
+            Text::ReportUsage => "{}\nUsage: /report [today|yesterday|week|YYYY-MM-DD..YYYY-MM-DD] [--publish]",
+            Text::InvalidDate => "Invalid date `{}`, use YYYY-MM-DD",
+            Text::ReversedRange => "The range starts after it ends",
+            Text::ReportFailed => "Failed to generate report",
+            Text::ReportPublished => "Report published",
+            Text::NoLinkedChannel => "You have no linked channel to publish to",
+            Text::ReportHeading => "Report for {}",
+            Text::ReportRangeHeading => "Report for {} - {}",
+            Text::NoCommits => "No commits in this period.",
+            Text::ReportUncategorized => "other",
+            Text::ReportAllocation => "Time allocation: {}",
+            Text::CategoryFeature => "feature",
+            Text::CategoryBugfix => "bugfix",
+            Text::CategoryRefactor => "refactor",
+            Text::CategoryDocs => "docs",
+            Text::CategoryInfra => "infra",
+            Text::CategoryTest => "test",
+            Text::LinkChannelUsage => "Usage: /link_channel @channel",
+            Text::ChannelNotFound => "I can't find {}, add me to it first",
+            Text::NotChannelAdmin => "Make me an admin of {} with permission to post messages first",
+            Text::ChannelLinked => "Reports will be posted to {}",
+            Text::DraftApprove => "Approve",
+            Text::DraftEdit => "Edit",
+            Text::DraftSkip => "Skip",
+            Text::DraftHandled => "This draft was already handled",
+            Text::DraftNotAuthor => "Only the author can review this draft",
+            Text::DraftEditPrompt => "Send me the revised report, or a message starting with \"notes:\" to add notes/blockers",
+            Text::DraftWaiting => "Waiting for your changes",
+            Text::DraftSkipped => "Report skipped",
+            Text::DraftDropped => "The bot restarted before you reviewed your report, it was not published",
+            Text::DraftNotes => "Notes/blockers:",
+            Text::StandupGroupOnly => "Send this command in your team group",
+            Text::StandupJoined => "You joined the stand-up, I'll message you privately at stand-up time",
+            Text::StandupAlreadyJoined => "You already take part in this stand-up",
+            Text::StandupLeft => "You left the stand-up",
+            Text::StandupNotMember => "You don't take part in this stand-up",
+            Text::StandupPrompt => "Stand-up time! What will you work on today?",
+            Text::StandupAskBlockers => "Anything blocking you? (reply \"none\" if not)",
+            Text::StandupThanks => "Thanks! Your answers will be in today's stand-up",
+            Text::StandupHeading => "Stand-up {}",
+            Text::StandupDone => "Done:",
+            Text::StandupToday => "Today:",
+            Text::StandupBlockers => "Blockers:",
+            Text::StandupNoCommits => "- no commits or merge requests",
+            Text::StandupCommitsUnavailable => "- commits unavailable",
+            Text::StandupNoToken => "- no Gitlab token registered",
+            Text::StandupNoAnswer => "No answer",
+        }
+    }
+
+    fn id(self) -> &'static str {
+        match self {
+            Text::NotUnderstood => "Maaf, saya tidak mengerti",
+            Text::EmptyMessage => "Pesannya kosong",
+            Text::UserNotFound => "Error: Pengguna tidak ditemukan",
+            Text::PlainTextOnly => "Kirimkan teks biasa.",
+            Text::TokenTextOnly => "Kirimkan token dalam bentuk teks.",
+            Text::AskFullName => "Ayo mulai! Siapa nama lengkap Anda?",
+            Text::AskGitlabToken => "Berikan token gitlab Anda, {}?",
+            Text::ProcessingToken => "Memproses token",
+            Text::TokenSaved => "Token Anda telah disimpan",
+            Text::RegisterTokenFirst => "Daftarkan token gitlab Anda dulu dengan /add_token <token>",
+            Text::RepositoryEntry => "id: {}\nnama: {}\ndeskripsi: {}\nvisibilitas: {}",
+            Text::RepositoriesNone => "Anda belum menjadi anggota repositori mana pun",
+            Text::RepositoriesFailed => "Gagal membaca repositori Anda dari gitlab",
+            Text::NoKnowledge => "Saya belum tahu apa-apa tentang itu",
+            Text::ConversationReset => "Percakapan dihapus, silakan bertanya lagi",
+            Text::AnswerSource => "Sumber: {}",
+            Text::AnswerConfidence => "Keyakinan: {}%",
+            Text::AnswerAlternatives => "Kemungkinan jawaban lain:",
+            Text::FeedbackThanks => "Terima kasih atas masukannya",
+            Text::FeedbackExpired => "Jawaban ini sudah tidak bisa dinilai",
+            Text::FeedbackEmpty => "Belum ada masukan yang tercatat",
+            Text::FeedbackExportFailed => "Gagal mengekspor catatan masukan",
+            Text::AdminOnly => "Hanya admin yang bisa melakukan itu",
+            Text::ApiKeyUsage => "Penggunaan: /api_key create <nama> [reports:read|admin,...], /api_key revoke <id> atau /api_key list",
+            Text::ApiKeyPrivateOnly => "Buat API key di chat pribadi dengan saya agar kuncinya tetap rahasia",
+            Text::ApiKeyInvalidScope => "Cakupan `{}` tidak dikenal, gunakan reports:read atau admin",
+            Text::ApiKeyCreated => "API key {} dibuat dengan cakupan {}. Simpan baik-baik, kunci ini tidak ditampilkan lagi:\n\n{}",
+            Text::ApiKeyRevoked => "API key {} dicabut",
+            Text::ApiKeyNotFound => "Tidak ada API key {}",
+            Text::ApiKeyNone => "Belum ada API key yang dibuat",
+            Text::HistoryFailed => "Gagal membaca riwayat dari gitlab, coba lagi nanti",
+            Text::HistoryEmpty => "Saya tidak menemukan perubahan yang cocok antara {} dan {}",
+            Text::HistoryMatches => "Perubahan yang cocok:",
+            Text::QaLoading => "Saya masih memuat pengetahuan saya, coba lagi sebentar lagi",
+            Text::QaDisabled => "Saya tidak bisa menjawab pertanyaan saat ini",
+            Text::QaBusy => "Saya sedang menjawab terlalu banyak pertanyaan, coba lagi sebentar lagi",
+            Text::QaTimedOut => "Terlalu lama, silakan tanyakan lagi",
+            Text::LangUsage => "Penggunaan: /lang en|id",
+            Text::LangChanged => "Mulai sekarang saya akan memakai {}",
+            Text::SummaryUsage => "Penggunaan: /summary on|off",
+            Text::SummaryOn => "Laporan diawali dengan ringkasan",
+            Text::SummaryOff => "Laporan mencantumkan setiap commit tanpa ringkasan",
+            Text::SummaryUnavailable => "Belum ada model peringkas, untuk sementara laporan mencantumkan setiap commit",
+            Text::ReportUsage => "{}\nPenggunaan: /report [today|yesterday|week|YYYY-MM-DD..YYYY-MM-DD] [--publish]",
+            Text::InvalidDate => "Tanggal `{}` tidak valid, gunakan YYYY-MM-DD",
+            Text::ReversedRange => "Awal rentang berada setelah akhirnya",
+            Text::ReportFailed => "Gagal membuat laporan",
+            Text::ReportPublished => "Laporan telah dipublikasikan",
+            Text::NoLinkedChannel => "Anda belum menautkan channel untuk publikasi",
+            Text::ReportHeading => "Laporan {}",
+            Text::ReportRangeHeading => "Laporan {} - {}",
+            Text::NoCommits => "Tidak ada commit pada periode ini.",
+            Text::ReportUncategorized => "lainnya",
+            Text::ReportAllocation => "Alokasi waktu: {}",
+            Text::CategoryFeature => "fitur",
+            Text::CategoryBugfix => "perbaikan bug",
+            Text::CategoryRefactor => "refaktor",
+            Text::CategoryDocs => "dokumentasi",
+            Text::CategoryInfra => "infrastruktur",
+            Text::CategoryTest => "pengujian",
+            Text::LinkChannelUsage => "Penggunaan: /link_channel @channel",
+            Text::ChannelNotFound => "Saya tidak menemukan {}, tambahkan saya ke sana dulu",
+            Text::NotChannelAdmin => "Jadikan saya admin {} dengan izin mengirim pesan dulu",
+            Text::ChannelLinked => "Laporan akan dikirim ke {}",
+            Text::DraftApprove => "Setujui",
+            Text::DraftEdit => "Ubah",
+            Text::DraftSkip => "Lewati",
+            Text::DraftHandled => "Draf ini sudah ditangani",
+            Text::DraftNotAuthor => "Hanya penulis yang dapat meninjau draf ini",
+            Text::DraftEditPrompt => "Kirimkan laporan yang sudah direvisi, atau pesan yang diawali \"notes:\" untuk menambahkan catatan/hambatan",
+            Text::DraftWaiting => "Menunggu perubahan Anda",
+            Text::DraftSkipped => "Laporan dilewati",
+            Text::DraftDropped => "Bot dimulai ulang sebelum Anda meninjau laporan, laporan tidak diterbitkan",
+            Text::DraftNotes => "Catatan/hambatan:",
+            Text::StandupGroupOnly => "Kirim perintah ini di grup tim Anda",
+            Text::StandupJoined => "Anda bergabung dengan stand-up, saya akan mengirim pesan pribadi saat waktu stand-up",
+            Text::StandupAlreadyJoined => "Anda sudah ikut stand-up ini",
+            Text::StandupLeft => "Anda keluar dari stand-up",
+            Text::StandupNotMember => "Anda tidak ikut stand-up ini",
+            Text::StandupPrompt => "Waktunya stand-up! Apa yang akan Anda kerjakan hari ini?",
+            Text::StandupAskBlockers => "Ada hambatan? (balas \"tidak ada\" jika tidak)",
+            Text::StandupThanks => "Terima kasih! Jawaban Anda akan masuk ke stand-up hari ini",
+            Text::StandupHeading => "Stand-up {}",
+            Text::StandupDone => "Selesai:",
+            Text::StandupToday => "Hari ini:",
+            Text::StandupBlockers => "Hambatan:",
+            Text::StandupNoCommits => "- tidak ada commit atau merge request",
+            Text::StandupCommitsUnavailable => "- commit tidak tersedia",
+            Text::StandupNoToken => "- token Gitlab belum didaftarkan",
+            Text::StandupNoAnswer => "Tidak menjawab",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(input: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(input, "%Y-%m-%d").unwrap()
+    }
+
+    #[test]
+    fn reads_language_tags() {
+        assert_eq!(Lang::from_code("en"), Some(Lang::En));
+        assert_eq!(Lang::from_code("en-US"), Some(Lang::En));
+        assert_eq!(Lang::from_code("ID"), Some(Lang::Id));
+        assert_eq!(Lang::from_code("id_ID"), Some(Lang::Id));
+        assert_eq!(Lang::from_code("in"), Some(Lang::Id));
+        assert_eq!(Lang::from_code("fr"), None);
+        assert_eq!(Lang::from_code(""), None);
+    }
+
+    #[test]
+    fn formats_dates_in_english() {
+        assert_eq!(Lang::En.format_date(date("2023-07-02")), "Sunday, 2 July 2023");
+    }
+
+    #[test]
+    fn formats_dates_in_indonesian() {
+        assert_eq!(Lang::Id.format_date(date("2023-07-02")), "Minggu, 2 Juli 2023");
+        assert_eq!(Lang::Id.format_date(date("2024-01-15")), "Senin, 15 Januari 2024");
+        assert_eq!(Lang::Id.format_date(date("2023-08-17")), "Kamis, 17 Agustus 2023");
+        assert_eq!(Lang::Id.format_date(date("2023-12-01")), "Jumat, 1 Desember 2023");
+    }
+
+    #[test]
+    fn fills_placeholders_in_order() {
+        assert_eq!(Text::ReportRangeHeading.fmt(Lang::En, &["Monday", "Friday"]), "Report for Monday - Friday");
+        assert_eq!(Text::LangChanged.fmt(Lang::Id, &["Bahasa Indonesia"]), "Mulai sekarang saya akan memakai Bahasa Indonesia");
+    }
+
+    #[test]
+    fn leaves_missing_arguments_empty_and_ignores_extra_ones() {
+        assert_eq!(Text::ReportRangeHeading.fmt(Lang::En, &["Monday"]), "Report for Monday - ");
+        assert_eq!(Text::ReportHeading.fmt(Lang::En, &["Monday", "Friday"]), "Report for Monday");
+        assert_eq!(Text::NotUnderstood.fmt(Lang::En, &["a"]), Text::NotUnderstood.get(Lang::En));
+    }
+}
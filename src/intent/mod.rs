@@ -0,0 +1,383 @@
+use chrono::{Datelike, Duration, NaiveDate};
+use std::error::Error;
+
+use crate::gitlab::GitlabUser;
+use crate::report::DateRange;
+
+// words that make a question about what was done in the repositories, "fix" is left out
+// as it more often asks how to fix something
+const HISTORY_WORDS: [&str; 14] = [
+    "change", "changed", "changes", "commit", "commits", "committed", "push", "pushed", "merge",
+    "merged", "ubah", "mengubah", "kerjakan", "dikerjakan",
+];
+
+// beginnings of questions that continue the previous one
+const FOLLOW_UP_STARTS: [&str; 10] = [
+    "and", "what about", "how about", "also", "then", "dan", "bagaimana dengan", "kalau", "lalu", "terus",
+];
+
+// capitalised words after "about" that are a time, not a person
+const TIME_WORDS: [&str; 8] = ["yesterday", "today", "last", "this", "kemarin", "hari", "minggu", "bulan"];
+
+// words that can follow "did" or "by" without being a person
+const NOT_PEOPLE: [&str; 14] = [
+    "i", "you", "we", "they", "he", "she", "the", "anyone", "someone", "anybody", "somebody", "saya",
+    "kami", "kita",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommitQuery {
+    // author name as written in the question
+    pub person: Option<String>,
+    pub range: DateRange,
+    // repositories the question named, remembered for follow-ups
+    pub projects: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Intent {
+    // a question about commits, e.g. "what did Budi change in the payment service last week?"
+    CommitHistory(CommitQuery),
+    // anything else is answered from the knowledge base
+    General,
+}
+
+/// decides whether a question is about the commit history
+pub fn detect(question: &str, today: NaiveDate) -> Intent {
+    let lower = question.to_lowercase();
+    let words: Vec<&str> = lower
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect();
+
+    if !words.iter().any(|word| HISTORY_WORDS.contains(word)) {
+        return Intent::General;
+    }
+
+    Intent::CommitHistory(CommitQuery {
+        person: person(question),
+        range: time_range(&lower, today).unwrap_or_else(|| DateRange {
+            from: today - Duration::days(6),
+            to: today,
+        }),
+        projects: Vec::new(),
+    })
+}
+
+/// whether the question continues the previous one, e.g. "and what about yesterday?"
+pub fn is_follow_up(question: &str) -> bool {
+    let lower = question.trim().to_lowercase();
+    let starts_with = |start: &str| {
+        lower == start || lower.starts_with(&format!("{} ", start)) || lower.starts_with(&format!("{},", start))
+    };
+
+    FOLLOW_UP_STARTS.iter().any(|start| starts_with(start))
+}
+
+/// the previous query with whatever the follow-up changes, None if the question is not a follow-up
+pub fn follow_up(question: &str, today: NaiveDate, previous: &CommitQuery) -> Option<CommitQuery> {
+    if !is_follow_up(question) {
+        return None;
+    }
+
+    let mut query = previous.clone();
+    if let Some(range) = time_range(&question.to_lowercase(), today) {
+        query.range = range;
+    }
+    if let Some(person) = person(question).or_else(|| named_person(question)) {
+        query.person = Some(person);
+    }
+
+    Some(query)
+}
+
+/// a capitalised name after "about", "for", "dengan" or "untuk", as in "what about Andi?"
+fn named_person(question: &str) -> Option<String> {
+    let words: Vec<&str> = question
+        .split(|c: char| !c.is_alphanumeric() && c != '.' && c != '-')
+        .filter(|word| !word.is_empty())
+        .collect();
+
+    words.windows(2).find_map(|pair| {
+        let trigger = pair[0].to_lowercase();
+        let name = pair[1].trim_end_matches('.');
+        let is_trigger = ["about", "for", "dengan", "untuk"].contains(&trigger.as_str());
+        let is_name = name.chars().next().is_some_and(char::is_uppercase)
+            && !TIME_WORDS.contains(&name.to_lowercase().as_str());
+        if is_trigger && is_name {
+            Some(name.to_string())
+        } else {
+            None
+        }
+    })
+}
+
+/// the name following "did", "by" or "oleh", keeping the case it was written in
+///
+/// "by" is also used for other things ("sorted by date"), so it only introduces a
+/// capitalised name or an `@username`
+fn person(question: &str) -> Option<String> {
+    let words: Vec<&str> = question
+        .split(|c: char| !c.is_alphanumeric() && c != '.' && c != '-' && c != '@')
+        .filter(|word| !word.is_empty())
+        .collect();
+
+    words.windows(2).find_map(|pair| {
+        let trigger = pair[0].to_lowercase();
+        let is_handle = pair[1].starts_with('@');
+        let name = pair[1].trim_start_matches('@').trim_end_matches('.');
+        let is_trigger = match trigger.as_str() {
+            "did" | "oleh" => true,
+            "by" => is_handle || name.chars().next().is_some_and(char::is_uppercase),
+            _ => false,
+        };
+        if is_trigger && !NOT_PEOPLE.contains(&name.to_lowercase().as_str()) && !name.is_empty() {
+            Some(name.to_string())
+        } else {
+            None
+        }
+    })
+}
+
+/// understands the usual ways to name a period in English and Indonesian
+fn time_range(lower: &str, today: NaiveDate) -> Option<DateRange> {
+    let days_ago = |days: i64| today - Duration::days(days);
+    let start_of_week = days_ago(today.weekday().num_days_from_monday() as i64);
+
+    if lower.contains("last week") || lower.contains("minggu lalu") {
+        return Some(DateRange {
+            from: start_of_week - Duration::days(7),
+            to: start_of_week - Duration::days(1),
+        });
+    }
+    if lower.contains("this week") || lower.contains("minggu ini") {
+        return Some(DateRange {
+            from: start_of_week,
+            to: today,
+        });
+    }
+    if lower.contains("yesterday") || lower.contains("kemarin") {
+        return Some(DateRange::day(days_ago(1)));
+    }
+    if lower.contains("today") || lower.contains("hari ini") {
+        return Some(DateRange::day(today));
+    }
+    if lower.contains("last month") || lower.contains("bulan lalu") {
+        let first_of_month = today.with_day(1)?;
+        let last_month_end = first_of_month - Duration::days(1);
+        return Some(DateRange {
+            from: last_month_end.with_day(1)?,
+            to: last_month_end,
+        });
+    }
+
+    // "last 3 days" / "3 hari terakhir"
+    let words: Vec<&str> = lower.split_whitespace().collect();
+    for (index, word) in words.iter().enumerate() {
+        if let Ok(days) = word.parse::<i64>() {
+            let unit = words.get(index + 1).copied().unwrap_or("");
+            if unit.starts_with("day") || unit == "hari" {
+                return Some(DateRange {
+                    from: days_ago(days.max(1) - 1),
+                    to: today,
+                });
+            }
+        }
+        if let Ok(date) = NaiveDate::parse_from_str(word.trim_matches(|c: char| !c.is_ascii_digit()), "%Y-%m-%d") {
+            return Some(DateRange::day(date));
+        }
+    }
+
+    None
+}
+
+/// whether the question mentions the repository, "payment service" matches `payment-service`
+pub fn mentions_project(question: &str, repository: &str) -> bool {
+    let normalize = |text: &str| {
+        text.to_lowercase()
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+
+    let name = normalize(repository);
+    !name.is_empty() && format!(" {} ", normalize(question)).contains(&format!(" {} ", name))
+}
+
+/// one commit or merge request matching a history question
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    // a sentence describing the change, read by the QA model
+    pub text: String,
+    pub url: Option<String>,
+}
+
+/// collects the commits and merge requests the question is about, from the repositories
+/// it mentions, those of the previous question, or every repository otherwise
+///
+/// the repositories the question names are stored in `query.projects`
+pub async fn find_history(
+    user: &GitlabUser,
+    question: &str,
+    query: &mut CommitQuery,
+) -> Result<Vec<HistoryEntry>, Box<dyn Error + Send + Sync>> {
+    let repositories = user.get_repositories().await?;
+    let mentioned: Vec<String> = repositories
+        .iter()
+        .filter(|repo| mentions_project(question, &repo.name))
+        .map(|repo| repo.name.clone())
+        .collect();
+    if !mentioned.is_empty() {
+        query.projects = mentioned;
+    }
+    let repositories: Vec<_> = repositories
+        .iter()
+        .filter(|repo| query.projects.is_empty() || query.projects.contains(&repo.name))
+        .collect();
+
+    let by_person = |name: &str| match &query.person {
+        Some(person) => name.to_lowercase().contains(&person.to_lowercase()),
+        None => true,
+    };
+
+    let mut entries = Vec::new();
+    for repo in repositories {
+        let commits = user
+            .get_commits_between(repo.id, query.range.since(), query.range.until(), None)
+            .await?;
+        for commit in commits.into_iter().filter(|commit| by_person(&commit.author_name)) {
+            let date = commit
+                .created_at
+                .map(|date| date.format(" on %Y-%m-%d").to_string())
+                .unwrap_or_default();
+            entries.push(HistoryEntry {
+                text: format!("{} changed {}{}: {}.", commit.author_name, repo.name, date, commit.title),
+                url: commit.web_url,
+            });
+        }
+
+        let merge_requests = user
+            .get_merge_requests(repo.id, query.range.since(), query.range.until(), None)
+            .await?;
+        for merge_request in merge_requests
+            .into_iter()
+            .filter(|mr| by_person(&mr.author.name) || by_person(&mr.author.username))
+        {
+            let mut text = format!(
+                "{} opened merge request !{} in {} ({}): {}.",
+                merge_request.author.name, merge_request.iid, repo.name, merge_request.state, merge_request.title
+            );
+            // the first line of the description usually says what the change is for
+            if let Some(summary) = merge_request.description.as_deref().and_then(|text| text.lines().next()) {
+                if !summary.trim().is_empty() {
+                    text.push_str(&format!(" {}", summary.trim()));
+                }
+            }
+            entries.push(HistoryEntry {
+                text,
+                url: Some(merge_request.web_url),
+            });
+        }
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn today() -> NaiveDate {
+        // a Wednesday
+        NaiveDate::from_ymd_opt(2023, 7, 12).unwrap()
+    }
+
+    fn query(question: &str) -> CommitQuery {
+        match detect(question, today()) {
+            Intent::CommitHistory(query) => query,
+            Intent::General => panic!("`{}` is not a history question", question),
+        }
+    }
+
+    #[test]
+    fn history_questions_need_a_history_word() {
+        assert_eq!(detect("how do I deploy the payment service?", today()), Intent::General);
+        assert_eq!(detect("how do I fix the failing build?", today()), Intent::General);
+        assert_eq!(detect("what was fixed yesterday?", today()), Intent::General);
+        assert!(matches!(detect("what changed yesterday?", today()), Intent::CommitHistory(_)));
+        assert!(matches!(detect("apa yang dikerjakan kemarin?", today()), Intent::CommitHistory(_)));
+    }
+
+    #[test]
+    fn finds_the_person() {
+        assert_eq!(query("what did Budi change last week?").person.as_deref(), Some("Budi"));
+        assert_eq!(query("what did budi change?").person.as_deref(), Some("budi"));
+        assert_eq!(query("which commits were pushed by Andi?").person.as_deref(), Some("Andi"));
+        assert_eq!(query("commits pushed by @andi.s today").person.as_deref(), Some("andi.s"));
+        assert_eq!(query("apa yang dikerjakan oleh Sari?").person.as_deref(), Some("Sari"));
+    }
+
+    #[test]
+    fn common_phrases_are_not_people() {
+        assert_eq!(query("what has been merged this week?").person, None);
+        assert_eq!(query("list the commits sorted by date").person, None);
+        assert_eq!(query("what did the team change?").person, None);
+        assert_eq!(query("what did we merge yesterday?").person, None);
+        assert_eq!(query("what was merged by The end of the sprint?").person, None);
+    }
+
+    #[test]
+    fn reads_the_period() {
+        let today = today();
+
+        assert_eq!(query("what changed yesterday?").range, DateRange::day(today - Duration::days(1)));
+        assert_eq!(
+            query("what changed last week?").range,
+            DateRange {
+                from: NaiveDate::from_ymd_opt(2023, 7, 3).unwrap(),
+                to: NaiveDate::from_ymd_opt(2023, 7, 9).unwrap(),
+            }
+        );
+        assert_eq!(
+            query("commits of the last 3 days").range,
+            DateRange {
+                from: today - Duration::days(2),
+                to: today,
+            }
+        );
+        assert_eq!(
+            query("what was pushed on 2023-07-01?").range,
+            DateRange::day(NaiveDate::from_ymd_opt(2023, 7, 1).unwrap())
+        );
+        // a week up to today without a period
+        assert_eq!(query("what changed?").range.from, today - Duration::days(6));
+    }
+
+    #[test]
+    fn follow_ups_keep_the_previous_query() {
+        let previous = CommitQuery {
+            person: Some("Budi".to_string()),
+            range: DateRange::day(today()),
+            projects: vec!["payment-service".to_string()],
+        };
+
+        let query = follow_up("and what about yesterday?", today(), &previous).unwrap();
+        assert_eq!(query.person.as_deref(), Some("Budi"));
+        assert_eq!(query.range, DateRange::day(today() - Duration::days(1)));
+        assert_eq!(query.projects, previous.projects);
+
+        let query = follow_up("what about Andi?", today(), &previous).unwrap();
+        assert_eq!(query.person.as_deref(), Some("Andi"));
+        assert_eq!(query.range, previous.range);
+
+        assert!(follow_up("who owns the payment service?", today(), &previous).is_none());
+    }
+
+    #[test]
+    fn matches_project_names() {
+        assert!(mentions_project("what changed in the payment service?", "payment-service"));
+        assert!(mentions_project("commits to payment_service", "payment-service"));
+        assert!(!mentions_project("what changed in payments?", "payment-service"));
+    }
+}
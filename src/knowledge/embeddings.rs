@@ -0,0 +1,254 @@
+use rust_bert::pipelines::sentence_embeddings::{
+    Embedding, SentenceEmbeddingsBuilder, SentenceEmbeddingsModel,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, RwLock};
+
+use super::Chunk;
+
+const DEFAULT_INDEX_PATH: &str = "embeddings.json";
+// chunks embedded per forward pass
+const BATCH_SIZE: usize = 32;
+
+#[derive(Default, Serialize, Deserialize)]
+struct StoredIndex {
+    // model the vectors were computed with, the index is rebuilt when it changes
+    model: String,
+    vectors: HashMap<String, Embedding>,
+}
+
+/// sentence embeddings of the knowledge base chunks, persisted to disk so
+/// unchanged chunks are not embedded again after a restart
+pub struct Embeddings {
+    model_dir: PathBuf,
+    index_path: PathBuf,
+    model: Mutex<Option<SentenceEmbeddingsModel>>,
+    // set once the model is loaded, read without waiting for the model lock
+    ready: AtomicBool,
+    vectors: RwLock<HashMap<String, Embedding>>,
+}
+
+impl Embeddings {
+    /// reads `EMBEDDINGS_MODEL_DIR` (a local sentence-transformers model) and
+    /// `EMBEDDINGS_INDEX_PATH`, returns None when semantic retrieval is not configured
+    pub fn from_env() -> Option<Embeddings> {
+        let model_dir = PathBuf::from(env::var("EMBEDDINGS_MODEL_DIR").ok()?);
+        let index_path =
+            PathBuf::from(env::var("EMBEDDINGS_INDEX_PATH").unwrap_or_else(|_| DEFAULT_INDEX_PATH.to_string()));
+
+        let model_name = model_dir.display().to_string();
+        let vectors = match fs::read_to_string(&index_path) {
+            Ok(content) => match serde_json::from_str::<StoredIndex>(&content) {
+                Ok(stored) if stored.model == model_name => stored.vectors,
+                Ok(_) => {
+                    log::info!("Embedding model changed, rebuilding {}", index_path.display());
+                    HashMap::new()
+                }
+                Err(err) => {
+                    log::warn!("ignoring unreadable {}: {}", index_path.display(), err);
+                    HashMap::new()
+                }
+            },
+            Err(_) => HashMap::new(),
+        };
+
+        Some(Embeddings {
+            model_dir,
+            index_path,
+            model: Mutex::new(None),
+            ready: AtomicBool::new(false),
+            vectors: RwLock::new(vectors),
+        })
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Acquire)
+    }
+
+    /// loads the model, blocks for as long as that takes
+    pub fn load_model(&self) {
+        if self.is_ready() {
+            return;
+        }
+
+        log::info!("Loading sentence embeddings model from {}", self.model_dir.display());
+        match SentenceEmbeddingsBuilder::local(self.model_dir.clone()).create_model() {
+            Ok(model) => {
+                log::info!("Sentence embeddings model loaded");
+                *self.model.lock().unwrap() = Some(model);
+                self.ready.store(true, Ordering::Release);
+            }
+            Err(err) => log::error!("failed to load sentence embeddings model: {}", err),
+        }
+    }
+
+    /// embeds chunks that are not in the index yet, forgets chunks that are gone
+    /// and saves the index when it changed, blocks until done
+    pub fn update<'a>(&self, chunks: impl Iterator<Item = &'a Chunk>) {
+        if !self.is_ready() {
+            return;
+        }
+
+        let chunks: Vec<(String, &Chunk)> = chunks.map(|chunk| (chunk_key(chunk), chunk)).collect();
+        let keys: HashSet<&String> = chunks.iter().map(|(key, _)| key).collect();
+        let missing: Vec<&(String, &Chunk)> = {
+            let vectors = self.vectors.read().unwrap();
+            chunks.iter().filter(|(key, _)| !vectors.contains_key(key)).collect()
+        };
+
+        let mut changed = false;
+        for batch in missing.chunks(BATCH_SIZE) {
+            let texts: Vec<&str> = batch.iter().map(|(_, chunk)| chunk.text.as_str()).collect();
+            // locked per batch so questions are not kept waiting for the whole pass
+            let encoded = match self.model.lock().unwrap().as_ref() {
+                Some(model) => model.encode(&texts),
+                None => return,
+            };
+            match encoded {
+                Ok(embeddings) => {
+                    let mut vectors = self.vectors.write().unwrap();
+                    for ((key, _), embedding) in batch.iter().zip(embeddings) {
+                        vectors.insert(key.clone(), embedding);
+                    }
+                    changed = true;
+                }
+                Err(err) => log::error!("failed to embed knowledge chunks: {}", err),
+            }
+        }
+
+        {
+            let mut vectors = self.vectors.write().unwrap();
+            let before = vectors.len();
+            vectors.retain(|key, _| keys.contains(key));
+            changed |= vectors.len() != before;
+        }
+
+        if changed {
+            self.save();
+        }
+    }
+
+    fn save(&self) {
+        let stored = StoredIndex {
+            model: self.model_dir.display().to_string(),
+            vectors: self.vectors.read().unwrap().clone(),
+        };
+
+        let result = serde_json::to_string(&stored)
+            .map_err(|err| err.to_string())
+            .and_then(|content| fs::write(&self.index_path, content).map_err(|err| err.to_string()));
+        if let Err(err) = result {
+            log::error!("failed to save {}: {}", self.index_path.display(), err);
+        }
+    }
+
+    /// returns the `top_k` chunks closest to the question by cosine similarity,
+    /// None while the model is not loaded, blocks while a batch is being embedded
+    pub fn search<'a>(
+        &self,
+        question: &str,
+        chunks: impl Iterator<Item = &'a Chunk>,
+        top_k: usize,
+    ) -> Option<Vec<Chunk>> {
+        let query = {
+            let model = self.model.lock().unwrap();
+            match model.as_ref()?.encode(&[question]) {
+                Ok(mut embeddings) => embeddings.pop()?,
+                Err(err) => {
+                    log::error!("failed to embed question: {}", err);
+                    return None;
+                }
+            }
+        };
+
+        let vectors = self.vectors.read().unwrap();
+        let mut scored: Vec<(f32, &Chunk)> = chunks
+            .filter_map(|chunk| {
+                vectors
+                    .get(&chunk_key(chunk))
+                    .map(|vector| (cosine_similarity(&query, vector), chunk))
+            })
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        Some(
+            scored
+                .into_iter()
+                .take(top_k)
+                .map(|(_, chunk)| chunk.clone())
+                .collect(),
+        )
+    }
+}
+
+// FNV-1a of the source and text, stable across builds unlike the std hasher
+fn chunk_key(chunk: &Chunk) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in chunk.source.bytes().chain([0]).chain(chunk.text.bytes()) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    format!("{:016x}", hash)
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a: f32 = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b: f32 = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+
+    if norm_a == 0.0 || norm_b == 0.0 {
+        0.0
+    } else {
+        dot / (norm_a * norm_b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() < 1e-6, "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn cosine_similarity_of_vectors() {
+        assert_close(cosine_similarity(&[1.0, 2.0, 3.0], &[2.0, 4.0, 6.0]), 1.0);
+        assert_close(cosine_similarity(&[1.0, 0.0], &[0.0, 1.0]), 0.0);
+        assert_close(cosine_similarity(&[1.0, -2.0], &[-1.0, 2.0]), -1.0);
+    }
+
+    #[test]
+    fn zero_vectors_are_not_similar() {
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 2.0]), 0.0);
+        assert_eq!(cosine_similarity(&[1.0, 2.0], &[0.0, 0.0]), 0.0);
+    }
+
+    #[test]
+    fn chunk_keys_are_stable() {
+        // the keys name cached embeddings on disk, they must not change between runs
+        let chunk = Chunk::new("a.md", None, "hi".to_string());
+        assert_eq!(chunk_key(&chunk), "793f10fb87d705b2");
+    }
+
+    #[test]
+    fn chunk_keys_depend_on_the_source() {
+        let text = "deploy on fridays".to_string();
+
+        assert_ne!(
+            chunk_key(&Chunk::new("a.md", None, text.clone())),
+            chunk_key(&Chunk::new("b.md", None, text))
+        );
+        // the separator keeps the source and text apart
+        assert_ne!(
+            chunk_key(&Chunk::new("ab", None, "c".to_string())),
+            chunk_key(&Chunk::new("a", None, "bc".to_string()))
+        );
+    }
+}
@@ -0,0 +1,627 @@
+use chrono::{Duration as ChronoDuration, Utc};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+use teloxide::types::UserId;
+
+use crate::context;
+use crate::gitlab::GitlabUser;
+use crate::shutdown::Shutdown;
+
+mod embeddings;
+
+use embeddings::Embeddings;
+
+const DEFAULT_DIR: &str = "knowledge";
+// single file the bot used to answer from, still picked up when present
+const LEGACY_FILE: &str = "about_me.txt";
+const EXTENSIONS: [&str; 3] = ["md", "markdown", "txt"];
+// chunks are cut after roughly this many words
+const CHUNK_WORDS: usize = 120;
+const DEFAULT_TOP_K: usize = 3;
+const DEFAULT_RELOAD_SECS: u64 = 30;
+const DEFAULT_REPO_SYNC_SECS: u64 = 3600;
+// upper bound of documentation files fetched per repository
+const MAX_REPO_DOCS: usize = 50;
+// recent commits are part of the knowledge base too
+const DEFAULT_COMMIT_DAYS: i64 = 30;
+
+// BM25 parameters
+const K1: f64 = 1.2;
+const B: f64 = 0.75;
+
+const STOPWORDS: [&str; 40] = [
+    "the", "and", "for", "are", "was", "what", "who", "how", "when", "where", "which", "why", "with",
+    "this", "that", "does", "did", "you", "your", "is", "of", "to", "in", "on", "an", "it", "be", "do",
+    "apa", "yang", "dan", "di", "ke", "dari", "ini", "itu", "siapa", "bagaimana", "kapan", "dengan",
+];
+
+#[derive(Clone, Debug)]
+pub struct Chunk {
+    // file path or `group/repository/path` the chunk was taken from
+    pub source: String,
+    pub text: String,
+    // the repository the chunk was taken from, None for local files every user may read
+    repository: Option<u32>,
+    terms: HashMap<String, usize>,
+    length: usize,
+}
+
+impl Chunk {
+    pub fn new(source: &str, repository: Option<u32>, text: String) -> Chunk {
+        let mut terms = HashMap::new();
+        let mut length = 0;
+        for term in tokenize(&text) {
+            *terms.entry(term).or_insert(0) += 1;
+            length += 1;
+        }
+
+        Chunk {
+            source: source.to_string(),
+            text,
+            repository,
+            terms,
+            length,
+        }
+    }
+}
+
+struct Document {
+    modified: SystemTime,
+    chunks: Vec<Chunk>,
+}
+
+#[derive(Default)]
+struct Index {
+    // local files with the modification time they were parsed at
+    files: HashMap<PathBuf, Document>,
+    // README, docs and recent commits of tracked repositories, keyed by source
+    repo_docs: HashMap<String, Vec<Chunk>>,
+    // the repositories each user could list at the last sync
+    access: HashMap<UserId, HashSet<u32>>,
+}
+
+impl Index {
+    fn chunks(&self) -> impl Iterator<Item = &Chunk> {
+        self.files
+            .values()
+            .flat_map(|document| document.chunks.iter())
+            .chain(self.repo_docs.values().flatten())
+    }
+
+    /// the chunks `user` may read, local files and the repositories they are a member of
+    fn chunks_for(&self, user: UserId) -> impl Iterator<Item = &Chunk> {
+        let access = self.access.get(&user);
+        self.chunks().filter(move |chunk| match chunk.repository {
+            Some(repository) => access.is_some_and(|access| access.contains(&repository)),
+            None => true,
+        })
+    }
+}
+
+/// documents the bot answers questions from, split into chunks so the QA model
+/// only reads the parts relevant to a question
+#[derive(Clone)]
+pub struct KnowledgeBase {
+    index: Arc<RwLock<Index>>,
+    // semantic retrieval, keyword matching is used when it is not configured or not loaded yet
+    embeddings: Option<Arc<Embeddings>>,
+    dir: PathBuf,
+    top_k: usize,
+}
+
+impl KnowledgeBase {
+    /// reads `KNOWLEDGE_DIR` (default `knowledge`) and `KNOWLEDGE_TOP_K`
+    pub fn from_env() -> KnowledgeBase {
+        let knowledge = KnowledgeBase {
+            index: Arc::new(RwLock::new(Index::default())),
+            embeddings: Embeddings::from_env().map(Arc::new),
+            dir: PathBuf::from(env::var("KNOWLEDGE_DIR").unwrap_or_else(|_| DEFAULT_DIR.to_string())),
+            top_k: env::var("KNOWLEDGE_TOP_K")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(DEFAULT_TOP_K),
+        };
+        knowledge.reload_files();
+
+        knowledge
+    }
+
+    /// re-parses files that changed since the last call and forgets deleted ones
+    pub fn reload_files(&self) {
+        let mut paths = Vec::new();
+        collect_files(&self.dir, &mut paths);
+        let legacy = PathBuf::from(LEGACY_FILE);
+        if legacy.is_file() {
+            paths.push(legacy);
+        }
+
+        let mut seen = HashSet::new();
+        for path in paths {
+            let modified = match fs::metadata(&path).and_then(|metadata| metadata.modified()) {
+                Ok(modified) => modified,
+                Err(err) => {
+                    log::warn!("failed to read {}: {}", path.display(), err);
+                    continue;
+                }
+            };
+            seen.insert(path.clone());
+
+            let unchanged = self
+                .index
+                .read()
+                .unwrap()
+                .files
+                .get(&path)
+                .is_some_and(|document| document.modified == modified);
+            if unchanged {
+                continue;
+            }
+
+            match fs::read_to_string(&path) {
+                Ok(content) => {
+                    log::info!("Loaded knowledge from {}", path.display());
+                    let chunks = chunk_text(&path.display().to_string(), None, &content);
+                    self.index
+                        .write()
+                        .unwrap()
+                        .files
+                        .insert(path, Document { modified, chunks });
+                }
+                Err(err) => log::warn!("failed to read {}: {}", path.display(), err),
+            }
+        }
+
+        self.index
+            .write()
+            .unwrap()
+            .files
+            .retain(|path, _| seen.contains(path));
+    }
+
+    /// fetches the README, `docs/` and recent commits of every repository the registered users can access,
+    /// and which of them each user may answer questions from; a user whose repositories cannot be listed
+    /// only reads local files until the next sync
+    pub async fn sync_repositories(&self, users: Vec<(UserId, GitlabUser)>) {
+        let mut synced = HashSet::new();
+        let mut repo_docs = HashMap::new();
+        let mut access: HashMap<UserId, HashSet<u32>> = HashMap::new();
+        let commit_days = env::var("KNOWLEDGE_COMMIT_DAYS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_COMMIT_DAYS);
+        let since = Utc::now() - ChronoDuration::days(commit_days);
+
+        for (user_id, user) in users {
+            let repositories = match user.get_repositories().await {
+                Ok(repositories) => repositories,
+                Err(err) => {
+                    log::warn!("failed to list repositories for the knowledge base: {}", err);
+                    continue;
+                }
+            };
+
+            for repo in repositories {
+                access.entry(user_id).or_default().insert(repo.id);
+                if !synced.insert(repo.id) {
+                    continue;
+                }
+                // names are only unique within a group
+                let name = repo.path_with_namespace.clone().unwrap_or_else(|| repo.name.clone());
+
+                let mut paths = vec!["README.md".to_string()];
+                match user.get_tree(repo.id, "docs").await {
+                    Ok(entries) => paths.extend(
+                        entries
+                            .into_iter()
+                            .filter(|entry| entry.kind == "blob" && has_known_extension(Path::new(&entry.path)))
+                            .map(|entry| entry.path)
+                            .take(MAX_REPO_DOCS),
+                    ),
+                    Err(err) => log::warn!("failed to list docs of {}: {}", name, err),
+                }
+
+                for path in paths {
+                    match user.get_file(repo.id, &path).await {
+                        Ok(Some(content)) => {
+                            let source = format!("{}/{}", name, path);
+                            let chunks = chunk_text(&source, Some(repo.id), &content);
+                            repo_docs.insert(source, chunks);
+                        }
+                        Ok(None) => {}
+                        Err(err) => log::warn!("failed to fetch {}/{}: {}", name, path, err),
+                    }
+                }
+
+                match user.get_commits_between(repo.id, since, Utc::now(), None).await {
+                    Ok(commits) => {
+                        for commit in commits {
+                            let source = format!("{}@{}", name, commit.short_id);
+                            let date = commit
+                                .created_at
+                                .map(|date| date.format("%Y-%m-%d").to_string())
+                                .unwrap_or_default();
+                            let text = format!(
+                                "{} committed to {} on {}: {}",
+                                commit.author_name, repo.name, date, commit.title
+                            );
+                            repo_docs.insert(source.clone(), vec![Chunk::new(&source, Some(repo.id), text)]);
+                        }
+                    }
+                    Err(err) => log::warn!("failed to fetch commits of {}: {}", name, err),
+                }
+            }
+        }
+
+        log::info!("Synced {} repository documents into the knowledge base", repo_docs.len());
+        let mut index = self.index.write().unwrap();
+        index.repo_docs = repo_docs;
+        index.access = access;
+    }
+
+    /// returns the chunks `user` may read that are most relevant to the question, best first,
+    /// by meaning when sentence embeddings are available and by keywords otherwise
+    pub async fn retrieve(&self, question: &str, user: UserId) -> Vec<Chunk> {
+        // the readiness flag never blocks, the search waits for the model off the runtime
+        if let Some(embeddings) = self.embeddings.clone().filter(|embeddings| embeddings.is_ready()) {
+            let knowledge = self.clone();
+            let question_owned = question.to_string();
+            let found = tokio::task::spawn_blocking(move || {
+                let chunks: Vec<Chunk> = knowledge.index.read().unwrap().chunks_for(user).cloned().collect();
+                embeddings.search(&question_owned, chunks.iter(), knowledge.top_k)
+            })
+            .await;
+
+            match found {
+                Ok(Some(chunks)) if !chunks.is_empty() => return chunks,
+                Ok(_) => {}
+                Err(err) => log::error!("semantic retrieval failed: {}", err),
+            }
+        }
+
+        self.keyword_search(question, user)
+    }
+
+    /// ranks the chunks `user` may read against the question with BM25
+    fn keyword_search(&self, question: &str, user: UserId) -> Vec<Chunk> {
+        let query: HashSet<String> = tokenize(question).collect();
+        if query.is_empty() {
+            return Vec::new();
+        }
+
+        let index = self.index.read().unwrap();
+        let chunks: Vec<&Chunk> = index.chunks_for(user).collect();
+        if chunks.is_empty() {
+            return Vec::new();
+        }
+
+        let count = chunks.len() as f64;
+        let average_length = chunks.iter().map(|chunk| chunk.length).sum::<usize>() as f64 / count;
+        let idf: HashMap<&String, f64> = query
+            .iter()
+            .map(|term| {
+                let frequency = chunks.iter().filter(|chunk| chunk.terms.contains_key(term)).count() as f64;
+                (term, ((count - frequency + 0.5) / (frequency + 0.5) + 1.0).ln())
+            })
+            .collect();
+
+        let mut scored: Vec<(f64, &Chunk)> = chunks
+            .into_iter()
+            .map(|chunk| {
+                let score = query
+                    .iter()
+                    .map(|term| {
+                        let frequency = *chunk.terms.get(term).unwrap_or(&0) as f64;
+                        let norm = K1 * (1.0 - B + B * chunk.length as f64 / average_length.max(1.0));
+                        idf[term] * frequency * (K1 + 1.0) / (frequency + norm)
+                    })
+                    .sum();
+                (score, chunk)
+            })
+            .filter(|(score, _)| *score > 0.0)
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        scored
+            .into_iter()
+            .take(self.top_k)
+            .map(|(_, chunk)| chunk.clone())
+            .collect()
+    }
+
+    /// keeps the knowledge base up to date: local files are checked every
+    /// `KNOWLEDGE_RELOAD_SECS`, repositories synced every `KNOWLEDGE_REPO_SYNC_SECS`,
+    /// until shutdown
+    pub async fn watch(self, ctxt: Arc<RwLock<context::Context>>, shutdown: Shutdown) {
+        let reload = Duration::from_secs(seconds_from_env("KNOWLEDGE_RELOAD_SECS", DEFAULT_RELOAD_SECS));
+        let repo_sync = Duration::from_secs(seconds_from_env("KNOWLEDGE_REPO_SYNC_SECS", DEFAULT_REPO_SYNC_SECS));
+        let mut last_sync: Option<Instant> = None;
+
+        if let Some(embeddings) = self.embeddings.clone() {
+            if let Err(err) = tokio::task::spawn_blocking(move || embeddings.load_model()).await {
+                log::error!("failed to load the sentence embeddings model: {}", err);
+            }
+        }
+
+        loop {
+            let knowledge = self.clone();
+            if let Err(err) = tokio::task::spawn_blocking(move || knowledge.reload_files()).await {
+                log::error!("failed to reload the knowledge base: {}", err);
+            }
+
+            if last_sync.is_none_or(|last_sync| last_sync.elapsed() >= repo_sync) {
+                let users = ctxt
+                    .read()
+                    .unwrap()
+                    .users()
+                    .into_iter()
+                    .map(|(user_id, gitlab_user, _)| (user_id, gitlab_user))
+                    .collect();
+                self.sync_repositories(users).await;
+                last_sync = Some(Instant::now());
+            }
+
+            if let Some(embeddings) = self.embeddings.clone() {
+                let knowledge = self.clone();
+                let updated = tokio::task::spawn_blocking(move || {
+                    // copied so reloading the files does not wait for the embedding pass
+                    let chunks: Vec<Chunk> = knowledge.index.read().unwrap().chunks().cloned().collect();
+                    embeddings.update(chunks.iter());
+                })
+                .await;
+                if let Err(err) = updated {
+                    log::error!("failed to update the embedding index: {}", err);
+                }
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep(reload) => {}
+                _ = shutdown.wait() => break,
+            }
+        }
+        log::info!("Knowledge base updates stopped");
+    }
+}
+
+fn seconds_from_env(name: &str, default: u64) -> u64 {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+fn has_known_extension(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| EXTENSIONS.contains(&extension.to_lowercase().as_str()))
+}
+
+fn collect_files(dir: &Path, paths: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            collect_files(&path, paths);
+        } else if has_known_extension(&path) {
+            paths.push(path);
+        }
+    }
+}
+
+fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() > 1)
+        .map(|word| word.to_lowercase())
+        .filter(|word| !STOPWORDS.contains(&word.as_str()))
+}
+
+/// splits a text or Markdown document into chunks of about `CHUNK_WORDS` words,
+/// starting a new chunk at every heading
+fn chunk_text(source: &str, repository: Option<u32>, content: &str) -> Vec<Chunk> {
+    let mut chunks = Vec::new();
+    let mut current: Vec<&str> = Vec::new();
+    let mut in_code_block = false;
+
+    let mut flush = |current: &mut Vec<&str>| {
+        if !current.is_empty() {
+            chunks.push(Chunk::new(source, repository, current.join(" ")));
+            current.clear();
+        }
+    };
+
+    for line in content.lines() {
+        let line = line.trim();
+        if line.starts_with("```") {
+            in_code_block = !in_code_block;
+            continue;
+        }
+        if in_code_block {
+            continue;
+        }
+        if line.starts_with('#') {
+            flush(&mut current);
+        }
+
+        let text = line.trim_start_matches(|c: char| "#>*-+ ".contains(c));
+        for word in text.split_whitespace() {
+            current.push(word);
+            if current.len() >= CHUNK_WORDS && word.ends_with(['.', '!', '?']) {
+                flush(&mut current);
+            }
+        }
+        // paragraphs without sentence ends still need a cut
+        if current.len() >= CHUNK_WORDS * 2 {
+            flush(&mut current);
+        }
+    }
+    flush(&mut current);
+
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(count: usize, last: &str) -> String {
+        let mut words = vec!["word"; count - 1];
+        words.push(last);
+        words.join(" ")
+    }
+
+    /// a knowledge base holding `chunks`, local files when they have no repository
+    fn knowledge(chunks: Vec<Chunk>, access: &[(u64, &[u32])], top_k: usize) -> KnowledgeBase {
+        let mut index = Index::default();
+        for chunk in chunks {
+            match chunk.repository {
+                Some(_) => index.repo_docs.entry(chunk.source.clone()).or_default().push(chunk),
+                None => {
+                    let document = index.files.entry(PathBuf::from(&chunk.source)).or_insert(Document {
+                        modified: SystemTime::UNIX_EPOCH,
+                        chunks: Vec::new(),
+                    });
+                    document.chunks.push(chunk);
+                }
+            }
+        }
+        index.access = access
+            .iter()
+            .map(|(user, repositories)| (UserId(*user), repositories.iter().copied().collect()))
+            .collect();
+
+        KnowledgeBase {
+            index: Arc::new(RwLock::new(index)),
+            embeddings: None,
+            dir: PathBuf::new(),
+            top_k,
+        }
+    }
+
+    fn sources(chunks: Vec<Chunk>) -> Vec<String> {
+        chunks.into_iter().map(|chunk| chunk.source).collect()
+    }
+
+    #[test]
+    fn tokenizes_lowercase_words_without_stopwords() {
+        let terms: Vec<String> = tokenize("What is the Deploy-Process of x, 2FA? Apa itu CI").collect();
+
+        assert_eq!(terms, vec!["deploy", "process", "2fa", "ci"]);
+    }
+
+    #[test]
+    fn starts_a_chunk_at_every_heading() {
+        let chunks = chunk_text("guide.md", None, "# Setup\nInstall **Rust**.\n\n## Deploy\n- run `make`\n> then wait");
+
+        let texts: Vec<&str> = chunks.iter().map(|chunk| chunk.text.as_str()).collect();
+        assert_eq!(texts, vec!["Setup Install **Rust**.", "Deploy run `make` then wait"]);
+        assert!(chunks.iter().all(|chunk| chunk.source == "guide.md" && chunk.repository.is_none()));
+    }
+
+    #[test]
+    fn leaves_code_blocks_out() {
+        let chunks = chunk_text("guide.md", Some(7), "Build it:\n```\ncargo build --release\n```\nThen ship it.");
+
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].text, "Build it: Then ship it.");
+        assert_eq!(chunks[0].repository, Some(7));
+    }
+
+    #[test]
+    fn cuts_long_text_at_a_sentence_end() {
+        let content = format!("{} {}", words(CHUNK_WORDS + 5, "end."), words(10, "tail."));
+
+        let chunks = chunk_text("long.md", None, &content);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].text.split_whitespace().count(), CHUNK_WORDS + 5);
+        assert_eq!(chunks[1].text.split_whitespace().count(), 10);
+    }
+
+    #[test]
+    fn cuts_paragraphs_without_sentence_ends() {
+        let line = words(CHUNK_WORDS, "word");
+        let content = [line.as_str(); 3].join("\n");
+
+        let chunks = chunk_text("long.md", None, &content);
+        assert_eq!(
+            chunks.iter().map(|chunk| chunk.text.split_whitespace().count()).collect::<Vec<_>>(),
+            vec![CHUNK_WORDS * 2, CHUNK_WORDS]
+        );
+    }
+
+    #[test]
+    fn ranks_rare_terms_higher() {
+        let knowledge = knowledge(
+            vec![
+                Chunk::new("deploy.md", None, "deploy with docker compose".to_string()),
+                Chunk::new("release.md", None, "every release deploy goes through review".to_string()),
+                Chunk::new("notes.md", None, "deploy notes".to_string()),
+                Chunk::new("coffee.md", None, "the coffee machine is on the second floor".to_string()),
+            ],
+            &[],
+            3,
+        );
+
+        let found = sources(knowledge.keyword_search("how do we deploy with docker?", UserId(1)));
+        assert_eq!(found[0], "deploy.md");
+        assert_eq!(found.len(), 3);
+        assert!(!found.contains(&"coffee.md".to_string()));
+    }
+
+    #[test]
+    fn shorter_chunks_win_on_equal_matches() {
+        let knowledge = knowledge(
+            vec![
+                Chunk::new("long.md", None, "deploy steps and many other unrelated words here".to_string()),
+                Chunk::new("short.md", None, "deploy steps".to_string()),
+            ],
+            &[],
+            3,
+        );
+
+        assert_eq!(sources(knowledge.keyword_search("deploy", UserId(1))), vec!["short.md", "long.md"]);
+    }
+
+    #[test]
+    fn returns_at_most_top_k_chunks() {
+        let chunks = (0..5)
+            .map(|index| Chunk::new(&format!("{}.md", index), None, "deploy".to_string()))
+            .collect();
+
+        assert_eq!(knowledge(chunks, &[], 2).keyword_search("deploy", UserId(1)).len(), 2);
+    }
+
+    #[test]
+    fn stopwords_alone_find_nothing() {
+        let knowledge = knowledge(vec![Chunk::new("a.md", None, "what is this".to_string())], &[], 3);
+
+        assert!(knowledge.keyword_search("what is this?", UserId(1)).is_empty());
+    }
+
+    #[test]
+    fn answers_only_from_repositories_the_user_can_access() {
+        let knowledge = knowledge(
+            vec![
+                Chunk::new("team/api/README.md", Some(1), "deploy the api".to_string()),
+                Chunk::new("secret/payroll/README.md", Some(2), "deploy the payroll".to_string()),
+                Chunk::new("knowledge/faq.md", None, "deploy on fridays".to_string()),
+            ],
+            &[(10, &[1]), (20, &[1, 2])],
+            3,
+        );
+
+        let mut member = sources(knowledge.keyword_search("deploy", UserId(10)));
+        member.sort();
+        assert_eq!(member, vec!["knowledge/faq.md", "team/api/README.md"]);
+
+        assert_eq!(knowledge.keyword_search("deploy", UserId(20)).len(), 3);
+        // users the last sync did not see only read local files
+        assert_eq!(sources(knowledge.keyword_search("deploy", UserId(30))), vec!["knowledge/faq.md"]);
+    }
+}
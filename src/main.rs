@@ -1,26 +1,89 @@
-use std::{sync::{mpsc::{self}, Arc, RwLock}, thread, error::Error};
+use std::{sync::{Arc, RwLock}, thread};
 use actix_web::rt;
-use teloxide::{prelude::{self, Dispatcher, Bot}, dispatching::DefaultKey};
+use teloxide::prelude::Bot;
 
 
+mod auth;
 mod chatbot;
+mod classify;
 mod gitlab;
 mod context;
 mod controller;
 mod server;
-mod errors;
+mod shutdown;
+mod feedback;
+mod i18n;
+mod intent;
+mod draft;
+mod events;
+mod knowledge;
+mod metrics;
+mod notify;
+mod qa;
+mod report;
+mod scheduler;
+mod standup;
+mod summary;
+mod webhook;
 
 #[tokio::main]
 async fn main() {
     println!("Starting App...");
-    let (tx, rx) = mpsc::channel::<Dispatcher<Bot, Box<dyn Error + Send + Sync>, DefaultKey>>();
 
 
     let ctxt = Arc::new(RwLock::new(context::Context::new()));
 
+    dotenv::dotenv().ok();
+    pretty_env_logger::init();
+    // the dashboard shows the reports sent before a restart too
+    ctxt.write().unwrap().persist_deliveries(draft::deliveries_path());
+
+    // SIGINT and SIGTERM stop the bot, the server and the background tasks together
+    let shutdown = shutdown::Shutdown::new();
+    tokio::spawn(shutdown::listen(shutdown.clone()));
+
+    let bot_token = std::env::var("BOT_TOKEN").expect("BOT_TOKEN not found in the environment");
+    let bot = Bot::new(bot_token);
+    // the bot and the HTTP API write reports with the same models
+    let writer = report::ReportWriter::from_env();
+    let keys = auth::ApiKeys::from_env();
+    // the bot answers right away, the model becomes available once loaded
+    let qa = qa::Qa::from_env();
+    metrics::init();
+    // pushes, merge requests and pipelines, from gitlab webhooks or polling
+    let bus = events::EventBus::from_env();
+    let poller = tokio::spawn(events::poll(Arc::clone(&ctxt), bus.clone(), shutdown.clone()));
+    let notifier = notify::Notifier::from_env();
+    let (webhook, updates) = match webhook::WebhookConfig::from_env() {
+        Ok(Some(config)) => {
+            let (webhook, updates) = webhook::Webhook::new(config);
+            (Some(webhook), Some(updates))
+        }
+        Ok(None) => (None, None),
+        Err(err) => {
+            log::error!("invalid webhook configuration: {}", err);
+            shutdown.trigger();
+            exit(1, &keys, &writer);
+        }
+    };
+    let server_config = match server::ServerConfig::from_env() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            shutdown.trigger();
+            exit(1, &keys, &writer);
+        }
+    };
+
     let m_ctx = Arc::clone(&ctxt);
-    thread::spawn(move || {
-        let bot_future = chatbot::serve(tx, m_ctx);
+    let m_bot = bot.clone();
+    let m_writer = writer.clone();
+    let m_keys = keys.clone();
+    let m_qa = qa.clone();
+    let m_shutdown = shutdown.clone();
+    let webhook_config = webhook.as_ref().map(|webhook| webhook.config.clone());
+    let bot_thread = thread::spawn(move || {
+        let bot_future = chatbot::serve(m_shutdown, m_bot, m_ctx, m_writer, m_keys, m_qa, webhook_config.zip(updates));
 
         rt::System::new().block_on(bot_future)
     });
@@ -28,13 +91,56 @@ async fn main() {
     // start the bot
     println!("Running server...");
 
+    // 0 once everything drained, 1 when something failed, 2 when the drain timed out
+    let mut status = 0;
+    let server = server::warp_server(
+        ctxt,
+        bot,
+        writer.clone(),
+        keys.clone(),
+        qa,
+        bus,
+        notifier,
+        webhook,
+        server_config,
+        shutdown.clone(),
+    );
+    match server.await {
+        Ok(()) => println!("The server http stop"),
+        Err(err) => {
+            eprintln!("{}", err);
+            status = 1;
+        }
+    }
+    // the server also stops on errors, the bot must not outlive it
+    shutdown.trigger();
 
-    server::warp_server(ctxt).await;
+    match tokio::task::spawn_blocking(move || bot_thread.join()).await {
+        Ok(Ok(true)) => {}
+        Ok(Ok(false)) => status = status.max(2),
+        _ => {
+            eprintln!("The bot stopped with a panic");
+            status = 1;
+        }
+    }
 
-    println!("The server http stop");
+    // a poll that started finishes its gitlab requests
+    if tokio::time::timeout(shutdown::drain_timeout(), poller).await.is_err() {
+        log::warn!("event polling did not finish within the shutdown timeout");
+        status = status.max(2);
+    }
 
-    let _ = rx.recv().unwrap().shutdown_token().shutdown();
+    exit(status, &keys, &writer);
+}
 
-    println!("System stopped");
+/// saves what outlives the process and exits, the API keys, the classifier's examples and
+/// the deliveries are kept while the rest of the context (subscriptions, stand-up answers,
+/// summary settings, languages, channel links) only lives in memory and starts empty next time
+fn exit(status: i32, keys: &auth::ApiKeys, writer: &report::ReportWriter) -> ! {
+    keys.flush();
+    writer.classifier.flush();
+    log::logger().flush();
 
+    println!("System stopped with status {}", status);
+    std::process::exit(status);
 }
@@ -0,0 +1,86 @@
+use std::future::Future;
+use std::time::Instant;
+
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_histogram, register_histogram_vec, register_int_counter_vec, Encoder, Histogram, HistogramVec,
+    IntCounterVec, TextEncoder,
+};
+
+/// telegram messages handled, by the dialogue state they arrived in
+pub static MESSAGES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!("digireport_messages_total", "Telegram messages handled", &["state"]).unwrap()
+});
+
+/// gitlab API calls by endpoint and HTTP status, `error` when no response came back
+pub static GITLAB_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!("digireport_gitlab_requests_total", "Gitlab API calls", &["endpoint", "status"])
+        .unwrap()
+});
+
+pub static GITLAB_LATENCY: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "digireport_gitlab_request_duration_seconds",
+        "Gitlab API call latency",
+        &["endpoint"]
+    )
+    .unwrap()
+});
+
+/// scheduled and API report deliveries, by result `sent` or `failed`
+pub static REPORTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!("digireport_reports_total", "Report deliveries", &["result"]).unwrap()
+});
+
+/// time the QA model takes for one batch of questions
+pub static QA_INFERENCE: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "digireport_qa_inference_seconds",
+        "QA model inference time per batch",
+        vec![0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0]
+    )
+    .unwrap()
+});
+
+/// registers every metric so they are exported before their first observation
+pub fn init() {
+    Lazy::force(&MESSAGES);
+    Lazy::force(&GITLAB_REQUESTS);
+    Lazy::force(&GITLAB_LATENCY);
+    Lazy::force(&REPORTS);
+    Lazy::force(&QA_INFERENCE);
+}
+
+/// sends a gitlab request, recording its status and latency under `endpoint`
+pub async fn gitlab_call<F>(endpoint: &str, request: F) -> reqwest::Result<reqwest::Response>
+where
+    F: Future<Output = reqwest::Result<reqwest::Response>>,
+{
+    let start = Instant::now();
+    let response = request.await;
+    GITLAB_LATENCY
+        .with_label_values(&[endpoint])
+        .observe(start.elapsed().as_secs_f64());
+
+    let status = match &response {
+        Ok(response) => response.status().as_u16().to_string(),
+        Err(_) => "error".to_string(),
+    };
+    GITLAB_REQUESTS.with_label_values(&[endpoint, &status]).inc();
+
+    response
+}
+
+pub fn report_sent(sent: bool) {
+    REPORTS.with_label_values(&[if sent { "sent" } else { "failed" }]).inc();
+}
+
+/// every metric in the Prometheus text format
+pub fn render() -> String {
+    let mut buffer = Vec::new();
+    if let Err(err) = TextEncoder::new().encode(&prometheus::gather(), &mut buffer) {
+        log::error!("failed to encode the metrics: {}", err);
+    }
+
+    String::from_utf8(buffer).unwrap_or_default()
+}
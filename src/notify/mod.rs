@@ -0,0 +1,386 @@
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use reqwest::Url;
+use serde::Deserialize;
+use sha2::Sha256;
+use teloxide::prelude::*;
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup, ParseMode};
+use teloxide::RequestError;
+use tokio::time::Instant;
+use utoipa::ToSchema;
+
+use crate::report;
+
+// requests signed longer ago than this are refused, so captured ones cannot be replayed later
+const MAX_SIGNATURE_AGE_SECS: i64 = 5 * 60;
+// telegram allows about 20 messages a minute in a group
+const DEFAULT_CHAT_INTERVAL_MS: u64 = 3000;
+const MAX_ATTEMPTS: u32 = 3;
+// a flood wait longer than this fails the delivery instead of holding the request
+const MAX_RETRY_WAIT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, thiserror::Error)]
+pub enum SignatureError {
+    #[error("missing `X-Notify-Timestamp` or `X-Notify-Signature` header")]
+    Missing,
+    #[error("invalid signature")]
+    Invalid,
+    #[error("the signature has expired, sign the request again")]
+    Expired,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Plain,
+    Html,
+    // telegram's MarkdownV2
+    Markdown,
+}
+
+/// a button opening a link
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+pub struct Button {
+    text: String,
+    url: String,
+}
+
+/// a message other tools send to the chats of a channel
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+pub struct Notification {
+    pub text: String,
+    #[serde(default)]
+    pub format: Format,
+    // rows of buttons shown under the message
+    #[serde(default)]
+    pub buttons: Vec<Vec<Button>>,
+    // sends without a notification sound
+    #[serde(default)]
+    pub silent: bool,
+}
+
+impl Notification {
+    pub fn plain(text: String) -> Notification {
+        Notification {
+            text,
+            format: Format::Plain,
+            buttons: Vec::new(),
+            silent: false,
+        }
+    }
+
+    /// the buttons as a keyboard, an error names the first invalid link
+    pub fn keyboard(&self) -> Result<Option<InlineKeyboardMarkup>, String> {
+        if self.buttons.iter().all(|row| row.is_empty()) {
+            return Ok(None);
+        }
+        let rows = self
+            .buttons
+            .iter()
+            .filter(|row| !row.is_empty())
+            .map(|row| {
+                row.iter()
+                    .map(|button| {
+                        Url::parse(&button.url)
+                            .map(|url| InlineKeyboardButton::url(button.text.clone(), url))
+                            .map_err(|_| format!("invalid button link `{}`", button.url))
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Some(InlineKeyboardMarkup::new(rows)))
+    }
+}
+
+/// checks the signatures of notification requests and sends them at a pace telegram accepts
+#[derive(Clone)]
+pub struct Notifier {
+    // None disables the endpoint
+    secret: Option<Vec<u8>>,
+    chat_interval: Duration,
+    // when each chat may receive its next message
+    next_slot: Arc<Mutex<HashMap<ChatId, Instant>>>,
+}
+
+impl Notifier {
+    /// reads `NOTIFY_SECRET`, the key requests are signed with, and
+    /// `NOTIFY_CHAT_INTERVAL_MS`, the time between two messages to the same chat
+    pub fn from_env() -> Notifier {
+        let chat_interval = env::var("NOTIFY_CHAT_INTERVAL_MS")
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+            .unwrap_or(DEFAULT_CHAT_INTERVAL_MS);
+
+        Notifier {
+            secret: env::var("NOTIFY_SECRET")
+                .ok()
+                .filter(|secret| !secret.is_empty())
+                .map(String::into_bytes),
+            chat_interval: Duration::from_millis(chat_interval),
+            next_slot: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.secret.is_some()
+    }
+
+    /// the signature is the hex HMAC-SHA256 of `<timestamp>.<body>`, optionally prefixed
+    /// with `sha256=`, the timestamp is in Unix seconds
+    pub fn verify(
+        &self,
+        timestamp: Option<&str>,
+        signature: Option<&str>,
+        body: &[u8],
+        now: DateTime<Utc>,
+    ) -> Result<(), SignatureError> {
+        let secret = self.secret.as_ref().ok_or(SignatureError::Invalid)?;
+        let (timestamp, signature) = match (timestamp, signature) {
+            (Some(timestamp), Some(signature)) => (timestamp.trim(), signature.trim()),
+            _ => return Err(SignatureError::Missing),
+        };
+        let signature = hex::decode(signature.strip_prefix("sha256=").unwrap_or(signature))
+            .map_err(|_| SignatureError::Invalid)?;
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+        mac.update(timestamp.as_bytes());
+        mac.update(b".");
+        mac.update(body);
+        mac.verify_slice(&signature).map_err(|_| SignatureError::Invalid)?;
+
+        let signed_at = timestamp.parse::<i64>().map_err(|_| SignatureError::Invalid)?;
+        if (now.timestamp() - signed_at).abs() > MAX_SIGNATURE_AGE_SECS {
+            return Err(SignatureError::Expired);
+        }
+
+        Ok(())
+    }
+
+    /// waits for the chat's next free slot and reserves the one after it
+    async fn wait_turn(&self, chat_id: ChatId) {
+        let slot = {
+            let mut next_slot = self.next_slot.lock().unwrap();
+            let now = Instant::now();
+            // forget chats that have been quiet for a while
+            next_slot.retain(|_, slot| *slot > now);
+            let slot = next_slot.get(&chat_id).copied().unwrap_or(now).max(now);
+            next_slot.insert(chat_id, slot + self.chat_interval);
+            slot
+        };
+        tokio::time::sleep_until(slot).await;
+    }
+
+    /// sends the notification to a chat, split when too long with the buttons under
+    /// the last part, retrying flood waits and network failures
+    pub async fn deliver(
+        &self,
+        bot: &Bot,
+        chat_id: ChatId,
+        notification: &Notification,
+        keyboard: Option<&InlineKeyboardMarkup>,
+    ) -> Result<(), RequestError> {
+        let parts = report::split_message(&notification.text);
+        let last = parts.len().saturating_sub(1);
+        for (index, part) in parts.iter().enumerate() {
+            let keyboard = if index == last { keyboard } else { None };
+            self.send_part(bot, chat_id, part, notification, keyboard).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn send_part(
+        &self,
+        bot: &Bot,
+        chat_id: ChatId,
+        text: &str,
+        notification: &Notification,
+        keyboard: Option<&InlineKeyboardMarkup>,
+    ) -> Result<(), RequestError> {
+        let mut attempt = 1;
+        loop {
+            self.wait_turn(chat_id).await;
+
+            let mut request = bot
+                .send_message(chat_id, text)
+                .disable_notification(notification.silent);
+            match notification.format {
+                Format::Plain => {}
+                Format::Html => request = request.parse_mode(ParseMode::Html),
+                Format::Markdown => request = request.parse_mode(ParseMode::MarkdownV2),
+            }
+            if let Some(keyboard) = keyboard {
+                request = request.reply_markup(keyboard.clone());
+            }
+
+            let wait = match request.await {
+                Ok(_) => return Ok(()),
+                Err(RequestError::RetryAfter(wait)) if attempt < MAX_ATTEMPTS && wait <= MAX_RETRY_WAIT => wait,
+                Err(RequestError::Network(_) | RequestError::Io(_)) if attempt < MAX_ATTEMPTS => {
+                    Duration::from_secs(1 << attempt)
+                }
+                Err(err) => return Err(err),
+            };
+            log::warn!(
+                "notification to {} failed, attempt {} of {}, retrying in {:?}",
+                chat_id,
+                attempt,
+                MAX_ATTEMPTS,
+                wait
+            );
+            tokio::time::sleep(wait).await;
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use teloxide::types::InlineKeyboardButtonKind;
+
+    const SECRET: &str = "deploy-secret";
+
+    fn notifier(secret: Option<&str>) -> Notifier {
+        Notifier {
+            secret: secret.map(|secret| secret.as_bytes().to_vec()),
+            chat_interval: Duration::from_millis(DEFAULT_CHAT_INTERVAL_MS),
+            next_slot: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn now() -> DateTime<Utc> {
+        Utc.timestamp_opt(1_700_000_000, 0).unwrap()
+    }
+
+    fn sign(timestamp: &str, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(SECRET.as_bytes()).unwrap();
+        mac.update(format!("{}.", timestamp).as_bytes());
+        mac.update(body);
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    #[test]
+    fn accepts_signed_requests() {
+        let notifier = notifier(Some(SECRET));
+        let timestamp = now().timestamp().to_string();
+        let body = br#"{"text":"deployed"}"#;
+        let signature = sign(&timestamp, body);
+
+        assert!(notifier.verify(Some(&timestamp), Some(&signature), body, now()).is_ok());
+        let prefixed = format!("sha256={}", signature);
+        assert!(notifier.verify(Some(&timestamp), Some(&prefixed), body, now()).is_ok());
+    }
+
+    #[test]
+    fn refuses_unsigned_or_tampered_requests() {
+        let notifier = notifier(Some(SECRET));
+        let timestamp = now().timestamp().to_string();
+        let signature = sign(&timestamp, b"deployed");
+
+        assert!(matches!(
+            notifier.verify(None, Some(&signature), b"deployed", now()),
+            Err(SignatureError::Missing)
+        ));
+        assert!(matches!(
+            notifier.verify(Some(&timestamp), None, b"deployed", now()),
+            Err(SignatureError::Missing)
+        ));
+        assert!(matches!(
+            notifier.verify(Some(&timestamp), Some(&signature), b"rolled back", now()),
+            Err(SignatureError::Invalid)
+        ));
+        assert!(matches!(
+            notifier.verify(Some(&timestamp), Some("sha256=zz"), b"deployed", now()),
+            Err(SignatureError::Invalid)
+        ));
+        // the timestamp is part of what is signed
+        let later = (now().timestamp() + 1).to_string();
+        assert!(matches!(
+            notifier.verify(Some(&later), Some(&signature), b"deployed", now()),
+            Err(SignatureError::Invalid)
+        ));
+    }
+
+    #[test]
+    fn refuses_old_signatures() {
+        let notifier = notifier(Some(SECRET));
+        let timestamp = (now().timestamp() - MAX_SIGNATURE_AGE_SECS - 1).to_string();
+        let signature = sign(&timestamp, b"deployed");
+
+        assert!(matches!(
+            notifier.verify(Some(&timestamp), Some(&signature), b"deployed", now()),
+            Err(SignatureError::Expired)
+        ));
+    }
+
+    #[test]
+    fn refuses_everything_without_a_secret() {
+        let notifier = notifier(None);
+        let timestamp = now().timestamp().to_string();
+        let signature = sign(&timestamp, b"deployed");
+
+        assert!(!notifier.is_enabled());
+        assert!(notifier.verify(Some(&timestamp), Some(&signature), b"deployed", now()).is_err());
+    }
+
+    #[test]
+    fn builds_the_keyboard_from_the_buttons() {
+        let notification: Notification = serde_json::from_str(
+            r#"{
+                "text": "v1.2 is live",
+                "format": "html",
+                "buttons": [[{"text": "Changelog", "url": "https://example.com/changelog"}], [],
+                            [{"text": "Pipeline", "url": "https://example.com/pipelines/7"}]]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(notification.format, Format::Html);
+        assert!(!notification.silent);
+
+        let keyboard = notification.keyboard().unwrap().unwrap();
+        let rows: Vec<Vec<(&str, String)>> = keyboard
+            .inline_keyboard
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|button| match &button.kind {
+                        InlineKeyboardButtonKind::Url(url) => (button.text.as_str(), url.to_string()),
+                        kind => panic!("unexpected button {:?}", kind),
+                    })
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            rows,
+            vec![
+                vec![("Changelog", "https://example.com/changelog".to_string())],
+                vec![("Pipeline", "https://example.com/pipelines/7".to_string())],
+            ]
+        );
+    }
+
+    #[test]
+    fn no_buttons_means_no_keyboard() {
+        assert!(Notification::plain("deployed".to_string()).keyboard().unwrap().is_none());
+    }
+
+    #[test]
+    fn names_the_invalid_link() {
+        let mut notification = Notification::plain("deployed".to_string());
+        notification.buttons = vec![vec![Button {
+            text: "Open".to_string(),
+            url: "not a link".to_string(),
+        }]];
+
+        assert_eq!(notification.keyboard().unwrap_err(), "invalid button link `not a link`");
+    }
+}
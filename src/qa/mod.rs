@@ -0,0 +1,789 @@
+use rust_bert::pipelines::common::{ModelResource, ModelType};
+use rust_bert::pipelines::question_answering::{
+    QaInput, QuestionAnsweringConfig, QuestionAnsweringModel,
+};
+use rust_bert::resources::LocalResource;
+use std::env;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::metrics;
+
+// answers scoring below this are treated as "don't know"
+const DEFAULT_SCORE_THRESHOLD: f64 = 0.07;
+// questions waiting for a worker before new ones are turned away
+const DEFAULT_QUEUE_SIZE: usize = 32;
+// questions answered together in one forward pass
+const DEFAULT_BATCH_SIZE: usize = 8;
+const DEFAULT_WORKERS: usize = 1;
+const DEFAULT_TIMEOUT_SECS: u64 = 10;
+// candidate answers returned per question
+const DEFAULT_TOP_N: usize = 3;
+
+#[derive(Clone, Debug)]
+pub struct QaConfig {
+    pub model_type: ModelType,
+    pub model_path: PathBuf,
+    pub config_path: PathBuf,
+    pub vocab_path: PathBuf,
+    // only used by RoBERTa models
+    pub merges_path: Option<PathBuf>,
+    pub lower_case: bool,
+    pub score_threshold: f64,
+    pub queue_size: usize,
+    pub batch_size: usize,
+    // every worker thread holds its own copy of the model
+    pub workers: usize,
+    pub timeout: Duration,
+    pub top_n: usize,
+}
+
+impl QaConfig {
+    /// reads the model location from the environment, returns None when no model is configured
+    ///
+    /// `QA_MODEL_DIR` points to a directory with `rust_model.ot`, `config.json`, the vocabulary
+    /// (`vocab.txt`, or `vocab.json` and `merges.txt` for RoBERTa), each file can be
+    /// overridden with `QA_MODEL_PATH`, `QA_CONFIG_PATH`, `QA_VOCAB_PATH` and `QA_MERGES_PATH`.
+    /// The worker pool is tuned with `QA_WORKERS`, `QA_QUEUE_SIZE`, `QA_BATCH_SIZE` and `QA_TIMEOUT_SECS`,
+    /// `QA_TOP_N` sets how many candidate answers are returned
+    pub fn from_env() -> Result<Option<QaConfig>, String> {
+        let dir = env::var("QA_MODEL_DIR").ok().map(PathBuf::from);
+        let model_path = env::var("QA_MODEL_PATH").ok().map(PathBuf::from);
+        if dir.is_none() && model_path.is_none() {
+            return Ok(None);
+        }
+
+        let model_type = match env::var("QA_MODEL_TYPE")
+            .unwrap_or_else(|_| "bert".to_string())
+            .to_lowercase()
+            .as_str()
+        {
+            "bert" => ModelType::Bert,
+            "distilbert" => ModelType::DistilBert,
+            "roberta" => ModelType::Roberta,
+            other => return Err(format!("unsupported QA_MODEL_TYPE `{}`", other)),
+        };
+        let is_roberta = model_type == ModelType::Roberta;
+
+        // a file from the environment, or its default name inside QA_MODEL_DIR
+        let path = |name: &str, default: &str| -> Option<PathBuf> {
+            env::var(name)
+                .ok()
+                .map(PathBuf::from)
+                .or_else(|| dir.as_ref().map(|dir| dir.join(default)))
+        };
+
+        let missing = |name: &str| format!("{} or QA_MODEL_DIR is required", name);
+        let vocab_default = if is_roberta { "vocab.json" } else { "vocab.txt" };
+
+        let config = QaConfig {
+            model_type,
+            model_path: path("QA_MODEL_PATH", "rust_model.ot").ok_or_else(|| missing("QA_MODEL_PATH"))?,
+            config_path: path("QA_CONFIG_PATH", "config.json").ok_or_else(|| missing("QA_CONFIG_PATH"))?,
+            vocab_path: path("QA_VOCAB_PATH", vocab_default).ok_or_else(|| missing("QA_VOCAB_PATH"))?,
+            merges_path: if is_roberta {
+                Some(path("QA_MERGES_PATH", "merges.txt").ok_or_else(|| missing("QA_MERGES_PATH"))?)
+            } else {
+                None
+            },
+            lower_case: env::var("QA_LOWER_CASE")
+                .map(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+                .unwrap_or(false),
+            score_threshold: match env::var("QA_SCORE_THRESHOLD") {
+                Ok(value) => value
+                    .parse()
+                    .map_err(|_| format!("invalid QA_SCORE_THRESHOLD `{}`", value))?,
+                Err(_) => DEFAULT_SCORE_THRESHOLD,
+            },
+            queue_size: number_from_env("QA_QUEUE_SIZE", DEFAULT_QUEUE_SIZE)?,
+            batch_size: number_from_env("QA_BATCH_SIZE", DEFAULT_BATCH_SIZE)?,
+            workers: number_from_env("QA_WORKERS", DEFAULT_WORKERS)?,
+            timeout: Duration::from_secs(number_from_env("QA_TIMEOUT_SECS", DEFAULT_TIMEOUT_SECS as usize)? as u64),
+            top_n: number_from_env("QA_TOP_N", DEFAULT_TOP_N)?,
+        };
+
+        for file in [&config.model_path, &config.config_path, &config.vocab_path]
+            .into_iter()
+            .chain(config.merges_path.as_ref())
+        {
+            if !file.is_file() {
+                return Err(format!("QA model file {} does not exist", file.display()));
+            }
+        }
+
+        Ok(Some(config))
+    }
+
+    fn to_pipeline_config(&self) -> QuestionAnsweringConfig {
+        QuestionAnsweringConfig::new(
+            self.model_type,
+            ModelResource::Torch(Box::new(LocalResource {
+                local_path: self.model_path.clone(),
+            })),
+            LocalResource {
+                local_path: self.config_path.clone(),
+            },
+            LocalResource {
+                local_path: self.vocab_path.clone(),
+            },
+            self.merges_path.clone().map(|local_path| LocalResource { local_path }),
+            self.lower_case,
+            None,
+            None,
+        )
+    }
+}
+
+/// reads a positive number from the environment variable `name`
+pub fn number_from_env(name: &str, default: usize) -> Result<usize, String> {
+    match env::var(name) {
+        Ok(value) => match value.parse() {
+            Ok(number) if number > 0 => Ok(number),
+            _ => Err(format!("invalid {} `{}`", name, value)),
+        },
+        Err(_) => Ok(default),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ModelState {
+    Disabled,
+    Loading,
+    Ready,
+    Failed,
+}
+
+#[derive(Clone, Debug)]
+pub struct Candidate {
+    pub text: String,
+    // model confidence between 0 and 1
+    pub score: f64,
+}
+
+pub enum QaAnswer {
+    // no model is configured or it failed to load
+    Disabled,
+    // the model is still being loaded in the background
+    Loading,
+    // the queue is full, the question was not accepted
+    Busy,
+    TimedOut,
+    // candidates above the score threshold, best first, never empty
+    Answer(Vec<Candidate>),
+    NoAnswer,
+}
+
+struct QaRequest {
+    input: QaInput,
+    reply: oneshot::Sender<Vec<Candidate>>,
+}
+
+/// the question answering service, questions are queued and answered in batches
+/// by dedicated worker threads so inference never blocks the async runtime
+#[derive(Clone)]
+pub struct Qa {
+    state: Arc<RwLock<ModelState>>,
+    // None when question answering is disabled
+    queue: Option<mpsc::Sender<QaRequest>>,
+    score_threshold: f64,
+    timeout: Duration,
+}
+
+impl Qa {
+    pub fn disabled() -> Qa {
+        Qa {
+            state: Arc::new(RwLock::new(ModelState::Disabled)),
+            queue: None,
+            score_threshold: DEFAULT_SCORE_THRESHOLD,
+            timeout: Duration::from_secs(DEFAULT_TIMEOUT_SECS),
+        }
+    }
+
+    /// starts the worker threads, each loads the model and then serves the queue
+    pub fn load(config: QaConfig) -> Qa {
+        let pipeline = config.clone();
+        Qa::start(&config, move || {
+            QuestionAnsweringModel::new(pipeline.to_pipeline_config()).map_err(|err| err.to_string())
+        })
+    }
+
+    /// starts `config.workers` threads that each load a model with `load` and then serve the queue
+    fn start<M, F>(config: &QaConfig, load: F) -> Qa
+    where
+        M: AnswerModel,
+        F: Fn() -> Result<M, String> + Send + Sync + 'static,
+    {
+        let (tx, rx) = mpsc::channel(config.queue_size);
+        let rx = Arc::new(Mutex::new(rx));
+        let state = Arc::new(RwLock::new(ModelState::Loading));
+        let failed_workers = Arc::new(Mutex::new(0));
+        let load = Arc::new(load);
+
+        for index in 0..config.workers {
+            let config = config.clone();
+            let rx = Arc::clone(&rx);
+            let worker_state = Arc::clone(&state);
+            let worker_failed = Arc::clone(&failed_workers);
+            let load = Arc::clone(&load);
+
+            let spawned = thread::Builder::new()
+                .name(format!("qa-worker-{}", index))
+                .spawn(move || {
+                    log::info!("Loading QA model from {}", config.model_path.display());
+                    let model = match load() {
+                        Ok(model) => model,
+                        Err(err) => {
+                            log::error!("QA worker {} failed to load the model: {}", index, err);
+                            worker_failed_to_start(&worker_failed, config.workers, &worker_state);
+                            return;
+                        }
+                    };
+                    log::info!("QA worker {} ready", index);
+                    *worker_state.write().unwrap() = ModelState::Ready;
+
+                    serve(&model, &rx, config.batch_size, config.top_n);
+                });
+
+            if let Err(err) = spawned {
+                log::error!("failed to start QA worker {}: {}", index, err);
+                worker_failed_to_start(&failed_workers, config.workers, &state);
+            }
+        }
+
+        Qa {
+            state,
+            queue: Some(tx),
+            score_threshold: config.score_threshold,
+            timeout: config.timeout,
+        }
+    }
+
+    /// loads the model configured in the environment, or returns a disabled one
+    pub fn from_env() -> Qa {
+        match QaConfig::from_env() {
+            Ok(Some(config)) => Qa::load(config),
+            Ok(None) => {
+                log::info!("No QA model configured, question answering is disabled");
+                Qa::disabled()
+            }
+            Err(err) => {
+                log::error!("invalid QA configuration, question answering is disabled: {}", err);
+                Qa::disabled()
+            }
+        }
+    }
+
+    /// whether questions can be answered, or no model was configured and nothing has to load
+    pub fn is_ready(&self) -> bool {
+        matches!(*self.state.read().unwrap(), ModelState::Ready | ModelState::Disabled)
+    }
+
+    pub async fn answer(&self, question: String, context: String) -> QaAnswer {
+        match *self.state.read().unwrap() {
+            ModelState::Ready => {}
+            ModelState::Loading => return QaAnswer::Loading,
+            ModelState::Disabled | ModelState::Failed => return QaAnswer::Disabled,
+        }
+        let queue = match &self.queue {
+            Some(queue) => queue,
+            None => return QaAnswer::Disabled,
+        };
+
+        let (reply, answer) = oneshot::channel();
+        let request = QaRequest {
+            input: QaInput { question, context },
+            reply,
+        };
+        if let Err(err) = queue.try_send(request) {
+            return match err {
+                mpsc::error::TrySendError::Full(_) => QaAnswer::Busy,
+                mpsc::error::TrySendError::Closed(_) => QaAnswer::Disabled,
+            };
+        }
+
+        match tokio::time::timeout(self.timeout, answer).await {
+            Ok(Ok(candidates)) => {
+                log::debug!("QA candidates {:?}", candidates);
+                let candidates: Vec<Candidate> = candidates
+                    .into_iter()
+                    .filter(|candidate| candidate.score > self.score_threshold)
+                    .collect();
+                if candidates.is_empty() {
+                    QaAnswer::NoAnswer
+                } else {
+                    QaAnswer::Answer(candidates)
+                }
+            }
+            // the worker dropped the request
+            Ok(Err(_)) => QaAnswer::Disabled,
+            Err(_) => QaAnswer::TimedOut,
+        }
+    }
+}
+
+/// counts a worker that will never answer, question answering fails once none is left
+fn worker_failed_to_start(failed_workers: &Mutex<usize>, workers: usize, state: &RwLock<ModelState>) {
+    let mut failed_workers = failed_workers.lock().unwrap();
+    *failed_workers += 1;
+    if *failed_workers == workers {
+        *state.write().unwrap() = ModelState::Failed;
+    }
+}
+
+/// something that answers a batch of questions, implemented by the rust-bert model
+/// and by fakes in tests
+trait AnswerModel {
+    /// up to `top_n` candidates for each input, in the order of the inputs
+    fn answer_batch(&self, inputs: &[QaInput], top_n: usize, batch_size: usize) -> Vec<Vec<Candidate>>;
+}
+
+impl AnswerModel for QuestionAnsweringModel {
+    fn answer_batch(&self, inputs: &[QaInput], top_n: usize, batch_size: usize) -> Vec<Vec<Candidate>> {
+        self.predict(inputs, top_n as i64, batch_size)
+            .into_iter()
+            .map(|answers| {
+                answers
+                    .into_iter()
+                    .map(|answer| Candidate {
+                        text: answer.answer,
+                        score: answer.score,
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+/// answers queued questions until the queue is closed, taking up to `batch_size`
+/// waiting questions at a time
+fn serve(model: &impl AnswerModel, rx: &Mutex<mpsc::Receiver<QaRequest>>, batch_size: usize, top_n: usize) {
+    loop {
+        let batch = {
+            let mut rx = rx.lock().unwrap();
+            let first = match rx.blocking_recv() {
+                Some(request) => request,
+                None => return,
+            };
+            let mut batch = vec![first];
+            while batch.len() < batch_size {
+                match rx.try_recv() {
+                    Ok(request) => batch.push(request),
+                    Err(_) => break,
+                }
+            }
+            batch
+        };
+
+        // skip questions whose asker already gave up waiting
+        let (inputs, replies): (Vec<_>, Vec<_>) = batch
+            .into_iter()
+            .filter(|request| !request.reply.is_closed())
+            .map(|request| (request.input, request.reply))
+            .unzip();
+        if inputs.is_empty() {
+            continue;
+        }
+
+        let timer = metrics::QA_INFERENCE.start_timer();
+        let mut answers = model.answer_batch(&inputs, top_n, batch_size).into_iter();
+        timer.observe_duration();
+        for reply in replies {
+            let mut candidates = answers.next().unwrap_or_default();
+            candidates.sort_by(|a, b| b.score.total_cmp(&a.score));
+            let _ = reply.send(candidates);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::sync::mpsc as std_mpsc;
+
+    // the environment is shared by every test thread
+    static ENV: Mutex<()> = Mutex::new(());
+
+    const VARIABLES: [&str; 13] = [
+        "QA_MODEL_DIR",
+        "QA_MODEL_PATH",
+        "QA_CONFIG_PATH",
+        "QA_VOCAB_PATH",
+        "QA_MERGES_PATH",
+        "QA_MODEL_TYPE",
+        "QA_LOWER_CASE",
+        "QA_SCORE_THRESHOLD",
+        "QA_QUEUE_SIZE",
+        "QA_BATCH_SIZE",
+        "QA_WORKERS",
+        "QA_TIMEOUT_SECS",
+        "QA_TOP_N",
+    ];
+
+    /// runs `from_env` with only `vars` set
+    fn from_env(vars: &[(&str, &str)]) -> Result<Option<QaConfig>, String> {
+        let _env = ENV.lock().unwrap_or_else(|err| err.into_inner());
+        for name in VARIABLES {
+            env::remove_var(name);
+        }
+        for (name, value) in vars {
+            env::set_var(name, value);
+        }
+        let config = QaConfig::from_env();
+        for (name, _) in vars {
+            env::remove_var(name);
+        }
+        config
+    }
+
+    /// a model directory holding `files`
+    fn model_dir(name: &str, files: &[&str]) -> String {
+        let dir = env::temp_dir().join(format!("digireport-qa-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for file in files {
+            fs::write(dir.join(file), "").unwrap();
+        }
+        dir.to_string_lossy().into_owned()
+    }
+
+    const BERT_FILES: [&str; 3] = ["rust_model.ot", "config.json", "vocab.txt"];
+
+    #[test]
+    fn disabled_without_a_model() {
+        assert!(from_env(&[]).unwrap().is_none());
+        assert!(from_env(&[("QA_MODEL_TYPE", "roberta")]).unwrap().is_none());
+    }
+
+    #[test]
+    fn reads_a_bert_model_directory_with_defaults() {
+        let dir = model_dir("defaults", &BERT_FILES);
+
+        let config = from_env(&[("QA_MODEL_DIR", &dir)]).unwrap().unwrap();
+        assert_eq!(config.model_type, ModelType::Bert);
+        assert_eq!(config.model_path, PathBuf::from(&dir).join("rust_model.ot"));
+        assert_eq!(config.config_path, PathBuf::from(&dir).join("config.json"));
+        assert_eq!(config.vocab_path, PathBuf::from(&dir).join("vocab.txt"));
+        assert_eq!(config.merges_path, None);
+        assert!(!config.lower_case);
+        assert_eq!(config.score_threshold, DEFAULT_SCORE_THRESHOLD);
+        assert_eq!(config.queue_size, DEFAULT_QUEUE_SIZE);
+        assert_eq!(config.batch_size, DEFAULT_BATCH_SIZE);
+        assert_eq!(config.workers, DEFAULT_WORKERS);
+        assert_eq!(config.timeout, Duration::from_secs(DEFAULT_TIMEOUT_SECS));
+        assert_eq!(config.top_n, DEFAULT_TOP_N);
+    }
+
+    #[test]
+    fn reads_a_roberta_model_with_merges() {
+        let dir = model_dir("roberta", &["rust_model.ot", "config.json", "vocab.json", "merges.txt"]);
+
+        let config = from_env(&[("QA_MODEL_DIR", &dir), ("QA_MODEL_TYPE", "RoBERTa"), ("QA_LOWER_CASE", "true")])
+            .unwrap()
+            .unwrap();
+        assert_eq!(config.model_type, ModelType::Roberta);
+        assert_eq!(config.vocab_path, PathBuf::from(&dir).join("vocab.json"));
+        assert_eq!(config.merges_path, Some(PathBuf::from(&dir).join("merges.txt")));
+        assert!(config.lower_case);
+    }
+
+    #[test]
+    fn files_can_be_named_one_by_one() {
+        let dir = model_dir("files", &["model.ot", "model.json", "words.txt"]);
+        let file = |name: &str| format!("{}/{}", dir, name);
+
+        let config = from_env(&[
+            ("QA_MODEL_PATH", &file("model.ot")),
+            ("QA_CONFIG_PATH", &file("model.json")),
+            ("QA_VOCAB_PATH", &file("words.txt")),
+        ])
+        .unwrap()
+        .unwrap();
+        assert_eq!(config.vocab_path, PathBuf::from(file("words.txt")));
+
+        let err = from_env(&[("QA_MODEL_PATH", &file("model.ot"))]).unwrap_err();
+        assert_eq!(err, "QA_CONFIG_PATH or QA_MODEL_DIR is required");
+    }
+
+    #[test]
+    fn reads_the_tuning() {
+        let dir = model_dir("tuning", &BERT_FILES);
+
+        let config = from_env(&[
+            ("QA_MODEL_DIR", &dir),
+            ("QA_SCORE_THRESHOLD", "0.2"),
+            ("QA_QUEUE_SIZE", "4"),
+            ("QA_BATCH_SIZE", "2"),
+            ("QA_WORKERS", "3"),
+            ("QA_TIMEOUT_SECS", "30"),
+            ("QA_TOP_N", "5"),
+        ])
+        .unwrap()
+        .unwrap();
+        assert_eq!(config.score_threshold, 0.2);
+        assert_eq!(config.queue_size, 4);
+        assert_eq!(config.batch_size, 2);
+        assert_eq!(config.workers, 3);
+        assert_eq!(config.timeout, Duration::from_secs(30));
+        assert_eq!(config.top_n, 5);
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        let dir = model_dir("invalid", &BERT_FILES);
+
+        let err = |name: &str, value: &str| from_env(&[("QA_MODEL_DIR", &dir), (name, value)]).unwrap_err();
+        assert_eq!(err("QA_MODEL_TYPE", "gpt2"), "unsupported QA_MODEL_TYPE `gpt2`");
+        assert_eq!(err("QA_SCORE_THRESHOLD", "high"), "invalid QA_SCORE_THRESHOLD `high`");
+        assert_eq!(err("QA_WORKERS", "0"), "invalid QA_WORKERS `0`");
+        assert_eq!(err("QA_QUEUE_SIZE", "-1"), "invalid QA_QUEUE_SIZE `-1`");
+        assert_eq!(err("QA_TOP_N", "many"), "invalid QA_TOP_N `many`");
+    }
+
+    #[test]
+    fn rejects_missing_model_files() {
+        let dir = model_dir("missing", &["rust_model.ot", "config.json"]);
+
+        let err = from_env(&[("QA_MODEL_DIR", &dir)]).unwrap_err();
+        assert!(err.contains("vocab.txt does not exist"), "{}", err);
+
+        // RoBERTa needs its own vocabulary and the merges
+        let dir = model_dir("missing-roberta", &BERT_FILES);
+        assert!(from_env(&[("QA_MODEL_DIR", &dir), ("QA_MODEL_TYPE", "roberta")]).is_err());
+    }
+
+    // where a gated model announces a batch and waits for the go
+    type Gate = (Arc<Mutex<std_mpsc::Sender<()>>>, Arc<Mutex<std_mpsc::Receiver<()>>>);
+
+    /// answers every question with the same candidates, and remembers how it was called
+    #[derive(Clone, Default)]
+    struct FakeModel {
+        candidates: Vec<(&'static str, f64)>,
+        // the number of questions and the top-N of every batch
+        batches: Arc<Mutex<Vec<(usize, usize)>>>,
+        // when set, every batch is announced here and waits for a go
+        gate: Option<Gate>,
+    }
+
+    impl AnswerModel for FakeModel {
+        fn answer_batch(&self, inputs: &[QaInput], top_n: usize, _batch_size: usize) -> Vec<Vec<Candidate>> {
+            self.batches.lock().unwrap().push((inputs.len(), top_n));
+            if let Some((started, go)) = &self.gate {
+                let _ = started.lock().unwrap().send(());
+                // the test dropping its sender lets the worker go too
+                let _ = go.lock().unwrap().recv();
+            }
+            let candidates: Vec<Candidate> = self
+                .candidates
+                .iter()
+                .map(|(text, score)| Candidate {
+                    text: text.to_string(),
+                    score: *score,
+                })
+                .collect();
+            inputs.iter().map(|_| candidates.clone()).collect()
+        }
+    }
+
+    /// a model whose batches wait for the test, with the ends the test holds
+    fn gated(candidates: Vec<(&'static str, f64)>) -> (FakeModel, std_mpsc::Receiver<()>, std_mpsc::Sender<()>) {
+        let (started_tx, started) = std_mpsc::channel();
+        let (go, go_rx) = std_mpsc::channel();
+        let model = FakeModel {
+            candidates,
+            gate: Some((Arc::new(Mutex::new(started_tx)), Arc::new(Mutex::new(go_rx)))),
+            ..FakeModel::default()
+        };
+        (model, started, go)
+    }
+
+    fn config() -> QaConfig {
+        QaConfig {
+            model_type: ModelType::Bert,
+            model_path: PathBuf::from("model/rust_model.ot"),
+            config_path: PathBuf::from("model/config.json"),
+            vocab_path: PathBuf::from("model/vocab.txt"),
+            merges_path: None,
+            lower_case: true,
+            score_threshold: DEFAULT_SCORE_THRESHOLD,
+            queue_size: DEFAULT_QUEUE_SIZE,
+            batch_size: DEFAULT_BATCH_SIZE,
+            workers: DEFAULT_WORKERS,
+            timeout: Duration::from_secs(DEFAULT_TIMEOUT_SECS),
+            top_n: DEFAULT_TOP_N,
+        }
+    }
+
+    async fn started(config: &QaConfig, model: FakeModel) -> Qa {
+        let qa = Qa::start(config, move || Ok(model.clone()));
+        wait_for(|| qa.is_ready()).await;
+        qa
+    }
+
+    async fn wait_for(done: impl Fn() -> bool) {
+        for _ in 0..500 {
+            if done() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("gave up waiting");
+    }
+
+    fn ask(qa: &Qa) -> tokio::task::JoinHandle<QaAnswer> {
+        let qa = qa.clone();
+        tokio::spawn(async move { qa.answer("what?".to_string(), "context".to_string()).await })
+    }
+
+    fn queued(qa: &Qa, config: &QaConfig) -> usize {
+        config.queue_size - qa.queue.as_ref().unwrap().capacity()
+    }
+
+    fn texts(answer: QaAnswer) -> Vec<String> {
+        match answer {
+            QaAnswer::Answer(candidates) => candidates.into_iter().map(|candidate| candidate.text).collect(),
+            QaAnswer::NoAnswer => Vec::new(),
+            _ => panic!("the question was not answered"),
+        }
+    }
+
+    #[tokio::test]
+    async fn answers_queued_questions() {
+        let model = FakeModel {
+            candidates: vec![("a report bot", 0.9)],
+            ..FakeModel::default()
+        };
+        let qa = started(&config(), model).await;
+
+        assert_eq!(texts(ask(&qa).await.unwrap()), vec!["a report bot"]);
+        assert_eq!(texts(ask(&qa).await.unwrap()), vec!["a report bot"]);
+    }
+
+    // the test blocks on the model while the runtime keeps asking
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn waiting_questions_are_answered_in_one_batch() {
+        let config = QaConfig {
+            batch_size: 3,
+            ..config()
+        };
+        let (model, started_batch, go) = gated(vec![("yes", 0.9)]);
+        let batches = Arc::clone(&model.batches);
+        let qa = started(&config, model).await;
+
+        // the worker is busy with the first question while four more wait
+        let first = ask(&qa);
+        started_batch.recv().unwrap();
+        let waiting: Vec<_> = (0..4).map(|_| ask(&qa)).collect();
+        wait_for(|| queued(&qa, &config) == 4).await;
+        drop(go);
+
+        assert!(matches!(first.await.unwrap(), QaAnswer::Answer(_)));
+        for question in waiting {
+            assert!(matches!(question.await.unwrap(), QaAnswer::Answer(_)));
+        }
+        let sizes: Vec<usize> = batches.lock().unwrap().iter().map(|(size, _)| *size).collect();
+        assert_eq!(sizes, vec![1, 3, 1]);
+    }
+
+    // the test blocks on the model while the runtime keeps asking
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn turns_questions_away_when_the_queue_is_full() {
+        let config = QaConfig {
+            queue_size: 1,
+            ..config()
+        };
+        let (model, started_batch, go) = gated(vec![("yes", 0.9)]);
+        let qa = started(&config, model).await;
+
+        let first = ask(&qa);
+        started_batch.recv().unwrap();
+        let second = ask(&qa);
+        wait_for(|| queued(&qa, &config) == 1).await;
+
+        assert!(matches!(qa.answer("what?".to_string(), String::new()).await, QaAnswer::Busy));
+        drop(go);
+        assert!(matches!(first.await.unwrap(), QaAnswer::Answer(_)));
+        assert!(matches!(second.await.unwrap(), QaAnswer::Answer(_)));
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_a_slow_model() {
+        let config = QaConfig {
+            timeout: Duration::from_millis(50),
+            ..config()
+        };
+        let (model, _started_batch, go) = gated(vec![("yes", 0.9)]);
+        let qa = started(&config, model).await;
+
+        assert!(matches!(ask(&qa).await.unwrap(), QaAnswer::TimedOut));
+        drop(go);
+    }
+
+    #[tokio::test]
+    async fn keeps_candidates_above_the_threshold_best_first() {
+        let config = QaConfig {
+            score_threshold: 0.1,
+            ..config()
+        };
+        let model = FakeModel {
+            candidates: vec![("maybe", 0.4), ("unlikely", 0.05), ("surely", 0.8)],
+            ..FakeModel::default()
+        };
+        let qa = started(&config, model).await;
+
+        assert_eq!(texts(ask(&qa).await.unwrap()), vec!["surely", "maybe"]);
+    }
+
+    #[tokio::test]
+    async fn no_answer_when_every_candidate_is_below_the_threshold() {
+        let model = FakeModel {
+            candidates: vec![("unlikely", 0.05), ("hardly", 0.01)],
+            ..FakeModel::default()
+        };
+        let qa = started(&config(), model).await;
+
+        assert!(matches!(ask(&qa).await.unwrap(), QaAnswer::NoAnswer));
+    }
+
+    #[tokio::test]
+    async fn asks_the_model_for_top_n_candidates() {
+        let config = QaConfig { top_n: 5, ..config() };
+        let model = FakeModel {
+            candidates: vec![("yes", 0.9)],
+            ..FakeModel::default()
+        };
+        let batches = Arc::clone(&model.batches);
+        let qa = started(&config, model).await;
+
+        ask(&qa).await.unwrap();
+        assert_eq!(*batches.lock().unwrap(), vec![(1, 5)]);
+    }
+
+    #[tokio::test]
+    async fn loading_until_a_worker_has_the_model() {
+        let (go, wait) = std_mpsc::channel::<()>();
+        let wait = Mutex::new(wait);
+        let qa = Qa::start(&config(), move || {
+            let _ = wait.lock().unwrap().recv();
+            Ok(FakeModel::default())
+        });
+
+        assert!(matches!(qa.answer("what?".to_string(), String::new()).await, QaAnswer::Loading));
+        assert!(!qa.is_ready());
+        drop(go);
+        wait_for(|| qa.is_ready()).await;
+    }
+
+    #[tokio::test]
+    async fn fails_once_every_worker_failed_to_load() {
+        let config = QaConfig { workers: 3, ..config() };
+        let qa = Qa::start(&config, || Err::<FakeModel, _>("no model".to_string()));
+
+        wait_for(|| *qa.state.read().unwrap() == ModelState::Failed).await;
+        assert!(!qa.is_ready());
+        assert!(matches!(qa.answer("what?".to_string(), String::new()).await, QaAnswer::Disabled));
+    }
+}
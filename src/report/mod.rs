@@ -0,0 +1,508 @@
+use chrono::{DateTime, Duration, NaiveDate, NaiveTime, TimeZone, Utc};
+use std::collections::HashMap;
+use serde::Serialize;
+use std::error::Error;
+use utoipa::ToSchema;
+
+use crate::chatbot::llm::Llm;
+use crate::classify::Classifier;
+use crate::gitlab::{Commit, GitlabUser, MergeRequest};
+use crate::i18n::{Lang, Text};
+use crate::summary::Summarizer;
+
+// telegram refuses messages longer than this many characters
+const MESSAGE_LIMIT: usize = 4096;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReportError {
+    #[error("invalid date `{0}`, use YYYY-MM-DD")]
+    InvalidDate(String),
+    #[error("the range starts after it ends")]
+    ReversedRange,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+pub struct DateRange {
+    // first day of the range, inclusive
+    pub from: NaiveDate,
+    // last day of the range, inclusive
+    pub to: NaiveDate,
+}
+
+impl DateRange {
+    pub fn day(date: NaiveDate) -> DateRange {
+        DateRange { from: date, to: date }
+    }
+
+    /// parses `today`, `yesterday`, `week`, `YYYY-MM-DD` or `YYYY-MM-DD..YYYY-MM-DD`,
+    /// an empty input means today
+    pub fn parse(input: &str, today: NaiveDate) -> Result<DateRange, ReportError> {
+        let input = input.trim().to_lowercase();
+
+        match input.as_str() {
+            "" | "today" => Ok(DateRange::day(today)),
+            "yesterday" => Ok(DateRange::day(today - Duration::days(1))),
+            "week" => Ok(DateRange {
+                from: today - Duration::days(6),
+                to: today,
+            }),
+            _ => match input.split_once("..") {
+                Some((from, to)) => {
+                    let range = DateRange {
+                        from: parse_date(from)?,
+                        to: parse_date(to)?,
+                    };
+                    if range.from > range.to {
+                        return Err(ReportError::ReversedRange);
+                    }
+                    Ok(range)
+                }
+                None => Ok(DateRange::day(parse_date(&input)?)),
+            },
+        }
+    }
+
+    pub fn since(&self) -> DateTime<Utc> {
+        Utc.from_utc_datetime(&self.from.and_time(NaiveTime::MIN))
+    }
+
+    pub fn until(&self) -> DateTime<Utc> {
+        Utc.from_utc_datetime(&(self.to + Duration::days(1)).and_time(NaiveTime::MIN))
+    }
+}
+
+impl ReportError {
+    pub fn localize(&self, lang: Lang) -> String {
+        match self {
+            ReportError::InvalidDate(date) => Text::InvalidDate.fmt(lang, &[date]),
+            ReportError::ReversedRange => Text::ReversedRange.get(lang).to_string(),
+        }
+    }
+}
+
+fn parse_date(input: &str) -> Result<NaiveDate, ReportError> {
+    NaiveDate::parse_from_str(input.trim(), "%Y-%m-%d")
+        .map_err(|_| ReportError::InvalidDate(input.trim().to_string()))
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct RepositoryReport {
+    pub name: String,
+    pub commits: Vec<Commit>,
+    // merge requests updated in the range, only read by the summary
+    pub merge_requests: Vec<MergeRequest>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Report {
+    pub range: DateRange,
+    pub repositories: Vec<RepositoryReport>,
+    // paragraph shown above the commit list when a summarisation model is available
+    pub summary: Option<String>,
+    // work category of each commit SHA, the report is grouped by category when set
+    pub categories: HashMap<String, String>,
+}
+
+impl Report {
+    pub fn commit_count(&self) -> usize {
+        self.repositories.iter().map(|repo| repo.commits.len()).sum()
+    }
+
+    pub fn render(&self, lang: Lang) -> String {
+        let mut message = if self.range.from == self.range.to {
+            Text::ReportHeading.fmt(lang, &[&lang.format_date(self.range.from)])
+        } else {
+            Text::ReportRangeHeading.fmt(
+                lang,
+                &[&lang.format_date(self.range.from), &lang.format_date(self.range.to)],
+            )
+        };
+        message.push_str("\n\n");
+
+        if let Some(summary) = &self.summary {
+            message.push_str(&format!("{}\n\n", summary));
+        }
+
+        if self.commit_count() == 0 {
+            message.push_str(Text::NoCommits.get(lang));
+            return message;
+        }
+
+        if !self.categories.is_empty() {
+            self.render_categories(lang, &mut message);
+            return message;
+        }
+
+        for repo in &self.repositories {
+            message.push_str(&format!("{}\n", repo.name));
+            for commit in &repo.commits {
+                message.push_str(&format!("- {} ({})\n", commit.title, commit.short_id));
+            }
+            message.push('\n');
+        }
+
+        message
+    }
+
+    /// one section per category, largest first, followed by the share of commits in each
+    fn render_categories(&self, lang: Lang, message: &mut String) {
+        let mut sections: Vec<(String, Vec<(&str, &Commit)>)> = Vec::new();
+        for repo in &self.repositories {
+            for commit in &repo.commits {
+                let category = match self.categories.get(&commit.id) {
+                    Some(category) => category_label(category, lang),
+                    None => Text::ReportUncategorized.get(lang).to_string(),
+                };
+                match sections.iter_mut().find(|(name, _)| *name == category) {
+                    Some((_, commits)) => commits.push((&repo.name, commit)),
+                    None => sections.push((category, vec![(&repo.name, commit)])),
+                }
+            }
+        }
+        sections.sort_by_key(|section| std::cmp::Reverse(section.1.len()));
+
+        for (category, commits) in &sections {
+            message.push_str(&format!("{}\n", category));
+            for (repo, commit) in commits {
+                message.push_str(&format!("- {} ({}, {})\n", commit.title, repo, commit.short_id));
+            }
+            message.push('\n');
+        }
+
+        // the share of commits is the best proxy for time we have
+        let total = self.commit_count();
+        let allocation = sections
+            .iter()
+            .map(|(category, commits)| format!("{} {}%", category, (commits.len() * 100 + total / 2) / total))
+            .collect::<Vec<_>>()
+            .join(", ");
+        message.push_str(&Text::ReportAllocation.fmt(lang, &[&allocation]));
+        message.push('\n');
+    }
+
+    /// labels every commit with its work category
+    pub async fn classify(&mut self, classifier: &Classifier) {
+        let commits: Vec<&Commit> = self.repositories.iter().flat_map(|repo| &repo.commits).collect();
+        self.categories = classifier.classify(&commits).await;
+    }
+
+    /// puts a summary above the commit list, the report stays a plain list when
+    /// no model is available or the summary fails
+    pub async fn summarize(&mut self, summarizer: &Summarizer) {
+        if self.commit_count() == 0 || !summarizer.is_enabled() {
+            return;
+        }
+        self.summary = summarizer.summarize(self.summary_input()).await;
+    }
+
+    /// the commit titles and merge request descriptions the summary is written from
+    fn summary_input(&self) -> String {
+        let mut input = String::new();
+
+        for repo in &self.repositories {
+            for commit in &repo.commits {
+                input.push_str(&format!("{}: {}.\n", repo.name, commit.title.trim_end_matches('.')));
+            }
+            for merge_request in &repo.merge_requests {
+                input.push_str(&format!("{}: {}.", repo.name, merge_request.title.trim_end_matches('.')));
+                if let Some(description) = &merge_request.description {
+                    input.push_str(&format!(" {}", description.trim()));
+                }
+                input.push('\n');
+            }
+        }
+
+        input
+    }
+}
+
+/// the name a category is shown with, the built-in categories are translated while
+/// categories added through `COMMIT_CATEGORIES` are shown as configured
+fn category_label(category: &str, lang: Lang) -> String {
+    let text = match category {
+        "feature" => Text::CategoryFeature,
+        "bugfix" => Text::CategoryBugfix,
+        "refactor" => Text::CategoryRefactor,
+        "docs" => Text::CategoryDocs,
+        "infra" => Text::CategoryInfra,
+        "test" => Text::CategoryTest,
+        _ => return category.to_string(),
+    };
+
+    text.get(lang).to_string()
+}
+
+/// the models that turn a report into the text sent to a chat, shared by the
+/// `/report` command, scheduled reports and the HTTP API
+#[derive(Clone)]
+pub struct ReportWriter {
+    pub summarizer: Summarizer,
+    pub classifier: Classifier,
+    pub llm: Llm,
+}
+
+impl ReportWriter {
+    pub fn from_env() -> ReportWriter {
+        ReportWriter {
+            summarizer: Summarizer::from_env(),
+            classifier: Classifier::from_env(),
+            llm: Llm::from_env(),
+        }
+    }
+
+    /// categorises and, when `summary` is set, summarises the report, then returns it
+    /// rephrased by the LLM or rendered as a plain list
+    pub async fn write(&self, report: &mut Report, lang: Lang, summary: bool) -> String {
+        report.classify(&self.classifier).await;
+        if summary {
+            report.summarize(&self.summarizer).await;
+        }
+
+        let rendered = report.render(lang);
+        match self.llm.phrase_report(&rendered, lang).await {
+            Some(text) => text,
+            None => rendered,
+        }
+    }
+}
+
+/// collects the user's own commits in every repository they are a member of within the range,
+/// used both by the `/report` command and scheduled reports
+pub async fn generate(
+    user: &GitlabUser,
+    range: DateRange,
+) -> Result<Report, Box<dyn Error + Send + Sync>> {
+    // members' projects are shared, only the token owner's work belongs in their report
+    let account = user.account().await?;
+    let mut repositories = Vec::new();
+
+    for repo in user.get_repositories().await? {
+        let commits = user
+            .get_commits_between(repo.id, range.since(), range.until(), Some(account.commit_author()))
+            .await?;
+        if !commits.is_empty() {
+            // the merge requests only feed the summary, the commits are reported without them
+            let merge_requests = match user
+                .get_merge_requests(repo.id, range.since(), range.until(), Some(account.id))
+                .await
+            {
+                Ok(merge_requests) => merge_requests,
+                Err(err) => {
+                    log::warn!("failed to read the merge requests of {}: {}", repo.name, err);
+                    Vec::new()
+                }
+            };
+            repositories.push(RepositoryReport {
+                name: repo.name,
+                commits,
+                merge_requests,
+            });
+        }
+    }
+
+    Ok(Report {
+        range,
+        repositories,
+        summary: None,
+        categories: HashMap::new(),
+    })
+}
+
+/// splits a long text on line boundaries so every part fits in one telegram message
+pub fn split_message(text: &str) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut current = String::new();
+
+    for line in text.lines() {
+        if !current.is_empty() && current.chars().count() + line.chars().count() + 1 > MESSAGE_LIMIT {
+            parts.push(std::mem::take(&mut current));
+        }
+        // a single line can be longer than the limit on its own, a piece that fills
+        // a whole message is sent without its newline
+        let chars: Vec<char> = line.chars().collect();
+        let mut pieces: Vec<String> = chars.chunks(MESSAGE_LIMIT).map(|piece| piece.iter().collect()).collect();
+        let last = pieces.pop().unwrap_or_default();
+        parts.extend(pieces);
+        if last.chars().count() == MESSAGE_LIMIT {
+            parts.push(last);
+        } else {
+            current.push_str(&last);
+            current.push('\n');
+        }
+    }
+
+    if !current.trim().is_empty() {
+        parts.push(current);
+    }
+
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(input: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(input, "%Y-%m-%d").unwrap()
+    }
+
+    #[test]
+    fn parses_named_ranges() {
+        let today = date("2023-07-12");
+
+        assert_eq!(DateRange::parse("", today).unwrap(), DateRange::day(today));
+        assert_eq!(DateRange::parse(" Today ", today).unwrap(), DateRange::day(today));
+        assert_eq!(DateRange::parse("yesterday", today).unwrap(), DateRange::day(date("2023-07-11")));
+        assert_eq!(
+            DateRange::parse("week", today).unwrap(),
+            DateRange {
+                from: date("2023-07-06"),
+                to: today,
+            }
+        );
+    }
+
+    #[test]
+    fn parses_dates_and_date_ranges() {
+        let today = date("2023-07-12");
+
+        assert_eq!(DateRange::parse("2023-07-01", today).unwrap(), DateRange::day(date("2023-07-01")));
+        assert_eq!(
+            DateRange::parse("2023-07-01..2023-07-03", today).unwrap(),
+            DateRange {
+                from: date("2023-07-01"),
+                to: date("2023-07-03"),
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_ranges() {
+        let today = date("2023-07-12");
+
+        assert!(matches!(
+            DateRange::parse("tomorrow", today),
+            Err(ReportError::InvalidDate(input)) if input == "tomorrow"
+        ));
+        assert!(matches!(
+            DateRange::parse("2023-07-01..july", today),
+            Err(ReportError::InvalidDate(input)) if input == "july"
+        ));
+        assert!(matches!(
+            DateRange::parse("2023-07-03..2023-07-01", today),
+            Err(ReportError::ReversedRange)
+        ));
+    }
+
+    #[test]
+    fn range_covers_whole_days() {
+        let range = DateRange::parse("2023-07-01..2023-07-03", date("2023-07-12")).unwrap();
+
+        assert_eq!(range.since().to_rfc3339(), "2023-07-01T00:00:00+00:00");
+        assert_eq!(range.until().to_rfc3339(), "2023-07-04T00:00:00+00:00");
+    }
+
+    #[test]
+    fn short_messages_are_not_split() {
+        assert_eq!(split_message("one\ntwo"), vec!["one\ntwo\n".to_string()]);
+        assert!(split_message("").is_empty());
+    }
+
+    #[test]
+    fn long_messages_are_split_on_lines() {
+        let line = "x".repeat(3000);
+        let text = format!("{}\n{}\n{}", line, line, line);
+
+        let parts = split_message(&text);
+        assert_eq!(parts.len(), 3);
+        assert!(parts.iter().all(|part| part.chars().count() <= MESSAGE_LIMIT));
+        assert_eq!(parts.concat(), format!("{}\n", text));
+    }
+
+    #[test]
+    fn long_lines_are_cut() {
+        let line = "é".repeat(MESSAGE_LIMIT * 2 + 10);
+
+        let parts = split_message(&line);
+        assert_eq!(parts.len(), 3);
+        assert!(parts.iter().all(|part| part.chars().count() <= MESSAGE_LIMIT));
+        assert_eq!(parts.concat().trim_end(), line);
+    }
+
+    #[test]
+    fn lines_filling_a_message_leave_room_for_the_newline() {
+        let line = "x".repeat(MESSAGE_LIMIT);
+        let text = format!("{}\nnext", line);
+
+        let parts = split_message(&text);
+        assert_eq!(parts, vec![line, "next\n".to_string()]);
+        assert_eq!(split_message(&"x".repeat(MESSAGE_LIMIT - 1)), vec![format!("{}\n", "x".repeat(MESSAGE_LIMIT - 1))]);
+    }
+
+    fn categorized(categories: &[Option<&str>]) -> Report {
+        let commits: Vec<Commit> = (0..categories.len())
+            .map(|index| Commit {
+                id: format!("sha{}", index),
+                short_id: format!("s{}", index),
+                title: format!("commit {}", index),
+                ..Commit::default()
+            })
+            .collect();
+        let labels = categories
+            .iter()
+            .enumerate()
+            .filter_map(|(index, category)| category.map(|category| (format!("sha{}", index), category.to_string())))
+            .collect();
+
+        Report {
+            range: DateRange::day(date("2023-07-12")),
+            repositories: vec![RepositoryReport {
+                name: "digireport".to_string(),
+                commits,
+                merge_requests: Vec::new(),
+            }],
+            summary: None,
+            categories: labels,
+        }
+    }
+
+    fn allocation(rendered: &str) -> &str {
+        rendered.lines().last().unwrap()
+    }
+
+    #[test]
+    fn groups_commits_by_category_largest_first() {
+        let report = categorized(&[Some("bugfix"), Some("feature"), Some("feature")]);
+
+        let rendered = report.render(Lang::En);
+        let feature = rendered.find("feature\n- commit 1 (digireport, s1)\n- commit 2 (digireport, s2)\n").unwrap();
+        let bugfix = rendered.find("bugfix\n- commit 0 (digireport, s0)\n").unwrap();
+        assert!(feature < bugfix);
+        assert_eq!(allocation(&rendered), "Time allocation: feature 67%, bugfix 33%");
+    }
+
+    #[test]
+    fn rounds_allocation_to_the_nearest_percent() {
+        // 1/8 is 12.5%, 7/8 is 87.5%
+        let mut categories = vec![Some("feature"); 7];
+        categories.push(Some("docs"));
+
+        let rendered = categorized(&categories).render(Lang::En);
+        assert_eq!(allocation(&rendered), "Time allocation: feature 88%, docs 13%");
+    }
+
+    #[test]
+    fn uncategorized_commits_go_to_other() {
+        let rendered = categorized(&[Some("test"), None, None, Some("test")]).render(Lang::En);
+
+        assert!(rendered.contains("other\n- commit 1 (digireport, s1)\n- commit 2 (digireport, s2)\n"));
+        assert_eq!(allocation(&rendered), "Time allocation: test 50%, other 50%");
+    }
+
+    #[test]
+    fn translates_built_in_categories_only() {
+        let rendered = categorized(&[Some("bugfix"), Some("security"), None]).render(Lang::Id);
+
+        assert_eq!(allocation(&rendered), "Alokasi waktu: perbaikan bug 33%, security 33%, lainnya 33%");
+    }
+}
@@ -0,0 +1,120 @@
+use chrono::{Duration, NaiveTime, Utc};
+use std::env;
+use std::sync::{Arc, RwLock};
+use teloxide::prelude::*;
+
+use crate::context;
+use crate::draft::{self, Delivery, DeliveryStatus, Draft, DraftTimers};
+use crate::metrics;
+use crate::report::{self, DateRange, ReportWriter};
+use crate::shutdown::Shutdown;
+
+// default time of day (UTC) the daily reports are sent at
+const DEFAULT_REPORT_TIME: &str = "17:00";
+
+/// the time of day (UTC) of the daily reports, `REPORT_TIME`
+pub fn report_time() -> NaiveTime {
+    time_from_env("REPORT_TIME", DEFAULT_REPORT_TIME)
+}
+
+/// reads a time of day (HH:MM, UTC) from the environment variable `name`
+pub fn time_from_env(name: &str, default: &str) -> NaiveTime {
+    let value = env::var(name).unwrap_or_else(|_| default.to_string());
+
+    match NaiveTime::parse_from_str(&value, "%H:%M") {
+        Ok(time) => time,
+        Err(_) => {
+            log::warn!("invalid {} `{}`, using {}", name, value, default);
+            NaiveTime::parse_from_str(default, "%H:%M").unwrap()
+        }
+    }
+}
+
+/// how long to wait from now until the next occurrence of `time`
+pub fn until_next(time: NaiveTime) -> std::time::Duration {
+    let now = Utc::now().naive_utc();
+    let mut next = now.date().and_time(time);
+    if next <= now {
+        next += Duration::days(1);
+    }
+
+    (next - now).to_std().unwrap_or_default()
+}
+
+/// drafts every user's report for the day once a day, approved drafts go to their linked channel,
+/// a run that started is finished on shutdown
+pub async fn run(
+    bot: Bot,
+    ctxt: Arc<RwLock<context::Context>>,
+    writer: ReportWriter,
+    timers: DraftTimers,
+    shutdown: Shutdown,
+) {
+    let time = report_time();
+    log::info!("Daily reports scheduled at {} UTC", time);
+
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(until_next(time)) => {}
+            _ = shutdown.wait() => break,
+        }
+        send_daily_reports(&bot, &ctxt, &writer, &timers).await;
+    }
+    log::info!("Daily reports stopped");
+}
+
+pub async fn send_daily_reports(
+    bot: &Bot,
+    ctxt: &Arc<RwLock<context::Context>>,
+    writer: &ReportWriter,
+    timers: &DraftTimers,
+) {
+    let range = DateRange::day(Utc::now().date_naive());
+    let targets = ctxt.read().unwrap().report_targets();
+
+    for (user_id, gitlab_user, channel) in targets {
+        let delivery = |draft, commits, status| Delivery {
+            draft,
+            author: user_id,
+            channel,
+            date: range.from,
+            commits,
+            status,
+            updated_at: Utc::now(),
+        };
+
+        let mut report = match report::generate(&gitlab_user, range).await {
+            Ok(report) => report,
+            Err(err) => {
+                log::error!("failed to generate report for {}: {}", user_id, err);
+                record(ctxt, delivery(None, 0, DeliveryStatus::Failed));
+                continue;
+            }
+        };
+
+        // the channel decides whether its reports are summarised
+        let (lang, summary) = {
+            let ctxt = ctxt.read().unwrap();
+            (ctxt.user_lang(user_id), ctxt.summary_enabled(channel))
+        };
+        let text = writer.write(&mut report, lang, summary).await;
+
+        // the author reviews the report before it reaches the channel
+        let draft = Draft::new(user_id, channel, text);
+        let commits = report.commit_count();
+        match draft::propose(bot, ctxt, timers, draft).await {
+            Ok(id) => record(ctxt, delivery(Some(id), commits, DeliveryStatus::AwaitingReview)),
+            Err(err) => {
+                log::error!("failed to send draft report to {}: {}", user_id, err);
+                record(ctxt, delivery(None, commits, DeliveryStatus::Failed));
+            }
+        }
+    }
+}
+
+/// counts a report in the metrics and keeps it for the dashboard, for every report
+/// sent on schedule or on request
+pub fn record(ctxt: &Arc<RwLock<context::Context>>, delivery: Delivery) {
+    metrics::report_sent(delivery.status != DeliveryStatus::Failed);
+    ctxt.write().unwrap().add_delivery(delivery);
+}
@@ -1,32 +1,384 @@
+use std::env;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::PathBuf;
 use std::sync::{Arc, RwLock};
 
 use actix_web::{middleware, App, HttpServer};
+use teloxide::Bot;
 
+use crate::auth::ApiKeys;
+use crate::events::EventBus;
+use crate::notify::Notifier;
 use crate::{controller, context};
+use crate::qa::Qa;
+use crate::report::ReportWriter;
+use crate::shutdown::{self, Shutdown};
+use crate::webhook::Webhook;
 
-pub async fn warp_server(
-    ctxt: Arc<RwLock<context::Context>>,
-) -> () {
+const DEFAULT_HOST: &str = "127.0.0.1";
+const DEFAULT_PORT: u16 = 8077;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ServerError {
+    #[error("invalid server configuration: {0}")]
+    Config(String),
+    #[error("failed to read {path}: {source}")]
+    Tls { path: PathBuf, source: io::Error },
+    #[error("invalid TLS certificate or key: {0}")]
+    InvalidTls(String),
+    #[error("failed to bind {address}: {source}")]
+    Bind { address: String, source: io::Error },
+    #[error("the server stopped with an error: {0}")]
+    Run(#[from] io::Error),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BindAddress {
+    // host:port, served over TLS when a certificate is configured
+    Tcp(String),
+    // path of a Unix domain socket, always plain HTTP
+    Unix(PathBuf),
+}
+
+impl std::fmt::Display for BindAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BindAddress::Tcp(address) => write!(f, "{}", address),
+            BindAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    pub addresses: Vec<BindAddress>,
+    // None uses one worker per CPU core
+    pub workers: Option<usize>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+}
+
+impl ServerConfig {
+    /// reads `HTTP_BIND`, a comma separated list of `host:port` and `unix:/path` addresses,
+    /// or `HTTP_HOST` and `HTTP_PORT` for a single one, `HTTP_WORKERS`, and
+    /// `TLS_CERT_PATH` with `TLS_KEY_PATH` (PEM) to serve TCP addresses over HTTPS
+    pub fn from_env() -> Result<ServerConfig, ServerError> {
+        let addresses = match env::var("HTTP_BIND") {
+            Ok(bind) => bind
+                .split(',')
+                .map(str::trim)
+                .filter(|address| !address.is_empty())
+                .map(|address| match address.strip_prefix("unix:") {
+                    Some(path) => BindAddress::Unix(PathBuf::from(path)),
+                    None => BindAddress::Tcp(address.to_string()),
+                })
+                .collect(),
+            Err(_) => {
+                let host = env::var("HTTP_HOST").unwrap_or_else(|_| DEFAULT_HOST.to_string());
+                let port = match env::var("HTTP_PORT") {
+                    Ok(port) => port
+                        .parse::<u16>()
+                        .map_err(|_| ServerError::Config(format!("invalid HTTP_PORT `{}`", port)))?,
+                    Err(_) => DEFAULT_PORT,
+                };
+                vec![BindAddress::Tcp(format!("{}:{}", host, port))]
+            }
+        };
+        if addresses.is_empty() {
+            return Err(ServerError::Config("HTTP_BIND has no addresses".to_string()));
+        }
+
+        let workers = match env::var("HTTP_WORKERS") {
+            Ok(workers) => match workers.parse::<usize>() {
+                Ok(workers) if workers > 0 => Some(workers),
+                _ => return Err(ServerError::Config(format!("invalid HTTP_WORKERS `{}`", workers))),
+            },
+            Err(_) => None,
+        };
+
+        let tls_cert = env::var("TLS_CERT_PATH").ok().map(PathBuf::from);
+        let tls_key = env::var("TLS_KEY_PATH").ok().map(PathBuf::from);
+        if tls_cert.is_some() != tls_key.is_some() {
+            return Err(ServerError::Config(
+                "TLS_CERT_PATH and TLS_KEY_PATH must be set together".to_string(),
+            ));
+        }
+
+        Ok(ServerConfig {
+            addresses,
+            workers,
+            tls_cert,
+            tls_key,
+        })
+    }
+
+    /// loads the certificate chain and private key, None when TLS is not configured
+    fn rustls_config(&self) -> Result<Option<rustls::ServerConfig>, ServerError> {
+        let (cert_path, key_path) = match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => (cert, key),
+            _ => return Ok(None),
+        };
+        let open = |path: &PathBuf| {
+            File::open(path)
+                .map(BufReader::new)
+                .map_err(|source| ServerError::Tls {
+                    path: path.clone(),
+                    source,
+                })
+        };
 
+        let certs: Vec<rustls::Certificate> = rustls_pemfile::certs(&mut open(cert_path)?)
+            .map_err(|source| ServerError::Tls {
+                path: cert_path.clone(),
+                source,
+            })?
+            .into_iter()
+            .map(rustls::Certificate)
+            .collect();
+        if certs.is_empty() {
+            return Err(ServerError::InvalidTls(format!("no certificate in {}", cert_path.display())));
+        }
 
+        let key = rustls_pemfile::read_all(&mut open(key_path)?)
+            .map_err(|source| ServerError::Tls {
+                path: key_path.clone(),
+                source,
+            })?
+            .into_iter()
+            .find_map(|item| match item {
+                rustls_pemfile::Item::PKCS8Key(key)
+                | rustls_pemfile::Item::RSAKey(key)
+                | rustls_pemfile::Item::ECKey(key) => Some(rustls::PrivateKey(key)),
+                _ => None,
+            })
+            .ok_or_else(|| ServerError::InvalidTls(format!("no private key in {}", key_path.display())))?;
 
-   match HttpServer::new(move || {
-        App::new()
+        rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map(Some)
+            .map_err(|err| ServerError::InvalidTls(err.to_string()))
+    }
+}
+
+/// a socket left behind by an earlier run makes binding fail
+#[cfg(unix)]
+fn remove_stale_socket(path: &PathBuf) {
+    use std::os::unix::fs::FileTypeExt;
+
+    let is_socket = std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket());
+    if is_socket {
+        if let Err(err) = std::fs::remove_file(path) {
+            log::warn!("failed to remove stale socket {}: {}", path.display(), err);
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn warp_server(
+    ctxt: Arc<RwLock<context::Context>>,
+    bot: Bot,
+    writer: ReportWriter,
+    keys: ApiKeys,
+    qa: Qa,
+    bus: EventBus,
+    notifier: Notifier,
+    webhook: Option<Webhook>,
+    config: ServerConfig,
+    shutdown: Shutdown,
+) -> Result<(), ServerError> {
+    let tls = config.rustls_config()?;
+    let stop = shutdown.clone();
+
+    let mut server = HttpServer::new(move || {
+        let app = App::new()
             .app_data(
                 actix_web::web::Data::new(
                     ctxt.clone()
                 )
             )
+            .app_data(actix_web::web::Data::new(bot.clone()))
+            .app_data(actix_web::web::Data::new(writer.clone()))
+            .app_data(actix_web::web::Data::new(keys.clone()))
+            .app_data(actix_web::web::Data::new(qa.clone()))
+            .app_data(actix_web::web::Data::new(bus.clone()))
+            .app_data(actix_web::web::Data::new(notifier.clone()))
+            .app_data(actix_web::web::Data::new(shutdown.clone()))
             .wrap(middleware::Logger::default())
             .service(controller::index)
-    })
-    .bind(("127.0.0.1", 8077)) {
-        Ok(s) => {
-            match s.run().await {
-                Ok(rn) => rn,
-                Err(e) => panic!("server")
+            .configure(controller::health::configure)
+            .configure(controller::dashboard::configure)
+            .service(controller::openapi::openapi_json)
+            .service(controller::openapi::docs)
+            // signed instead of authenticated, so it comes before the `/api/v1` scope
+            .service(controller::notify::notify)
+            .configure(controller::api::configure)
+            .service(controller::events::gitlab_hook);
+
+        // the update endpoint only exists in webhook mode
+        match &webhook {
+            Some(webhook) => app
+                .app_data(actix_web::web::Data::new(webhook.clone()))
+                .service(controller::telegram_webhook),
+            None => app,
+        }
+    });
+    // signals are handled by `shutdown` for the whole app
+    server = server
+        .disable_signals()
+        .shutdown_timeout(shutdown::drain_timeout().as_secs());
+    if let Some(workers) = config.workers {
+        server = server.workers(workers);
+    }
+
+    for address in &config.addresses {
+        let bound = match (address, &tls) {
+            (BindAddress::Tcp(tcp), Some(tls)) => server.bind_rustls(tcp.as_str(), tls.clone()),
+            (BindAddress::Tcp(tcp), None) => server.bind(tcp.as_str()),
+            #[cfg(unix)]
+            (BindAddress::Unix(path), _) => {
+                remove_stale_socket(path);
+                server.bind_uds(path)
             }
-        }, 
-        Err(e) => panic!("server vault")
+            #[cfg(not(unix))]
+            (BindAddress::Unix(_), _) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Unix sockets are not supported on this platform",
+            )),
+        };
+        server = bound.map_err(|source| ServerError::Bind {
+            address: address.to_string(),
+            source,
+        })?;
+        log::info!(
+            "HTTP server listening on {}{}",
+            address,
+            if tls.is_some() && matches!(address, BindAddress::Tcp(_)) { " (TLS)" } else { "" }
+        );
+    }
+
+    let server = server.run();
+    // stops accepting connections and gives the requests in flight time to finish
+    let handle = server.handle();
+    tokio::spawn(async move {
+        stop.wait().await;
+        handle.stop(true).await;
+    });
+    server.await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    // the tests share the process environment
+    static ENV: Mutex<()> = Mutex::new(());
+
+    const VARS: [&str; 6] = ["HTTP_BIND", "HTTP_HOST", "HTTP_PORT", "HTTP_WORKERS", "TLS_CERT_PATH", "TLS_KEY_PATH"];
+
+    /// reads the configuration with only `vars` set
+    fn config(vars: &[(&str, &str)]) -> Result<ServerConfig, ServerError> {
+        let _env = ENV.lock().unwrap_or_else(|err| err.into_inner());
+        for var in VARS {
+            env::remove_var(var);
+        }
+        for (var, value) in vars {
+            env::set_var(var, value);
+        }
+        ServerConfig::from_env()
+    }
+
+    fn config_error(vars: &[(&str, &str)]) -> String {
+        match config(vars) {
+            Ok(config) => panic!("accepted {:?}", config),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    #[test]
+    fn defaults_to_the_local_port() {
+        let config = config(&[]).unwrap();
+
+        assert_eq!(config.addresses, vec![BindAddress::Tcp("127.0.0.1:8077".to_string())]);
+        assert_eq!(config.workers, None);
+        assert_eq!(config.tls_cert, None);
+    }
+
+    #[test]
+    fn reads_host_and_port() {
+        let config = config(&[("HTTP_HOST", "0.0.0.0"), ("HTTP_PORT", "9000"), ("HTTP_WORKERS", "4")]).unwrap();
+
+        assert_eq!(config.addresses, vec![BindAddress::Tcp("0.0.0.0:9000".to_string())]);
+        assert_eq!(config.workers, Some(4));
+    }
+
+    #[test]
+    fn binds_every_listed_address() {
+        let config = config(&[
+            ("HTTP_BIND", "127.0.0.1:8077, [::1]:8077,,unix:/run/digireport.sock"),
+            // ignored when HTTP_BIND is set
+            ("HTTP_PORT", "9000"),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            config.addresses,
+            vec![
+                BindAddress::Tcp("127.0.0.1:8077".to_string()),
+                BindAddress::Tcp("[::1]:8077".to_string()),
+                BindAddress::Unix(PathBuf::from("/run/digireport.sock")),
+            ]
+        );
+        assert_eq!(config.addresses[2].to_string(), "unix:/run/digireport.sock");
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        let invalid = |message: &str| format!("invalid server configuration: {}", message);
+
+        assert_eq!(config_error(&[("HTTP_BIND", " , ")]), invalid("HTTP_BIND has no addresses"));
+        assert_eq!(config_error(&[("HTTP_PORT", "80000")]), invalid("invalid HTTP_PORT `80000`"));
+        assert_eq!(config_error(&[("HTTP_WORKERS", "0")]), invalid("invalid HTTP_WORKERS `0`"));
+        assert_eq!(config_error(&[("HTTP_WORKERS", "many")]), invalid("invalid HTTP_WORKERS `many`"));
+    }
+
+    #[test]
+    fn needs_both_the_certificate_and_the_key() {
+        assert!(config_error(&[("TLS_CERT_PATH", "cert.pem")]).contains("must be set together"));
+        assert!(config_error(&[("TLS_KEY_PATH", "key.pem")]).contains("must be set together"));
+
+        let config = config(&[("TLS_CERT_PATH", "cert.pem"), ("TLS_KEY_PATH", "key.pem")]).unwrap();
+        assert_eq!(config.tls_cert, Some(PathBuf::from("cert.pem")));
+        assert_eq!(config.tls_key, Some(PathBuf::from("key.pem")));
+    }
+
+    #[test]
+    fn reports_unreadable_or_empty_tls_files() {
+        let dir = env::temp_dir().join(format!("digireport-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let empty = dir.join("empty.pem");
+        std::fs::write(&empty, "").unwrap();
+        let tls = |cert: &PathBuf, key: &PathBuf| ServerConfig {
+            addresses: vec![BindAddress::Tcp("127.0.0.1:0".to_string())],
+            workers: None,
+            tls_cert: Some(cert.clone()),
+            tls_key: Some(key.clone()),
+        };
+
+        let missing = dir.join("missing.pem");
+        assert!(matches!(
+            tls(&missing, &empty).rustls_config(),
+            Err(ServerError::Tls { path, .. }) if path == missing
+        ));
+        assert!(matches!(tls(&empty, &empty).rustls_config(), Err(ServerError::InvalidTls(_))));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let plain = config(&[]).unwrap();
+        assert!(plain.rustls_config().unwrap().is_none());
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,82 @@
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::watch;
+
+// time given to in-flight deliveries and HTTP requests once a shutdown starts
+const DEFAULT_DRAIN_SECS: u64 = 30;
+
+/// the time to drain in-flight work, `SHUTDOWN_TIMEOUT_SECS`
+pub fn drain_timeout() -> Duration {
+    let secs = env::var("SHUTDOWN_TIMEOUT_SECS")
+        .ok()
+        .and_then(|value| value.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_DRAIN_SECS);
+
+    Duration::from_secs(secs)
+}
+
+/// tells the bot, the HTTP server and the background tasks to stop taking new work,
+/// shared by every part of the app and usable from any runtime
+#[derive(Clone)]
+pub struct Shutdown {
+    sender: Arc<watch::Sender<bool>>,
+    receiver: watch::Receiver<bool>,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        let (sender, receiver) = watch::channel(false);
+        Shutdown {
+            sender: Arc::new(sender),
+            receiver,
+        }
+    }
+
+    /// starts the shutdown, calling it again does nothing
+    pub fn trigger(&self) {
+        self.sender.send_replace(true);
+    }
+
+    /// returns once the shutdown started
+    pub async fn wait(&self) {
+        let mut receiver = self.receiver.clone();
+        // the sender lives in `self`, so the channel cannot close while waiting
+        let _ = receiver.wait_for(|triggered| *triggered).await;
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown::new()
+    }
+}
+
+/// triggers the shutdown on the first SIGINT or SIGTERM, a second one exits right away
+pub async fn listen(shutdown: Shutdown) {
+    let name = signal().await;
+    log::info!("received {}, shutting down", name);
+    shutdown.trigger();
+
+    let name = signal().await;
+    log::warn!("received {} again, exiting without draining", name);
+    std::process::exit(130);
+}
+
+#[cfg(unix)]
+async fn signal() -> &'static str {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+        _ = terminate.recv() => "SIGTERM",
+    }
+}
+
+#[cfg(not(unix))]
+async fn signal() -> &'static str {
+    let _ = tokio::signal::ctrl_c().await;
+    "Ctrl-C"
+}
@@ -0,0 +1,292 @@
+use chrono::{Duration, Utc};
+use std::env;
+use std::sync::{Arc, RwLock};
+use teloxide::dispatching::dialogue::InMemStorage;
+use teloxide::prelude::*;
+use teloxide::types::UserId;
+
+use crate::chatbot::State;
+use crate::context;
+use crate::i18n::{Lang, Text};
+use crate::report::{self, DateRange, Report};
+use crate::scheduler;
+use crate::shutdown::Shutdown;
+
+// default time of day (UTC) members are asked for their stand-up
+const DEFAULT_STANDUP_TIME: &str = "09:00";
+// minutes members have to answer before the digest is posted
+const DEFAULT_STANDUP_WINDOW: u64 = 30;
+
+#[derive(Clone, Debug)]
+pub struct StandupMember {
+    pub user_id: UserId,
+    pub name: String,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct StandupAnswer {
+    pub plans: String,
+    pub blockers: String,
+}
+
+fn standup_window() -> std::time::Duration {
+    let minutes = env::var("STANDUP_WINDOW_MINUTES")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_STANDUP_WINDOW);
+
+    std::time::Duration::from_secs(minutes * 60)
+}
+
+/// the time of day (UTC) of the stand-up, `STANDUP_TIME`
+pub fn standup_time() -> chrono::NaiveTime {
+    scheduler::time_from_env("STANDUP_TIME", DEFAULT_STANDUP_TIME)
+}
+
+/// every day at `STANDUP_TIME` asks the members of every team for their plans
+/// and blockers, then posts the digest to each team group
+pub async fn run(
+    bot: Bot,
+    ctxt: Arc<RwLock<context::Context>>,
+    storage: Arc<InMemStorage<State>>,
+    shutdown: Shutdown,
+) {
+    let time = standup_time();
+    log::info!("Stand-ups scheduled at {} UTC", time);
+
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(scheduler::until_next(time)) => {}
+            _ = shutdown.wait() => break,
+        }
+
+        start_round(&bot, &ctxt, &storage).await;
+        // the answers are only kept in memory, a round cut short posts what it has
+        tokio::select! {
+            _ = tokio::time::sleep(standup_window()) => {}
+            _ = shutdown.wait() => {}
+        }
+        post_digests(&bot, &ctxt).await;
+    }
+}
+
+/// clears yesterday's answers and prompts every member in a private chat, members in the
+/// middle of another exchange with the bot (registering, editing a draft) are left out
+pub async fn start_round(bot: &Bot, ctxt: &Arc<RwLock<context::Context>>, storage: &Arc<InMemStorage<State>>) {
+    let members = {
+        let mut ctxt = ctxt.write().unwrap();
+        ctxt.clear_standup_answers();
+        ctxt.standup_members()
+            .into_iter()
+            .map(|user_id| (user_id, ctxt.user_lang(user_id)))
+            .collect::<Vec<_>>()
+    };
+
+    for (user_id, lang) in members {
+        // the private chat with a user has the same ID as the user
+        let chat_id = ChatId(user_id.0 as i64);
+        let dialogue = Dialogue::new(Arc::clone(storage), chat_id);
+        let conversation = match dialogue.get().await {
+            Ok(None) | Ok(Some(State::Start)) => None,
+            Ok(Some(State::General { conversation })) => Some(conversation),
+            // yesterday's stand-up was left unanswered
+            Ok(Some(State::StandupPlans { conversation }))
+            | Ok(Some(State::StandupBlockers { conversation, .. })) => conversation,
+            Ok(Some(state)) => {
+                log::info!("not prompting {} for stand-up while in {:?}", user_id, state);
+                continue;
+            }
+            Err(err) => {
+                log::error!("failed to read dialogue of {}: {}", user_id, err);
+                continue;
+            }
+        };
+
+        if let Err(err) = bot
+            .send_message(chat_id, Text::StandupPrompt.get(lang))
+            .await
+        {
+            log::warn!("failed to prompt {} for stand-up: {}", user_id, err);
+            continue;
+        }
+        if let Err(err) = dialogue.update(State::StandupPlans { conversation }).await {
+            log::error!("failed to update dialogue of {}: {}", user_id, err);
+        }
+    }
+}
+
+/// what goes in a member's "done" section
+enum Done {
+    Report(Report),
+    // the member never registered a Gitlab token
+    NoToken,
+    Unavailable,
+}
+
+/// the part of the digest about one member, yesterday's commits and merge requests
+/// followed by their answer
+fn member_section(lang: Lang, name: &str, done: &Done, answer: Option<&StandupAnswer>) -> String {
+    let mut section = format!("{}\n{}\n", name, Text::StandupDone.get(lang));
+    match done {
+        Done::Report(report) => {
+            let done_before = section.len();
+            for repo in &report.repositories {
+                for commit in &repo.commits {
+                    section.push_str(&format!("- {} ({})\n", commit.title, repo.name));
+                }
+                for merge_request in &repo.merge_requests {
+                    section.push_str(&format!(
+                        "- {} ({} !{}, {})\n",
+                        merge_request.title, repo.name, merge_request.iid, merge_request.state
+                    ));
+                }
+            }
+            if section.len() == done_before {
+                section.push_str(&format!("{}\n", Text::StandupNoCommits.get(lang)));
+            }
+        }
+        Done::NoToken => section.push_str(&format!("{}\n", Text::StandupNoToken.get(lang))),
+        Done::Unavailable => section.push_str(&format!("{}\n", Text::StandupCommitsUnavailable.get(lang))),
+    }
+
+    match answer {
+        Some(answer) => section.push_str(&format!(
+            "{}\n{}\n{}\n{}\n\n",
+            Text::StandupToday.get(lang),
+            answer.plans,
+            Text::StandupBlockers.get(lang),
+            answer.blockers
+        )),
+        None => section.push_str(&format!("{}\n\n", Text::StandupNoAnswer.get(lang))),
+    }
+
+    section
+}
+
+/// posts one digest per team, using each member's own commits and merge requests of yesterday
+/// as the "done" section
+pub async fn post_digests(bot: &Bot, ctxt: &Arc<RwLock<context::Context>>) {
+    let today = Utc::now().date_naive();
+    let yesterday = DateRange::day(today - Duration::days(1));
+    let teams = ctxt.read().unwrap().standup_teams();
+
+    for (team, members) in teams {
+        let lang = ctxt.read().unwrap().lang(team, None);
+        let mut digest = format!("{}\n\n", Text::StandupHeading.fmt(lang, &[&lang.format_date(today)]));
+
+        for member in members {
+            let (gitlab_user, answer) = {
+                let ctxt = ctxt.read().unwrap();
+                (
+                    ctxt.get_gitlab_user(member.user_id).cloned(),
+                    ctxt.get_standup_answer(member.user_id).cloned(),
+                )
+            };
+
+            let done = match gitlab_user {
+                Some(gitlab_user) => match report::generate(&gitlab_user, yesterday).await {
+                    Ok(report) => Done::Report(report),
+                    Err(err) => {
+                        log::error!("failed to fetch commits of {}: {}", member.user_id, err);
+                        Done::Unavailable
+                    }
+                },
+                None => Done::NoToken,
+            };
+            digest.push_str(&member_section(lang, &member.name, &done, answer.as_ref()));
+        }
+
+        for part in report::split_message(&digest) {
+            if let Err(err) = bot.send_message(team, part).await {
+                log::error!("failed to post stand-up to {}: {}", team, err);
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gitlab::{Commit, MergeRequest};
+    use crate::report::RepositoryReport;
+    use std::collections::HashMap;
+
+    fn report(commits: &[&str], merge_requests: &[(u32, &str, &str)]) -> Report {
+        let today = Utc::now().date_naive();
+        Report {
+            range: DateRange::day(today),
+            repositories: vec![RepositoryReport {
+                name: "digireport".to_string(),
+                commits: commits
+                    .iter()
+                    .map(|title| Commit {
+                        title: title.to_string(),
+                        ..Commit::default()
+                    })
+                    .collect(),
+                merge_requests: merge_requests
+                    .iter()
+                    .map(|(iid, title, state)| MergeRequest {
+                        iid: *iid,
+                        title: title.to_string(),
+                        state: state.to_string(),
+                        ..MergeRequest::default()
+                    })
+                    .collect(),
+            }],
+            summary: None,
+            categories: HashMap::new(),
+        }
+    }
+
+    fn answer() -> StandupAnswer {
+        StandupAnswer {
+            plans: "write the tests".to_string(),
+            blockers: "none".to_string(),
+        }
+    }
+
+    #[test]
+    fn lists_commits_and_merge_requests_as_done() {
+        let done = Done::Report(report(&["fix: login"], &[(12, "Add stand-ups", "merged")]));
+
+        assert_eq!(
+            member_section(Lang::En, "Adi", &done, Some(&answer())),
+            "Adi\nDone:\n- fix: login (digireport)\n- Add stand-ups (digireport !12, merged)\n\
+             Today:\nwrite the tests\nBlockers:\nnone\n\n"
+        );
+    }
+
+    #[test]
+    fn merge_requests_alone_count_as_done() {
+        let done = Done::Report(report(&[], &[(3, "Review the digest", "opened")]));
+
+        let section = member_section(Lang::En, "Adi", &done, None);
+        assert!(section.contains("- Review the digest (digireport !3, opened)\n"));
+        assert!(!section.contains(Text::StandupNoCommits.get(Lang::En)));
+    }
+
+    #[test]
+    fn says_when_nothing_was_done_or_answered() {
+        let done = Done::Report(report(&[], &[]));
+
+        assert_eq!(
+            member_section(Lang::Id, "Adi", &done, None),
+            format!(
+                "Adi\nSelesai:\n{}\n{}\n\n",
+                Text::StandupNoCommits.get(Lang::Id),
+                Text::StandupNoAnswer.get(Lang::Id)
+            )
+        );
+    }
+
+    #[test]
+    fn says_why_the_work_is_missing() {
+        let no_token = member_section(Lang::En, "Adi", &Done::NoToken, Some(&answer()));
+        assert!(no_token.starts_with(&format!("Adi\nDone:\n{}\nToday:", Text::StandupNoToken.get(Lang::En))));
+
+        let unavailable = member_section(Lang::En, "Adi", &Done::Unavailable, Some(&answer()));
+        assert!(unavailable.contains(Text::StandupCommitsUnavailable.get(Lang::En)));
+    }
+}
@@ -0,0 +1,199 @@
+use rust_bert::pipelines::common::{ModelResource, ModelType};
+use rust_bert::pipelines::summarization::{SummarizationConfig, SummarizationModel};
+use rust_bert::resources::LocalResource;
+use std::env;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::qa::number_from_env;
+
+// reports waiting to be summarised before new ones are turned away
+const QUEUE_SIZE: usize = 8;
+// longest summary in tokens
+const DEFAULT_MAX_LENGTH: usize = 80;
+// generation is slow on CPU, a day with many commits can take a while
+const DEFAULT_TIMEOUT_SECS: u64 = 60;
+
+#[derive(Clone, Debug)]
+pub struct SummaryConfig {
+    pub model_type: ModelType,
+    pub model_path: PathBuf,
+    pub config_path: PathBuf,
+    pub vocab_path: PathBuf,
+    // only used by BART models
+    pub merges_path: Option<PathBuf>,
+    pub max_length: usize,
+    pub timeout: Duration,
+}
+
+impl SummaryConfig {
+    /// reads the model location from the environment, returns None when no model is configured
+    ///
+    /// `SUMMARY_MODEL_DIR` points to a directory with `rust_model.ot`, `config.json` and the
+    /// vocabulary (`vocab.json` and `merges.txt` for BART, `spiece.model` for T5),
+    /// `SUMMARY_MODEL_TYPE` is `bart` (default) or `t5`
+    pub fn from_env() -> Result<Option<SummaryConfig>, String> {
+        let dir = match env::var("SUMMARY_MODEL_DIR") {
+            Ok(dir) => PathBuf::from(dir),
+            Err(_) => return Ok(None),
+        };
+
+        let model_type = match env::var("SUMMARY_MODEL_TYPE")
+            .unwrap_or_else(|_| "bart".to_string())
+            .to_lowercase()
+            .as_str()
+        {
+            "bart" => ModelType::Bart,
+            "t5" => ModelType::T5,
+            other => return Err(format!("unsupported SUMMARY_MODEL_TYPE `{}`", other)),
+        };
+        let is_bart = model_type == ModelType::Bart;
+
+        let config = SummaryConfig {
+            model_type,
+            model_path: dir.join("rust_model.ot"),
+            config_path: dir.join("config.json"),
+            vocab_path: dir.join(if is_bart { "vocab.json" } else { "spiece.model" }),
+            merges_path: if is_bart { Some(dir.join("merges.txt")) } else { None },
+            max_length: number_from_env("SUMMARY_MAX_LENGTH", DEFAULT_MAX_LENGTH)?,
+            timeout: Duration::from_secs(number_from_env("SUMMARY_TIMEOUT_SECS", DEFAULT_TIMEOUT_SECS as usize)? as u64),
+        };
+
+        for file in [&config.model_path, &config.config_path, &config.vocab_path]
+            .into_iter()
+            .chain(config.merges_path.as_ref())
+        {
+            if !file.is_file() {
+                return Err(format!("summarisation model file {} does not exist", file.display()));
+            }
+        }
+
+        Ok(Some(config))
+    }
+
+    fn to_pipeline_config(&self) -> SummarizationConfig {
+        let mut config = SummarizationConfig::new(
+            self.model_type,
+            ModelResource::Torch(Box::new(LocalResource {
+                local_path: self.model_path.clone(),
+            })),
+            LocalResource {
+                local_path: self.config_path.clone(),
+            },
+            LocalResource {
+                local_path: self.vocab_path.clone(),
+            },
+            self.merges_path.clone().map(|local_path| LocalResource { local_path }),
+        );
+        config.max_length = Some(self.max_length as i64);
+        config.min_length = (self.max_length as i64 / 4).min(config.min_length);
+
+        config
+    }
+}
+
+struct SummaryRequest {
+    text: String,
+    reply: oneshot::Sender<Option<String>>,
+}
+
+/// turns long reports into a short paragraph, generation runs on its own thread
+#[derive(Clone)]
+pub struct Summarizer {
+    // None when no model is configured or it failed to load
+    queue: Option<mpsc::Sender<SummaryRequest>>,
+    timeout: Duration,
+}
+
+impl Summarizer {
+    pub fn disabled() -> Summarizer {
+        Summarizer {
+            queue: None,
+            timeout: Duration::from_secs(DEFAULT_TIMEOUT_SECS),
+        }
+    }
+
+    /// starts the worker thread, requests queue up while the model is loading
+    pub fn load(config: SummaryConfig) -> Summarizer {
+        let (tx, mut rx) = mpsc::channel::<SummaryRequest>(QUEUE_SIZE);
+        let timeout = config.timeout;
+
+        let spawned = thread::Builder::new()
+            .name("summary-worker".to_string())
+            .spawn(move || {
+                log::info!("Loading summarisation model from {}", config.model_path.display());
+                let model = match SummarizationModel::new(config.to_pipeline_config()) {
+                    Ok(model) => model,
+                    Err(err) => {
+                        // dropping the receiver makes every later request fail right away
+                        log::error!("failed to load the summarisation model: {}", err);
+                        return;
+                    }
+                };
+                log::info!("Summarisation model ready");
+
+                while let Some(request) = rx.blocking_recv() {
+                    if request.reply.is_closed() {
+                        continue;
+                    }
+                    let summary = model.summarize(&[request.text]).pop();
+                    let _ = request.reply.send(summary);
+                }
+            });
+
+        match spawned {
+            Ok(_) => Summarizer {
+                queue: Some(tx),
+                timeout,
+            },
+            Err(err) => {
+                log::error!("failed to start the summarisation worker: {}", err);
+                Summarizer::disabled()
+            }
+        }
+    }
+
+    /// loads the model configured in the environment, or returns a disabled one
+    pub fn from_env() -> Summarizer {
+        match SummaryConfig::from_env() {
+            Ok(Some(config)) => Summarizer::load(config),
+            Ok(None) => {
+                log::info!("No summarisation model configured, reports list every commit");
+                Summarizer::disabled()
+            }
+            Err(err) => {
+                log::error!("invalid summarisation configuration, summaries are disabled: {}", err);
+                Summarizer::disabled()
+            }
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.queue.as_ref().is_some_and(|queue| !queue.is_closed())
+    }
+
+    /// a short paragraph about `text`, None when no summary could be made in time
+    pub async fn summarize(&self, text: String) -> Option<String> {
+        let queue = self.queue.as_ref()?;
+        if text.trim().is_empty() {
+            return None;
+        }
+
+        let (reply, summary) = oneshot::channel();
+        if let Err(err) = queue.try_send(SummaryRequest { text, reply }) {
+            log::warn!("summary not queued: {}", err);
+            return None;
+        }
+
+        match tokio::time::timeout(self.timeout, summary).await {
+            Ok(Ok(summary)) => summary.map(|summary| summary.trim().to_string()).filter(|summary| !summary.is_empty()),
+            Ok(Err(_)) => None,
+            Err(_) => {
+                log::warn!("summary timed out after {:?}", self.timeout);
+                None
+            }
+        }
+    }
+}
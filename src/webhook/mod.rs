@@ -0,0 +1,130 @@
+use reqwest::Url;
+use std::convert::Infallible;
+use std::env;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use teloxide::stop::{mk_stop_token, StopFlag, StopToken};
+use teloxide::types::Update;
+use teloxide::update_listeners::{StatefulListener, UpdateListener};
+use tokio::sync::mpsc;
+use tokio_stream::Stream;
+
+use crate::shutdown::Shutdown;
+
+// the header telegram puts the secret token in
+pub const SECRET_TOKEN_HEADER: &str = "X-Telegram-Bot-Api-Secret-Token";
+
+#[derive(Clone, Debug)]
+pub struct WebhookConfig {
+    // public URL telegram posts updates to, ending with the secret path
+    pub url: Url,
+    // last segment of the URL, only telegram knows it
+    pub path: String,
+    pub secret_token: String,
+}
+
+impl WebhookConfig {
+    /// reads `BOT_MODE` (`polling` by default, or `webhook`), in webhook mode
+    /// `WEBHOOK_URL` is the public base URL of the server, `WEBHOOK_PATH` the secret
+    /// path segment and `WEBHOOK_SECRET_TOKEN` the token telegram sends with every update
+    pub fn from_env() -> Result<Option<WebhookConfig>, String> {
+        match env::var("BOT_MODE").unwrap_or_else(|_| "polling".to_string()).to_lowercase().as_str() {
+            "polling" => return Ok(None),
+            "webhook" => {}
+            other => return Err(format!("unsupported BOT_MODE `{}`", other)),
+        }
+
+        let required = |name: &str| env::var(name).map_err(|_| format!("{} is required in webhook mode", name));
+        let path = required("WEBHOOK_PATH")?;
+        let secret_token = required("WEBHOOK_SECRET_TOKEN")?;
+
+        // telegram only accepts these characters in the secret token
+        let is_valid = |value: &str| {
+            !value.is_empty()
+                && value.len() <= 256
+                && value.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        };
+        if !is_valid(&path) {
+            return Err("WEBHOOK_PATH may only contain A-Z, a-z, 0-9, _ and -".to_string());
+        }
+        if !is_valid(&secret_token) {
+            return Err("WEBHOOK_SECRET_TOKEN must be 1-256 characters of A-Z, a-z, 0-9, _ and -".to_string());
+        }
+
+        let base = required("WEBHOOK_URL")?;
+        let url = Url::parse(&format!("{}/webhook/{}", base.trim_end_matches('/'), path))
+            .map_err(|err| format!("invalid WEBHOOK_URL `{}`: {}", base, err))?;
+
+        Ok(Some(WebhookConfig {
+            url,
+            path,
+            secret_token,
+        }))
+    }
+}
+
+/// hands updates received by the HTTP server to the dispatcher
+#[derive(Clone)]
+pub struct Webhook {
+    pub config: WebhookConfig,
+    sender: mpsc::UnboundedSender<Result<Update, Infallible>>,
+}
+
+pub type UpdateReceiver = mpsc::UnboundedReceiver<Result<Update, Infallible>>;
+
+impl Webhook {
+    pub fn new(config: WebhookConfig) -> (Webhook, UpdateReceiver) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        (Webhook { config, sender }, receiver)
+    }
+
+    /// returns false once the dispatcher stopped listening
+    pub fn push(&self, update: Update) -> bool {
+        self.sender.send(Ok(update)).is_ok()
+    }
+
+    /// whether a request carries the configured path and secret token
+    pub fn accepts(&self, path: &str, secret_token: Option<&str>) -> bool {
+        path == self.config.path && secret_token == Some(self.config.secret_token.as_str())
+    }
+}
+
+/// the updates pushed by the HTTP server, ends once the listener is stopped
+pub struct UpdateStream {
+    receiver: UpdateReceiver,
+    stop_flag: StopFlag,
+}
+
+impl Stream for UpdateStream {
+    type Item = Result<Update, Infallible>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if Pin::new(&mut self.stop_flag).poll(cx).is_ready() {
+            return Poll::Ready(None);
+        }
+        self.receiver.poll_recv(cx)
+    }
+}
+
+fn stream(state: &mut (UpdateStream, StopToken)) -> &mut UpdateStream {
+    &mut state.0
+}
+
+/// the dispatcher side of the webhook, yields the updates pushed by the HTTP server
+/// until the dispatcher stops it or the app shuts down
+pub fn listener(receiver: UpdateReceiver, shutdown: Shutdown) -> impl UpdateListener<Err = Infallible> {
+    let (stop_token, stop_flag) = mk_stop_token();
+
+    let token = stop_token.clone();
+    tokio::spawn(async move {
+        shutdown.wait().await;
+        token.stop();
+    });
+
+    StatefulListener::new(
+        (UpdateStream { receiver, stop_flag }, stop_token),
+        stream,
+        |state: &mut (_, StopToken)| state.1.clone(),
+    )
+}